@@ -0,0 +1,120 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+// Passes the resolved versions of the script engines to the code as WASMI_VERSION and WASMER_VERSION, so
+// the compiled script cache gets a new folder whenever cargo picks up a different release. Without a
+// lock file to read them from they are "unknown", and the cache has to be cleared by hand on updates.
+
+const UNKNOWN : &str = "unknown";
+
+struct Package
+{
+    name : String,
+    version : String,
+    // "name" or "name version (source)", as the lock file lists them.
+    dependencies : Vec<String>,
+}
+
+fn quoted(value : &str) -> String {
+    value.trim().trim_end_matches(',').trim_matches('"').to_string()
+}
+
+fn parse_lock(lock : &str) -> Vec<Package> {
+    let mut packages : Vec<Package> = Vec::new();
+    // False outside [[package]] tables, e.g. in [metadata].
+    let mut in_package = false;
+    let mut in_dependencies = false;
+    for line in lock.lines().map(str::trim) {
+        if in_dependencies {
+            if line.starts_with(']') {
+                in_dependencies = false;
+            }
+            else if let Some(package) = packages.last_mut() {
+                package.dependencies.push(quoted(line));
+            }
+            continue;
+        }
+
+        if line.starts_with('[') {
+            in_package = line == "[[package]]";
+            if in_package {
+                packages.push(Package { name : String::new(), version : String::new(), dependencies : Vec::new() });
+            }
+            continue;
+        }
+        let package = match packages.last_mut() {
+            Some(package) if in_package => package,
+            _ => continue,
+        };
+
+        if let Some(name) = line.strip_prefix("name = ") {
+            package.name = quoted(name);
+        }
+        else if let Some(version) = line.strip_prefix("version = ") {
+            package.version = quoted(version);
+        }
+        else if let Some(list) = line.strip_prefix("dependencies = [") {
+            // Old lock files put short lists on one line.
+            match list.strip_suffix(']') {
+                Some(list) => package.dependencies.extend(list.split(',').map(quoted).filter(|dependency| !dependency.is_empty())),
+                None => in_dependencies = true,
+            }
+        }
+    }
+    packages
+}
+
+// The version of `dependency` that `package` at `version` was resolved against. The lock file only adds
+// the version to a dependency's name when there is more than one of it.
+fn dependency_version(packages : &[Package], package : &str, version : &str, dependency : &str) -> Option<String> {
+    let parent = packages.iter().find(|candidate| candidate.name == package && candidate.version == version)?;
+    let entry = parent.dependencies.iter().find(|entry| entry.split(' ').next() == Some(dependency))?;
+    match entry.split(' ').nth(1) {
+        Some(version) => Some(version.to_string()),
+        None => packages.iter().find(|candidate| candidate.name == dependency).map(|candidate| candidate.version.clone()),
+    }
+}
+
+// The lock file is at the workspace root, which may be above this crate.
+fn find_lock() -> Option<PathBuf> {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    manifest_dir.ancestors().map(|directory| directory.join("Cargo.lock")).find(|path| path.is_file())
+}
+
+fn emit(variable : &str, version : Option<String>, what : &str) {
+    let version = version.unwrap_or_else(|| {
+        println!("cargo:warning=Can't find the {} version in Cargo.lock, compiled scripts are cached as {}", what, UNKNOWN);
+        UNKNOWN.to_string()
+    });
+    println!("cargo:rustc-env={}={}", variable, version);
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let lock_path = find_lock();
+    let lock = lock_path.as_ref().and_then(|path| fs::read_to_string(path).ok());
+    if let Some(path) = lock_path.as_ref() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    let packages = lock.as_ref().map_or(Vec::new(), |lock| parse_lock(lock));
+
+    let name = env::var("CARGO_PKG_NAME").unwrap();
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let dependency = |parent : &str, parent_version : &str, dependency : &str| dependency_version(&packages, parent, parent_version, dependency);
+
+    emit("WASMI_VERSION", dependency(&name, &version, "wasmi"), "wasmi");
+
+    // Wasmer artifacts are native code, they depend on the runtime and the compiler backend that made them.
+    if env::var_os("CARGO_FEATURE_JIT").is_some() {
+        let runtime = dependency(&name, &version, "wasmer-runtime-core");
+        let backend = dependency(&name, &version, "wasmer-runtime")
+            .and_then(|runtime_version| dependency("wasmer-runtime", &runtime_version, "wasmer-clif-backend"));
+        let wasmer = runtime.map(|runtime| match backend {
+            Some(backend) if backend != runtime => format!("{}-clif{}", runtime, backend),
+            _ => runtime,
+        });
+        emit("WASMER_VERSION", wasmer, "wasmer");
+    }
+}
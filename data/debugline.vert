@@ -0,0 +1,22 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Script debug lines, in world space.
+
+layout(location = 0) in vec3 a_pos;
+layout(location = 1) in vec4 a_color;
+layout(location = 0) out vec4 v_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 mvpmat;
+};
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+void main() {
+    v_color = a_color;
+    gl_Position = mvpmat * vec4(a_pos, 1.0);
+    gl_Position.y *= -1;
+}
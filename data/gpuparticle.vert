@@ -0,0 +1,64 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Billboards straight from the GPU particle buffer, six vertices per particle slot. Builds the same
+// quads as add_particle in main.rs does on the CPU.
+
+struct Particle {
+    float px, py, pz;
+    float vx, vy, vz;
+    float gx, gy, gz;
+    float drag;
+    float age;
+    float lifetime;
+    float size;
+    uint color;
+    int emitter;
+};
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 mvpmat;
+};
+
+layout(std430, set = 1, binding = 0) readonly buffer Particles {
+    Particle particles[];
+};
+
+layout(push_constant) uniform Camera {
+    vec4 position;
+    vec4 up;
+} camera;
+
+layout(location = 0) out vec2 v_uv;
+layout(location = 1) out vec4 v_color;
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+const vec2 CORNERS[6] = vec2[6](vec2(-1, 1), vec2(1, 1), vec2(1, -1), vec2(-1, 1), vec2(1, -1), vec2(-1, -1));
+
+void main() {
+    Particle p = particles[gl_VertexIndex / 6];
+    vec2 corner = CORNERS[gl_VertexIndex % 6];
+
+    if (p.age >= p.lifetime) {
+        // Dead slot, outside the clip volume.
+        gl_Position = vec4(2.0, 2.0, 2.0, 1.0);
+        v_uv = vec2(0.0);
+        v_color = vec4(0.0);
+        return;
+    }
+
+    vec3 position = vec3(p.px, p.py, p.pz);
+    vec3 n = normalize(camera.position.xyz - position);
+    vec3 r = cross(n, camera.up.xyz);
+    vec3 u = cross(r, n);
+
+    vec3 world_position = position + (r * corner.x + u * corner.y) * p.size;
+
+    v_uv = corner * 0.5 + 0.5;
+    v_color = unpackUnorm4x8(p.color);
+    gl_Position = mvpmat * vec4(world_position, 1.0);
+    gl_Position.y *= -1;
+}
@@ -0,0 +1,14 @@
+// Freestanding math.h for scripts built with a local clang. There is no libc in the module, these are
+// imported from the host (see hostapi.rs).
+#pragma once
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+float sinf(float x);
+float cosf(float x);
+
+#ifdef __cplusplus
+}
+#endif
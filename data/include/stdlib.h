@@ -0,0 +1,12 @@
+// Freestanding stdlib.h for scripts built with a local clang, rand is imported from the host.
+#pragma once
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+int rand(void);
+
+#ifdef __cplusplus
+}
+#endif
@@ -0,0 +1,12 @@
+// Freestanding string.h for scripts built with a local clang, memcpy is imported from the host.
+#pragma once
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+void* memcpy(void* dst, const void* src, __SIZE_TYPE__ len);
+
+#ifdef __cplusplus
+}
+#endif
@@ -0,0 +1,30 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Script meshes, in world space. A zero normal leaves the vertex unlit.
+
+layout(location = 0) in vec3 a_pos;
+layout(location = 1) in vec3 a_normal;
+layout(location = 2) in vec4 a_color;
+layout(location = 0) out vec4 v_color;
+
+layout(set = 0, binding = 0) uniform Locals {
+    mat4 mvpmat;
+};
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+const vec3 LIGHT_DIR = normalize(vec3(0.4, 1.0, 0.3));
+const float AMBIENT = 0.3;
+
+void main() {
+    float light = 1.0;
+    if (dot(a_normal, a_normal) > 0.0) {
+        light = AMBIENT + (1.0 - AMBIENT) * abs(dot(normalize(a_normal), LIGHT_DIR));
+    }
+    v_color = vec4(a_color.rgb * light, a_color.a);
+    gl_Position = mvpmat * vec4(a_pos, 1.0);
+    gl_Position.y *= -1;
+}
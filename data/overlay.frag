@@ -0,0 +1,9 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec4 v_color;
+layout(location = 0) out vec4 target0;
+
+void main() {
+    target0 = v_color;
+}
@@ -0,0 +1,17 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+// Debug overlay quads, already in normalized device coordinates.
+
+layout(location = 0) in vec2 a_pos;
+layout(location = 1) in vec4 a_color;
+layout(location = 0) out vec4 v_color;
+
+out gl_PerVertex {
+    vec4 gl_Position;
+};
+
+void main() {
+    v_color = a_color;
+    gl_Position = vec4(a_pos, 0.0, 1.0);
+}
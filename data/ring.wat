@@ -0,0 +1,31 @@
+;; A ring of particles orbiting the origin, written directly in the text format.
+;; Run with `cargo run --features=<backend> -- --script=data/ring.wat`.
+(module
+  (import "env" "set_camera" (func $set_camera (param f32 f32 f32 f32 f32 f32)))
+  (import "env" "add_particle" (func $add_particle (param f32 f32 f32 f32 i32)))
+  (import "env" "sinf" (func $sinf (param f32) (result f32)))
+  (import "env" "cosf" (func $cosf (param f32) (result f32)))
+
+  (memory (export "memory") 1)
+
+  (func (export "tick") (param $t f32) (param $dt f32)
+    (local $i i32)
+    (local $angle f32)
+
+    (call $set_camera (f32.const 0) (f32.const 0) (f32.const -10) (f32.const 0) (f32.const 0) (f32.const 0))
+
+    (loop $particles
+      ;; angle = t + i * 2pi / 64
+      (local.set $angle
+        (f32.add (local.get $t)
+                 (f32.mul (f32.convert_i32_s (local.get $i)) (f32.const 0.0981748))))
+
+      (call $add_particle
+        (f32.mul (call $cosf (local.get $angle)) (f32.const 3))
+        (f32.mul (call $sinf (local.get $angle)) (f32.const 3))
+        (f32.const 0)
+        (f32.const 0.1)
+        (i32.const 0xff20c0ff))
+
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $particles (i32.lt_s (local.get $i) (i32.const 64))))))
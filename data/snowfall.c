@@ -0,0 +1,36 @@
+#include "webasm_scripting.h"
+
+// snow.c on host simulated particles: the script only places emitters, the host integrates and draws
+// every flake.
+
+constexpr int EMITTERS = 16;
+int g_emitters[EMITTERS];
+
+float rand_uniform(float a, float b) {
+  return a + (b-a)*((rand() % 8097) / 8096.f);
+}
+
+void init()
+{
+  for(int i=0; i<EMITTERS; i++) {
+    int e = create_emitter();
+    g_emitters[i] = e;
+
+    ws_set_emitter_position(e, ws_vec3_make(rand_uniform(-10.f, 10.f), 10.f, rand_uniform(-10.f, 10.f)));
+    ws_set_emitter_velocity(e, ws_vec3_make(0.f, -1.f, 0.f), 0.5f);
+    set_emitter_rate(e, 500.f);
+    set_emitter_lifetime(e, 10.f);
+    ws_set_emitter_appearance(e, 0.1f, ws_color_rgba(255, 255, 255, 255));
+    ws_set_emitter_physics(e, ws_vec3_make(0.f, -0.5f, 0.f), 0.5f);
+  }
+}
+
+void tick(float t, float dt)
+{
+  set_camera(0, 0, -10, 0, 0, 0);
+
+  // Move one emitter per tick so the snow doesn't fall in columns.
+  static int next = 0;
+  ws_set_emitter_position(g_emitters[next], ws_vec3_make(rand_uniform(-10.f, 10.f), 10.f, rand_uniform(-10.f, 10.f)));
+  next = (next + 1) % EMITTERS;
+}
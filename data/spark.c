@@ -1,5 +1,6 @@
 #include <math.h>
 #include <stdlib.h>
+#include "webasm_scripting.h"
 
 using u32 = unsigned;
 using u8 = unsigned char;
@@ -34,21 +35,12 @@ struct color_rgba
   color_rgba(u8 _r, u8 _g, u8 _b, u8 _a) : r(_r), g(_g), b(_b), a(_a) {}
   
   u32 as_u32() const {
-    return (a << 24) | (b << 16) | (g << 8) | r;
+    return ws_pack_rgba(r, g, b, a);
   }
 };
 
-extern "C" {
-  void set_camera(float posx, float posy, float posz, float lookatx, float lookaty, float lookatz);
-  
-  void add_particle(float posx, float posy, float posz, float size, u32 color);
-  
-  void tick(float t);
-}
-
 int g_particles_num = 0;
 vec3 camera_position;
-float last_t;
 float last_emission = 0.f;
 
 struct particle {
@@ -75,11 +67,8 @@ template<typename V, typename U>
     return static_cast<V>(u);
   }
 
-void tick(float t)
+void tick(float t, float dt)
 {
-  float dt = t - last_t;
-  last_t = t;
-  
   float camera_rotation_radius = 20.f;
   //set_camera(sin(t) * camera_rotation_radius, 0, cos(t) * camera_rotation_radius, 0, 0, 0);
   set_camera(0, 0, -10, 0, 0, 0);
@@ -92,8 +81,8 @@ void tick(float t)
       i--;
     }
     else {
-      g_particles[i].pos = g_particles[i].pos + g_particles[i].velocity;
-      g_particles[i].velocity.y += -0.001;
+      g_particles[i].pos = g_particles[i].pos + g_particles[i].velocity * dt;
+      g_particles[i].velocity.y += -3.6f * dt;
       
       add_particle(g_particles[i].pos.x, g_particles[i].pos.y, g_particles[i].pos.z, g_particles[i].size,
       g_particles[i].color.as_u32());
@@ -111,7 +100,7 @@ void tick(float t)
       g_particles_num++;
       
       p.pos = emiter_pos;
-      p.velocity = vec3(rand_uniform(-0.2, 0.2), rand_uniform(1., 2.), 0) * 0.1f;
+      p.velocity = vec3(rand_uniform(-0.2, 0.2), rand_uniform(1., 2.), 0) * 6.f;
       p.size = 0.1f;
       p.lifetime = 0;
       u8 red = (rand() % 32) + 255 - 33;
@@ -0,0 +1,348 @@
+//! Generated by src/bin/sdkgen.rs from src/hostapi.rs, do not edit.
+//!
+//! Scripts implement these exports with `#[no_mangle] pub extern "C"`:
+//!
+//! - `fn tick(t: f32, dt: f32)` (required): Called every fixed simulation step with the simulation time and the step length, in seconds.
+//! - `fn init()` (optional): Called after the script is loaded.
+//! - `fn shutdown()` (optional): Called before the script is unloaded or reloaded.
+//! - `fn on_resize(width: i32, height: i32)` (optional): Called when the swapchain is recreated.
+//! - `fn on_particle_spawn(emitter: i32, particle: i32)` (optional): Called for particles of emitters with the spawn callback flag.
+//! - `fn on_particle_death(emitter: i32, x: f32, y: f32, z: f32)` (optional): Called where a particle of an emitter with the death callback flag expired.
+
+#![no_std]
+
+/// Raw host imports.
+pub mod sys {
+    #[link(wasm_import_module = "env")]
+    extern "C" {
+        /// Places the camera at pos, looking at lookat.
+        pub fn set_camera(posx: f32, posy: f32, posz: f32, lookatx: f32, lookaty: f32, lookatz: f32);
+
+        /// Draws a particle this frame. color is RGBA8 with red in the lowest byte.
+        pub fn add_particle(posx: f32, posy: f32, posz: f32, size: f32, color: u32);
+
+        /// add_particle with float channels in [0, 1].
+        pub fn add_particle_rgba(posx: f32, posy: f32, posz: f32, size: f32, r: f32, g: f32, b: f32, a: f32);
+
+        /// add_particle with the hue in degrees and saturation, value and alpha in [0, 1].
+        pub fn add_particle_hsv(posx: f32, posy: f32, posz: f32, size: f32, h: f32, s: f32, v: f32, a: f32);
+
+        /// How the script's particle colors are encoded, 0 = linear (the default) or 1 = sRGB. sRGB colors are converted to linear by the host.
+        pub fn set_color_space(space: i32);
+
+        pub fn cosf(x: f32) -> f32;
+
+        pub fn sinf(x: f32) -> f32;
+
+        pub fn memcpy(dst: *mut u8, src: *const u8, len: usize) -> *mut u8;
+
+        pub fn rand() -> i32;
+
+        /// Logs len bytes of str. Levels are 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.
+        pub fn log_message(level: i32, str: *const u8, len: i32);
+
+        /// Logs a printf style message, see log_message for the levels.
+        pub fn log_printf(level: i32, format: *const u8, ...);
+
+        /// Creates a host simulated particle emitter and returns its id. Emitters are destroyed when the script is unloaded.
+        pub fn create_emitter() -> i32;
+
+        /// Stops an emitter, its particles live out their lifetime.
+        pub fn destroy_emitter(emitter: i32);
+
+        /// Emits count particles at once on the next update, on top of the rate.
+        pub fn emit_particles(emitter: i32, count: i32);
+
+        pub fn set_emitter_position(emitter: i32, x: f32, y: f32, z: f32);
+
+        /// Initial velocity in units per second, each component randomized by up to +-spread.
+        pub fn set_emitter_velocity(emitter: i32, x: f32, y: f32, z: f32, spread: f32);
+
+        pub fn set_emitter_rate(emitter: i32, particles_per_second: f32);
+
+        pub fn set_emitter_lifetime(emitter: i32, seconds: f32);
+
+        /// color is packed like add_particle colors, in the script's color space.
+        pub fn set_emitter_appearance(emitter: i32, size: f32, color: u32);
+
+        /// Acceleration in units per second squared, drag is the fraction of velocity lost per second.
+        pub fn set_emitter_physics(emitter: i32, gravityx: f32, gravityy: f32, gravityz: f32, drag: f32);
+
+        /// Calls on_particle_spawn (flag 1) and on_particle_death (flag 2) for this emitter's particles.
+        pub fn set_emitter_callbacks(emitter: i32, flags: i32);
+
+        /// Changes a particle from on_particle_spawn, which passes its index.
+        pub fn set_particle_velocity(particle: i32, x: f32, y: f32, z: f32);
+
+        /// Draws this emitter's particles as billboards (mode 0), stretched along their velocity (1) or as ribbons through their past positions (2), length seconds long.
+        pub fn set_emitter_render_mode(emitter: i32, mode: i32, length: f32);
+
+        /// Like set_emitter_render_mode for one particle, from on_particle_spawn.
+        pub fn set_particle_render_mode(particle: i32, mode: i32, length: f32);
+
+        /// Changes a particle from on_particle_spawn, which passes its index.
+        pub fn set_particle_color(particle: i32, color: u32);
+
+        /// Draws a debug line this frame. Debug colors are packed like add_particle colors.
+        pub fn draw_line(x0: f32, y0: f32, z0: f32, x1: f32, y1: f32, z1: f32, color: u32);
+
+        /// Draws the edges of an axis aligned box this frame.
+        pub fn draw_box(minx: f32, miny: f32, minz: f32, maxx: f32, maxy: f32, maxz: f32, color: u32);
+
+        /// Draws a sphere as three circles this frame.
+        pub fn draw_sphere_wire(x: f32, y: f32, z: f32, radius: f32, color: u32);
+
+        /// Draws len bytes of str this frame, x and y in pixels from the top left of the window.
+        pub fn draw_text_2d(x: f32, y: f32, str: *const u8, len: i32, color: u32);
+
+        /// Draws len bytes of str this frame, starting at a point in the world.
+        pub fn draw_text_3d(x: f32, y: f32, z: f32, str: *const u8, len: i32, color: u32);
+
+        /// Draws count vertices (ws_vertex) as triangles this frame, every three making one.
+        pub fn add_triangles(vertices: *const u8, count: i32);
+
+        /// Draws an indexed mesh this frame. indices are unsigned ints into vertices (ws_vertex), three per triangle.
+        pub fn add_mesh(vertices: *const u8, vertex_count: i32, indices: *const u8, index_count: i32);
+    }
+}
+
+use core::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3 {
+    pub const fn new(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, s: f32) -> Vec3 {
+        Vec3::new(self.x * s, self.y * s, self.z * s)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 {
+        Vec3::new(-self.x, -self.y, -self.z)
+    }
+}
+
+/// An RGBA8 color. `add_particle` takes it packed with red in the lowest byte, matching the
+/// Rgba8Unorm vertex attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color { r, g, b, a }
+    }
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, a: 255 }
+    }
+
+    pub const fn pack(self) -> u32 {
+        (self.r as u32) | ((self.g as u32) << 8) | ((self.b as u32) << 16) | ((self.a as u32) << 24)
+    }
+}
+
+/// A vertex for `add_triangles` and `add_mesh`, laid out as the host reads it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: [f32; 2],
+    /// Packed like `Color::pack`.
+    pub color: u32,
+}
+
+impl Vertex {
+    pub const fn new(position: Vec3, normal: Vec3, uv: [f32; 2], color: Color) -> Vertex {
+        Vertex { position, normal, uv, color: color.pack() }
+    }
+}
+
+/// How particle colors are encoded, see `set_color_space`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Linear = 0,
+    Srgb = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+pub fn set_camera(pos: Vec3, lookat: Vec3) {
+    unsafe { sys::set_camera(pos.x, pos.y, pos.z, lookat.x, lookat.y, lookat.z) }
+}
+
+pub fn add_particle(pos: Vec3, size: f32, color: Color) {
+    unsafe { sys::add_particle(pos.x, pos.y, pos.z, size, color.pack()) }
+}
+
+/// Channels in [0, 1].
+pub fn add_particle_rgba(pos: Vec3, size: f32, rgba: [f32; 4]) {
+    unsafe { sys::add_particle_rgba(pos.x, pos.y, pos.z, size, rgba[0], rgba[1], rgba[2], rgba[3]) }
+}
+
+/// Hue in degrees, saturation, value and alpha in [0, 1].
+pub fn add_particle_hsv(pos: Vec3, size: f32, h: f32, s: f32, v: f32, a: f32) {
+    unsafe { sys::add_particle_hsv(pos.x, pos.y, pos.z, size, h, s, v, a) }
+}
+
+pub fn set_color_space(space: ColorSpace) {
+    unsafe { sys::set_color_space(space as i32) }
+}
+
+pub const CALLBACK_SPAWN: i32 = 1;
+pub const CALLBACK_DEATH: i32 = 2;
+
+/// How host simulated particles are drawn, see `Emitter::set_render_mode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Billboard = 0,
+    /// Stretched along the velocity.
+    Stretched = 1,
+    /// A strip through the particle's past positions.
+    Ribbon = 2,
+}
+
+/// A host simulated particle emitter. Emitters live until destroyed or until the script is unloaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Emitter(pub i32);
+
+impl Emitter {
+    pub fn create() -> Emitter {
+        Emitter(unsafe { sys::create_emitter() })
+    }
+
+    pub fn destroy(self) {
+        unsafe { sys::destroy_emitter(self.0) }
+    }
+
+    pub fn emit(self, count: i32) {
+        unsafe { sys::emit_particles(self.0, count) }
+    }
+
+    pub fn set_position(self, pos: Vec3) {
+        unsafe { sys::set_emitter_position(self.0, pos.x, pos.y, pos.z) }
+    }
+
+    pub fn set_velocity(self, velocity: Vec3, spread: f32) {
+        unsafe { sys::set_emitter_velocity(self.0, velocity.x, velocity.y, velocity.z, spread) }
+    }
+
+    pub fn set_rate(self, particles_per_second: f32) {
+        unsafe { sys::set_emitter_rate(self.0, particles_per_second) }
+    }
+
+    pub fn set_lifetime(self, seconds: f32) {
+        unsafe { sys::set_emitter_lifetime(self.0, seconds) }
+    }
+
+    pub fn set_appearance(self, size: f32, color: Color) {
+        unsafe { sys::set_emitter_appearance(self.0, size, color.pack()) }
+    }
+
+    pub fn set_physics(self, gravity: Vec3, drag: f32) {
+        unsafe { sys::set_emitter_physics(self.0, gravity.x, gravity.y, gravity.z, drag) }
+    }
+
+    pub fn set_callbacks(self, flags: i32) {
+        unsafe { sys::set_emitter_callbacks(self.0, flags) }
+    }
+
+    /// `length` is the seconds of motion a streak or ribbon covers.
+    pub fn set_render_mode(self, mode: RenderMode, length: f32) {
+        unsafe { sys::set_emitter_render_mode(self.0, mode as i32, length) }
+    }
+}
+
+/// Every three vertices make a triangle.
+pub fn add_triangles(vertices: &[Vertex]) {
+    unsafe { sys::add_triangles(vertices.as_ptr() as *const u8, vertices.len() as i32) }
+}
+
+/// Three indices into `vertices` per triangle.
+pub fn add_mesh(vertices: &[Vertex], indices: &[u32]) {
+    unsafe {
+        sys::add_mesh(vertices.as_ptr() as *const u8, vertices.len() as i32, indices.as_ptr() as *const u8, indices.len() as i32)
+    }
+}
+
+pub fn sin(x: f32) -> f32 {
+    unsafe { sys::sinf(x) }
+}
+
+pub fn cos(x: f32) -> f32 {
+    unsafe { sys::cosf(x) }
+}
+
+pub fn rand() -> i32 {
+    unsafe { sys::rand() }
+}
+
+pub fn log(level: LogLevel, message: &str) {
+    unsafe { sys::log_message(level as i32, message.as_ptr(), message.len() as i32) }
+}
+
+pub fn draw_line(from: Vec3, to: Vec3, color: Color) {
+    unsafe { sys::draw_line(from.x, from.y, from.z, to.x, to.y, to.z, color.pack()) }
+}
+
+pub fn draw_box(min: Vec3, max: Vec3, color: Color) {
+    unsafe { sys::draw_box(min.x, min.y, min.z, max.x, max.y, max.z, color.pack()) }
+}
+
+pub fn draw_sphere_wire(center: Vec3, radius: f32, color: Color) {
+    unsafe { sys::draw_sphere_wire(center.x, center.y, center.z, radius, color.pack()) }
+}
+
+/// x and y in pixels from the top left of the window.
+pub fn draw_text_2d(x: f32, y: f32, text: &str, color: Color) {
+    unsafe { sys::draw_text_2d(x, y, text.as_ptr(), text.len() as i32, color.pack()) }
+}
+
+pub fn draw_text_3d(pos: Vec3, text: &str, color: Color) {
+    unsafe { sys::draw_text_3d(pos.x, pos.y, pos.z, text.as_ptr(), text.len() as i32, color.pack()) }
+}
@@ -0,0 +1,194 @@
+// Generated by src/bin/sdkgen.rs from src/hostapi.rs, do not edit.
+#pragma once
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Host functions, imported from "env".
+
+// Places the camera at pos, looking at lookat.
+void set_camera(float posx, float posy, float posz, float lookatx, float lookaty, float lookatz);
+
+// Draws a particle this frame. color is RGBA8 with red in the lowest byte.
+void add_particle(float posx, float posy, float posz, float size, unsigned color);
+
+// add_particle with float channels in [0, 1].
+void add_particle_rgba(float posx, float posy, float posz, float size, float r, float g, float b, float a);
+
+// add_particle with the hue in degrees and saturation, value and alpha in [0, 1].
+void add_particle_hsv(float posx, float posy, float posz, float size, float h, float s, float v, float a);
+
+// How the script's particle colors are encoded, 0 = linear (the default) or 1 = sRGB. sRGB colors are converted to linear by the host.
+void set_color_space(int space);
+
+float cosf(float x);
+
+float sinf(float x);
+
+void* memcpy(void* dst, const void* src, __SIZE_TYPE__ len);
+
+int rand(void);
+
+// Logs len bytes of str. Levels are 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.
+void log_message(int level, const char* str, int len);
+
+// Logs a printf style message, see log_message for the levels.
+void log_printf(int level, const char* format, ...);
+
+// Creates a host simulated particle emitter and returns its id. Emitters are destroyed when the script is unloaded.
+int create_emitter(void);
+
+// Stops an emitter, its particles live out their lifetime.
+void destroy_emitter(int emitter);
+
+// Emits count particles at once on the next update, on top of the rate.
+void emit_particles(int emitter, int count);
+
+void set_emitter_position(int emitter, float x, float y, float z);
+
+// Initial velocity in units per second, each component randomized by up to +-spread.
+void set_emitter_velocity(int emitter, float x, float y, float z, float spread);
+
+void set_emitter_rate(int emitter, float particles_per_second);
+
+void set_emitter_lifetime(int emitter, float seconds);
+
+// color is packed like add_particle colors, in the script's color space.
+void set_emitter_appearance(int emitter, float size, unsigned color);
+
+// Acceleration in units per second squared, drag is the fraction of velocity lost per second.
+void set_emitter_physics(int emitter, float gravityx, float gravityy, float gravityz, float drag);
+
+// Calls on_particle_spawn (flag 1) and on_particle_death (flag 2) for this emitter's particles.
+void set_emitter_callbacks(int emitter, int flags);
+
+// Changes a particle from on_particle_spawn, which passes its index.
+void set_particle_velocity(int particle, float x, float y, float z);
+
+// Draws this emitter's particles as billboards (mode 0), stretched along their velocity (1) or as ribbons through their past positions (2), length seconds long.
+void set_emitter_render_mode(int emitter, int mode, float length);
+
+// Like set_emitter_render_mode for one particle, from on_particle_spawn.
+void set_particle_render_mode(int particle, int mode, float length);
+
+// Changes a particle from on_particle_spawn, which passes its index.
+void set_particle_color(int particle, unsigned color);
+
+// Draws a debug line this frame. Debug colors are packed like add_particle colors.
+void draw_line(float x0, float y0, float z0, float x1, float y1, float z1, unsigned color);
+
+// Draws the edges of an axis aligned box this frame.
+void draw_box(float minx, float miny, float minz, float maxx, float maxy, float maxz, unsigned color);
+
+// Draws a sphere as three circles this frame.
+void draw_sphere_wire(float x, float y, float z, float radius, unsigned color);
+
+// Draws len bytes of str this frame, x and y in pixels from the top left of the window.
+void draw_text_2d(float x, float y, const char* str, int len, unsigned color);
+
+// Draws len bytes of str this frame, starting at a point in the world.
+void draw_text_3d(float x, float y, float z, const char* str, int len, unsigned color);
+
+// Draws count vertices (ws_vertex) as triangles this frame, every three making one.
+void add_triangles(const void* vertices, int count);
+
+// Draws an indexed mesh this frame. indices are unsigned ints into vertices (ws_vertex), three per triangle.
+void add_mesh(const void* vertices, int vertex_count, const void* indices, int index_count);
+
+// Script exports, implemented by the script. Only tick is required.
+
+// Called every fixed simulation step with the simulation time and the step length, in seconds.
+void tick(float t, float dt);
+
+// Called after the script is loaded.
+void init(void);
+
+// Called before the script is unloaded or reloaded.
+void shutdown(void);
+
+// Called when the swapchain is recreated.
+void on_resize(int width, int height);
+
+// Called for particles of emitters with the spawn callback flag.
+void on_particle_spawn(int emitter, int particle);
+
+// Called where a particle of an emitter with the death callback flag expired.
+void on_particle_death(int emitter, float x, float y, float z);
+
+#ifdef __cplusplus
+}
+#endif
+
+// Helpers
+
+typedef struct ws_vec3 { float x, y, z; } ws_vec3;
+
+static inline ws_vec3 ws_vec3_make(float x, float y, float z) { ws_vec3 v = { x, y, z }; return v; }
+static inline ws_vec3 ws_vec3_add(ws_vec3 a, ws_vec3 b) { return ws_vec3_make(a.x + b.x, a.y + b.y, a.z + b.z); }
+static inline ws_vec3 ws_vec3_sub(ws_vec3 a, ws_vec3 b) { return ws_vec3_make(a.x - b.x, a.y - b.y, a.z - b.z); }
+static inline ws_vec3 ws_vec3_scale(ws_vec3 v, float s) { return ws_vec3_make(v.x * s, v.y * s, v.z * s); }
+static inline float ws_vec3_dot(ws_vec3 a, ws_vec3 b) { return a.x * b.x + a.y * b.y + a.z * b.z; }
+
+// add_particle colors are RGBA8 with red in the lowest byte, matching the Rgba8Unorm vertex attribute.
+typedef struct ws_color { unsigned char r, g, b, a; } ws_color;
+
+static inline ws_color ws_color_rgba(unsigned char r, unsigned char g, unsigned char b, unsigned char a) { ws_color c = { r, g, b, a }; return c; }
+
+static inline unsigned ws_pack_rgba(unsigned char r, unsigned char g, unsigned char b, unsigned char a) {
+  return (unsigned)r | ((unsigned)g << 8) | ((unsigned)b << 16) | ((unsigned)a << 24);
+}
+
+static inline unsigned ws_color_pack(ws_color c) { return ws_pack_rgba(c.r, c.g, c.b, c.a); }
+
+static inline void ws_set_camera(ws_vec3 pos, ws_vec3 lookat) { set_camera(pos.x, pos.y, pos.z, lookat.x, lookat.y, lookat.z); }
+
+static inline void ws_add_particle(ws_vec3 pos, float size, ws_color color) { add_particle(pos.x, pos.y, pos.z, size, ws_color_pack(color)); }
+
+static inline void ws_add_particle_rgba(ws_vec3 pos, float size, float r, float g, float b, float a) { add_particle_rgba(pos.x, pos.y, pos.z, size, r, g, b, a); }
+
+static inline void ws_add_particle_hsv(ws_vec3 pos, float size, float h, float s, float v, float a) { add_particle_hsv(pos.x, pos.y, pos.z, size, h, s, v, a); }
+
+// add_triangles and add_mesh vertices, 36 bytes. color is packed like add_particle colors.
+typedef struct ws_vertex { ws_vec3 position; ws_vec3 normal; float u, v; unsigned color; } ws_vertex;
+
+static inline ws_vertex ws_vertex_make(ws_vec3 position, ws_vec3 normal, float u, float v, ws_color color) {
+  ws_vertex vertex = { position, normal, u, v, ws_color_pack(color) };
+  return vertex;
+}
+
+// set_color_space values.
+#define WS_COLOR_SPACE_LINEAR 0
+#define WS_COLOR_SPACE_SRGB 1
+
+// set_emitter_callbacks flags.
+#define WS_CALLBACK_SPAWN 1
+#define WS_CALLBACK_DEATH 2
+
+// set_emitter_render_mode and set_particle_render_mode modes.
+#define WS_RENDER_BILLBOARD 0
+#define WS_RENDER_STRETCHED 1
+#define WS_RENDER_RIBBON 2
+
+static inline void ws_set_emitter_position(int emitter, ws_vec3 pos) { set_emitter_position(emitter, pos.x, pos.y, pos.z); }
+
+static inline void ws_set_emitter_velocity(int emitter, ws_vec3 velocity, float spread) { set_emitter_velocity(emitter, velocity.x, velocity.y, velocity.z, spread); }
+
+static inline void ws_set_emitter_appearance(int emitter, float size, ws_color color) { set_emitter_appearance(emitter, size, ws_color_pack(color)); }
+
+static inline void ws_set_emitter_physics(int emitter, ws_vec3 gravity, float drag) { set_emitter_physics(emitter, gravity.x, gravity.y, gravity.z, drag); }
+
+#define WS_LOG_ERROR 1
+#define WS_LOG_WARN 2
+#define WS_LOG_INFO 3
+#define WS_LOG_DEBUG 4
+#define WS_LOG_TRACE 5
+
+// Logs a NUL terminated string.
+static inline void ws_log(int level, const char* str) {
+  int len = 0;
+  while (str[len]) {
+    len++;
+  }
+  log_message(level, str, len);
+}
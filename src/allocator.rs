@@ -0,0 +1,292 @@
+use hal::{
+    adapter::MemoryType,
+    memory as m,
+};
+use hal::{Device};
+
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::backenderror::{BackendError, BackendMemory, BackendDevice};
+use crate::freelist::{self, FreeList};
+
+// Device memory for buffers and images. Devices allow only so many memory allocations (4096 is common)
+// and each one is slow, so small buffers share bigger blocks, one set of blocks per memory type, and
+// only big buffers and images get a block of their own. Host visible blocks stay mapped until they
+// are freed.
+
+// Size of the shared blocks. Requests over half of it get a dedicated block.
+const BLOCK_SIZE : u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryUsage
+{
+    // Written by the CPU, read by the GPU: host visible, preferably coherent and uncached.
+    Upload,
+    // Written by the GPU, read by the CPU: host visible, preferably cached.
+    Readback,
+    // Only used by the GPU: device local if there is such memory. The CPU goes through a staging
+    // buffer of one of the other kinds and a copy.
+    DeviceLocal,
+}
+
+struct Block
+{
+    memory : Rc<BackendMemory>,
+    ranges : FreeList,
+    // The whole block, when it is host visible.
+    mapping : Option<NonNull<u8>>,
+    dedicated : bool,
+}
+
+// A range of a block. Give it back with GpuAllocator::free once nothing uses it.
+pub struct Allocation
+{
+    memory : Rc<BackendMemory>,
+    memory_type : usize,
+    range : Range<u64>,
+    // The start of `range` in the block's mapping.
+    mapping : Option<NonNull<u8>>,
+    // Non-coherent memory needs flushes and invalidations, in multiples of this many bytes.
+    atom_size : Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AllocatorStats
+{
+    // Device memory allocations, dedicated ones included, and their total size.
+    pub blocks : u32,
+    pub dedicated : u32,
+    pub reserved : u64,
+    // Live allocations and the bytes they take up.
+    pub allocations : u32,
+    pub used : u64,
+}
+
+pub struct GpuAllocator
+{
+    memory_types : Vec<MemoryType>,
+    atom_size : u64,
+    // Indexed by memory type.
+    blocks : Vec<Vec<Block>>,
+    stats : AllocatorStats,
+}
+
+fn count(flags : m::Properties) -> u32 {
+    flags.bits().count_ones()
+}
+
+// The allowed memory type with the most preferred and fewest unwanted properties, the lowest index on
+// a tie as drivers list the faster types first.
+fn find_memory_type(memory_types : &[MemoryType], type_mask : u64, usage : MemoryUsage) -> Option<usize> {
+    let (required, preferred, unwanted) = match usage {
+        // Device local host visible memory tends to be a small window, leave it to what needs it.
+        MemoryUsage::Upload => (m::Properties::CPU_VISIBLE, m::Properties::COHERENT, m::Properties::CPU_CACHED | m::Properties::DEVICE_LOCAL),
+        MemoryUsage::Readback => (m::Properties::CPU_VISIBLE, m::Properties::CPU_CACHED | m::Properties::COHERENT, m::Properties::DEVICE_LOCAL),
+        MemoryUsage::DeviceLocal => (m::Properties::DEVICE_LOCAL, m::Properties::empty(), m::Properties::CPU_VISIBLE),
+    };
+
+    let find = |required : m::Properties| memory_types.iter()
+        .enumerate()
+        .filter(|(id, memory_type)| type_mask & (1 << id) != 0 && memory_type.properties.contains(required))
+        .max_by_key(|(id, memory_type)| {
+            let properties = memory_type.properties;
+            (count(properties & preferred), std::cmp::Reverse(count(properties & unwanted)), std::cmp::Reverse(*id))
+        })
+        .map(|(id, _)| id);
+
+    match usage {
+        // Some software renderers have no device local memory, anything will do there.
+        MemoryUsage::DeviceLocal => find(required).or_else(|| find(m::Properties::empty())),
+        _ => find(required),
+    }
+}
+
+impl Allocation
+{
+    pub fn memory(&self) -> &BackendMemory {
+        &self.memory
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.range.start
+    }
+
+    pub fn size(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    // The allocation's bytes, None unless it is host visible.
+    pub fn mapping(&self) -> Option<NonNull<u8>> {
+        self.mapping
+    }
+
+    fn mapped_range(&self, bytes : u64, atom_size : u64) -> Range<u64> {
+        self.range.start .. self.range.start + freelist::align_up(bytes, atom_size).min(self.size())
+    }
+
+    // Makes CPU writes to the first `bytes` bytes visible to the GPU.
+    pub fn flush(&self, device : &BackendDevice, bytes : u64) {
+        if let Some(atom_size) = self.atom_size.filter(|_| bytes > 0) {
+            let range = self.mapped_range(bytes, atom_size);
+            unsafe { device.flush_mapped_memory_ranges(Some((&*self.memory, range))) }.expect("Can't flush mapped memory");
+        }
+    }
+
+    // Makes GPU writes to the first `bytes` bytes visible to the CPU.
+    pub fn invalidate(&self, device : &BackendDevice, bytes : u64) {
+        if let Some(atom_size) = self.atom_size.filter(|_| bytes > 0) {
+            let range = self.mapped_range(bytes, atom_size);
+            unsafe { device.invalidate_mapped_memory_ranges(Some((&*self.memory, range))) }.expect("Can't invalidate mapped memory");
+        }
+    }
+}
+
+impl GpuAllocator
+{
+    pub fn new(memory_properties : &hal::adapter::MemoryProperties, limits : &hal::Limits) -> GpuAllocator {
+        GpuAllocator {
+            memory_types : memory_properties.memory_types.clone(),
+            atom_size : (limits.non_coherent_atom_size as u64).max(1),
+            blocks : memory_properties.memory_types.iter().map(|_| Vec::new()).collect(),
+            stats : AllocatorStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        self.stats
+    }
+
+    // Memory for a buffer.
+    pub fn allocate(&mut self, device : &BackendDevice, requirements : &m::Requirements, usage : MemoryUsage) -> Result<Allocation, BackendError> {
+        self.allocate_in_block(device, requirements, usage, requirements.size > BLOCK_SIZE / 2)
+    }
+
+    // Memory for an image, always a block of its own: sharing one with buffers would have to keep them
+    // the device's buffer image granularity apart.
+    pub fn allocate_image(&mut self, device : &BackendDevice, requirements : &m::Requirements, usage : MemoryUsage) -> Result<Allocation, BackendError> {
+        self.allocate_in_block(device, requirements, usage, true)
+    }
+
+    fn allocate_in_block(&mut self, device : &BackendDevice, requirements : &m::Requirements, usage : MemoryUsage, dedicated : bool)
+        -> Result<Allocation, BackendError> {
+        let memory_type = find_memory_type(&self.memory_types, requirements.type_mask, usage).ok_or(BackendError::NoMemoryType(usage))?;
+        let properties = self.memory_types[memory_type].properties;
+        let visible = properties.contains(m::Properties::CPU_VISIBLE);
+        let atom_size = if visible && !properties.contains(m::Properties::COHERENT) { Some(self.atom_size) } else { None };
+
+        // Flushing one allocation must not reach into its neighbours, so non-coherent ones take whole atoms.
+        let (size, alignment) = match atom_size {
+            Some(atom_size) => (freelist::align_up(requirements.size, atom_size), requirements.alignment.max(atom_size)),
+            None => (requirements.size, requirements.alignment),
+        };
+
+        let blocks = &mut self.blocks[memory_type];
+        let shared = blocks.iter_mut()
+            .enumerate()
+            .filter(|(_, block)| !dedicated && !block.dedicated)
+            .find_map(|(index, block)| block.ranges.allocate(size, alignment).map(|range| (index, range)));
+
+        let (index, range) = match shared {
+            Some(found) => found,
+            None => {
+                let block_size = if dedicated { size } else { BLOCK_SIZE };
+                let memory = unsafe { device.allocate_memory(memory_type.into(), block_size) }?;
+                let mapping = if visible {
+                    match unsafe { device.map_memory(&memory, 0 .. block_size) } {
+                        Ok(pointer) => NonNull::new(pointer),
+                        Err(error) => {
+                            unsafe { device.free_memory(memory) };
+                            return Err(error.into());
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let mut ranges = FreeList::new(block_size);
+                let range = ranges.allocate(size, alignment).unwrap();
+                blocks.push(Block { memory : Rc::new(memory), ranges : ranges, mapping : mapping, dedicated : dedicated });
+
+                self.stats.blocks += 1;
+                self.stats.reserved += block_size;
+                if dedicated {
+                    self.stats.dedicated += 1;
+                }
+                (blocks.len() - 1, range)
+            }
+        };
+
+        self.stats.allocations += 1;
+        self.stats.used += range.end - range.start;
+
+        let block = &blocks[index];
+        Ok(Allocation {
+            memory : block.memory.clone(),
+            memory_type : memory_type,
+            mapping : block.mapping.map(|mapping| unsafe { NonNull::new_unchecked(mapping.as_ptr().add(range.start as usize)) }),
+            range : range,
+            atom_size : atom_size,
+        })
+    }
+
+    // The GPU must be done with whatever was bound to the allocation. Blocks are freed once empty.
+    pub fn free(&mut self, device : &BackendDevice, allocation : Allocation) {
+        let Allocation { memory, memory_type, range, .. } = allocation;
+        let blocks = &mut self.blocks[memory_type];
+        let index = blocks.iter()
+            .position(|block| Rc::ptr_eq(&block.memory, &memory))
+            .expect("Allocation from another allocator");
+        drop(memory);
+
+        self.stats.allocations -= 1;
+        self.stats.used -= range.end - range.start;
+
+        blocks[index].ranges.free(range);
+        if blocks[index].ranges.is_unused() {
+            let block = blocks.swap_remove(index);
+            self.release(device, block);
+        }
+    }
+
+    fn release(&mut self, device : &BackendDevice, block : Block) {
+        self.stats.blocks -= 1;
+        self.stats.reserved -= block.ranges.size();
+        if block.dedicated {
+            self.stats.dedicated -= 1;
+        }
+
+        match Rc::try_unwrap(block.memory) {
+            Ok(memory) => unsafe {
+                if block.mapping.is_some() {
+                    device.unmap_memory(&memory);
+                }
+                device.free_memory(memory);
+            },
+            // Still bound to a buffer or image somebody forgot to destroy.
+            Err(_) => println!("GPU allocator: leaking a block of {} bytes that is still in use", block.ranges.size()),
+        }
+    }
+
+    // Frees every block. Everything allocated should have been freed by now, and the GPU must be idle.
+    pub fn destroy(mut self, device : &BackendDevice) {
+        if self.stats.allocations > 0 {
+            println!("GPU allocator: {} allocations ({} bytes) were not freed", self.stats.allocations, self.stats.used);
+        }
+
+        let blocks : Vec<Block> = self.blocks.iter_mut().flat_map(|blocks| blocks.drain(..)).collect();
+        for block in blocks {
+            self.release(device, block);
+        }
+    }
+}
+
+impl Drop for GpuAllocator
+{
+    fn drop(&mut self) {
+        if self.stats.blocks > 0 {
+            println!("GPU allocator dropped without destroy, leaking {} blocks ({} bytes)", self.stats.blocks, self.stats.reserved);
+        }
+    }
+}
@@ -2,11 +2,16 @@ pub type BackendDevice = <back::Backend as hal::Backend>::Device;
 pub type BackendBuffer = <back::Backend as hal::Backend>::Buffer;
 pub type BackendMemory = <back::Backend as hal::Backend>::Memory;
 
+use crate::allocator::MemoryUsage;
+
 pub enum BackendError
 {
     AllocationError(hal::device::AllocationError),
     BufferCreationError(hal::buffer::CreationError),
-    BindError(hal::device::BindError)
+    BindError(hal::device::BindError),
+    MappingError(hal::mapping::Error),
+    // None of the memory types the resource allows suit the usage.
+    NoMemoryType(MemoryUsage)
 }
 
 impl From<hal::device::AllocationError> for BackendError
@@ -28,4 +33,11 @@ impl From<hal::device::BindError> for BackendError
     fn from(error: hal::device::BindError) -> Self {
         BackendError::BindError(error)
     }
-}
\ No newline at end of file
+}
+
+impl From<hal::mapping::Error> for BackendError
+{
+    fn from(error: hal::mapping::Error) -> Self {
+        BackendError::MappingError(error)
+    }
+}
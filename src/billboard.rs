@@ -0,0 +1,228 @@
+use nalgebra_glm as glm;
+use glm::Vec3;
+
+use std::collections::VecDeque;
+use std::thread;
+
+// Camera facing geometry for particles, in the quad pipeline's vertex format: plain billboards,
+// billboards stretched along the velocity for streaks, and ribbons along a particle's past positions.
+// data/quad.frag fades each quad out from the middle of its uv square.
+//
+// Every sprite has a vertex count known up front, so write_sprites can split a frame's sprites across
+// threads that each fill their own part of the mapped vertex buffer.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_snake_case)]
+pub struct Vertex {
+    pub a_Pos: [f32; 4],
+    pub a_Uv: [f32; 2],
+    pub a_Color: u32,
+}
+
+pub const QUAD_VERTICES : usize = 6;
+
+// Shorter streaks and ribbon segments than this are drawn as plain billboards or collapsed.
+const MIN_LENGTH : f32 = 1e-5;
+
+// Below this many sprites per thread, starting the threads costs more than it saves.
+const MIN_SPRITES_PER_THREAD : usize = 8192;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Sprite<'a>
+{
+    // A square of half width `size` facing the camera.
+    Billboard { position : Vec3, size : f32, color : u32 },
+    // A streak from where the particle was `length` seconds ago at its current velocity to where it
+    // is, `size` wide and rounded off by `size` at both ends. Slow particles stay billboards.
+    Stretched { position : Vec3, velocity : Vec3, length : f32, size : f32, color : u32 },
+    // A strip through `history`, oldest first, and then `position`, `size` wide at `position` and
+    // narrowing and fading towards the oldest point. Without history it is a billboard.
+    Ribbon { history : &'a VecDeque<Vec3>, position : Vec3, size : f32, color : u32 },
+}
+
+impl<'a> Sprite<'a>
+{
+    pub fn vertex_count(&self) -> usize {
+        match self {
+            Sprite::Ribbon { history, .. } if !history.is_empty() => history.len() * QUAD_VERTICES,
+            _ => QUAD_VERTICES,
+        }
+    }
+
+    // Fills exactly vertex_count vertices. `points` is scratch space for ribbons.
+    fn write(&self, out : &mut [Vertex], camera_position : Vec3, up : Vec3, points : &mut Vec<Vec3>) {
+        match *self {
+            Sprite::Billboard { position, size, color } => write_billboard(out, position, size, color, camera_position, up),
+            Sprite::Stretched { position, velocity, length, size, color } =>
+                write_stretched(out, position, velocity, length, size, color, camera_position, up),
+            Sprite::Ribbon { history, position, size, color } => {
+                points.clear();
+                points.extend(history.iter().cloned());
+                points.push(position);
+                write_ribbon(out, points, size, color, camera_position, up);
+            }
+        }
+    }
+}
+
+fn vertex(position : Vec3, uv : [f32; 2], color : u32) -> Vertex {
+    Vertex { a_Pos: [position.x, position.y, position.z, 1.0], a_Uv: uv, a_Color: color }
+}
+
+// Two triangles, corners in the order (0, 1), (1, 1), (1, 0), (0, 0) of uv.
+fn write_quad(out : &mut [Vertex], corners : [Vec3; 4], uvs : [[f32; 2]; 4], colors : [u32; 4]) {
+    let v = |i : usize| vertex(corners[i], uvs[i], colors[i]);
+    out[.. QUAD_VERTICES].copy_from_slice(&[v(0), v(1), v(2), v(0), v(2), v(3)]);
+}
+
+// Scales every channel, alpha included, as quad.frag does for its fade.
+fn fade(color : u32, amount : f32) -> u32 {
+    let amount = amount.max(0.0).min(1.0);
+    let mut faded = 0;
+    for shift in [0, 8, 16, 24].iter() {
+        let channel = ((color >> shift) & 0xff) as f32 * amount;
+        faded |= (channel.round() as u32) << shift;
+    }
+    faded
+}
+
+// Unit vector across `direction` as seen from `eye`, None when looking along it.
+fn side(direction : Vec3, position : Vec3, eye : Vec3) -> Option<Vec3> {
+    let side = direction.cross(&(eye - position));
+    let length = glm::length(&side);
+    if length > MIN_LENGTH { Some(side / length) } else { None }
+}
+
+fn write_billboard(out : &mut [Vertex], world_position : Vec3, size : f32, color : u32, camera_position : Vec3, up : Vec3) {
+    let n = camera_position - world_position;
+    let n = glm::normalize(&n);
+
+    let r = n.cross(&up);
+    let u = r.cross(&n);
+
+    let v0 = world_position - r * size + u * size;
+    let v1 = world_position + r * size + u * size;
+    let v2 = world_position + r * size - u * size;
+    let v3 = world_position - r * size - u * size;
+
+    write_quad(out, [v0, v1, v2, v3], [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]], [color; 4]);
+}
+
+fn write_stretched(out : &mut [Vertex], world_position : Vec3, velocity : Vec3, length : f32, size : f32, color : u32,
+                   camera_position : Vec3, up : Vec3) {
+    let streak = velocity * length;
+    let streak_length = glm::length(&streak);
+    let across = side(streak, world_position, camera_position);
+    let across = match across {
+        Some(across) if streak_length > MIN_LENGTH => across * size,
+        _ => return write_billboard(out, world_position, size, color, camera_position, up),
+    };
+
+    let along = streak / streak_length * size;
+    let head = world_position + along;
+    let tail = world_position - streak - along;
+
+    write_quad(out, [tail + across, head + across, head - across, tail - across],
+               [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]], [color; 4]);
+}
+
+// One quad per segment between `points`; segments with nothing to draw collapse to a point so the
+// count stays fixed.
+fn write_ribbon(out : &mut [Vertex], points : &[Vec3], size : f32, color : u32, camera_position : Vec3, up : Vec3) {
+    if points.len() < 2 {
+        return write_billboard(out, points[0], size, color, camera_position, up);
+    }
+
+    // Each point's edge is shared by the segments on both sides of it, so bends don't leave gaps.
+    let last = points.len() - 1;
+    let mut previous_side = None;
+    let mut previous_edge = None;
+    for (i, point) in points.iter().enumerate() {
+        let tangent = points[(i + 1).min(last)] - points[i.max(1) - 1];
+        let across = side(tangent, *point, camera_position).or(previous_side);
+        previous_side = across;
+
+        let amount = i as f32 / last as f32;
+        let edge = (across.map(|across| across * size * amount), fade(color, amount));
+        if i == 0 {
+            previous_edge = Some(edge);
+            continue;
+        }
+
+        let (a, b) = (points[i - 1], *point);
+        let quad = &mut out[(i - 1) * QUAD_VERTICES .. i * QUAD_VERTICES];
+        match (previous_edge, edge) {
+            (Some((Some(across_a), color_a)), (Some(across_b), color_b)) if glm::distance(&a, &b) >= MIN_LENGTH => {
+                // u stays in the middle so quad.frag fades the strip across its width only.
+                write_quad(quad, [a + across_a, b + across_b, b - across_b, a - across_a],
+                           [[0.5, 1.0], [0.5, 1.0], [0.5, 0.0], [0.5, 0.0]], [color_a, color_b, color_b, color_a]);
+            }
+            _ => write_quad(quad, [a; 4], [[0.5, 0.5]; 4], [0; 4]),
+        }
+        previous_edge = Some(edge);
+    }
+}
+
+// Writes the sprites that fit into `out` from the start, on up to `max_threads` threads. Returns the
+// number of vertices written.
+pub fn write_sprites(sprites : &[Sprite], out : &mut [Vertex], camera_position : Vec3, up : Vec3, max_threads : usize) -> usize {
+    let mut total = 0;
+    let mut fitting = 0;
+    for sprite in sprites {
+        let count = sprite.vertex_count();
+        if total + count > out.len() {
+            break;
+        }
+        total += count;
+        fitting += 1;
+    }
+    let sprites = &sprites[.. fitting];
+
+    let threads = (sprites.len() / MIN_SPRITES_PER_THREAD).clamp(1, max_threads.max(1));
+    if threads == 1 {
+        let mut points = Vec::new();
+        let mut offset = 0;
+        for sprite in sprites {
+            let count = sprite.vertex_count();
+            sprite.write(&mut out[offset .. offset + count], camera_position, up, &mut points);
+            offset += count;
+        }
+        return total;
+    }
+
+    // Contiguous runs of sprites of about the same number of vertices, each with its own part of `out`.
+    thread::scope(|scope| {
+        let mut remaining_out = &mut out[.. total];
+        let mut remaining_sprites = sprites;
+        for thread_index in 0..threads {
+            let target = remaining_out.len() / (threads - thread_index);
+            let mut count = 0;
+            let mut end = 0;
+            while end < remaining_sprites.len() && (count < target || thread_index == threads - 1) {
+                count += remaining_sprites[end].vertex_count();
+                end += 1;
+            }
+
+            let (chunk_sprites, rest_sprites) = remaining_sprites.split_at(end);
+            let (chunk_out, rest_out) = std::mem::take(&mut remaining_out).split_at_mut(count);
+            remaining_sprites = rest_sprites;
+            remaining_out = rest_out;
+
+            scope.spawn(move || {
+                let mut points = Vec::new();
+                let mut offset = 0;
+                for sprite in chunk_sprites {
+                    let count = sprite.vertex_count();
+                    sprite.write(&mut chunk_out[offset .. offset + count], camera_position, up, &mut points);
+                    offset += count;
+                }
+            });
+        }
+    });
+    total
+}
+
+// Threads write_sprites should use on this machine.
+pub fn worker_threads() -> usize {
+    thread::available_parallelism().map_or(1, |threads| threads.get())
+}
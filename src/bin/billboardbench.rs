@@ -0,0 +1,109 @@
+// Times writing particle quads into a vertex buffer on one thread against all of them:
+//
+//   cargo run --release --bin billboardbench              200k particles
+//   cargo run --release --bin billboardbench -- 1000000   or any other count
+//   cargo run --release --bin billboardbench -- --threads=4
+//
+// Plain memory stands in for the mapped vertex buffer. Each case reports the best of RUNS runs.
+
+use nalgebra_glm as glm;
+use glm::{Vec3, vec3};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../billboard.rs"]
+mod billboard;
+
+use billboard::{Sprite, Vertex};
+
+const DEFAULT_PARTICLES : usize = 200_000;
+const RUNS : usize = 20;
+const RIBBON_POINTS : usize = 8;
+
+// Deterministic positions, so runs compare the same work.
+struct Lcg(u32);
+
+impl Lcg
+{
+    fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn point(&mut self) -> Vec3 {
+        vec3(self.next() * 20.0 - 10.0, self.next() * 20.0 - 10.0, self.next() * 20.0 - 10.0)
+    }
+}
+
+fn best_of<F : FnMut()>(mut run : F) -> Duration {
+    (0..RUNS).map(|_| {
+        let start = Instant::now();
+        run();
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn millis(duration : Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn bench(name : &str, sprites : &[Sprite], threads : usize) {
+    let (camera_position, up) = (vec3(0.0, 0.0, 30.0), vec3(0.0, 1.0, 0.0));
+    let vertex_count : usize = sprites.iter().map(Sprite::vertex_count).sum();
+    let empty = Vertex { a_Pos: [0.0; 4], a_Uv: [0.0; 2], a_Color: 0 };
+    let mut mapped = vec![empty; vertex_count];
+
+    // How the client used to do it: build the vertices in a Vec, then copy them over one by one.
+    let copied = best_of(|| {
+        let mut vertices = vec![empty; vertex_count];
+        billboard::write_sprites(sprites, &mut vertices, camera_position, up, 1);
+        for (i, v) in vertices.iter().enumerate() {
+            mapped[i] = *v;
+        }
+    });
+    let single = best_of(|| { billboard::write_sprites(sprites, &mut mapped, camera_position, up, 1); });
+    let reference = mapped.clone();
+    let parallel = best_of(|| { billboard::write_sprites(sprites, &mut mapped, camera_position, up, threads); });
+    assert!(mapped == reference, "{}: threads wrote different vertices", name);
+
+    println!("{}: {} sprites, {} vertices", name, sprites.len(), vertex_count);
+    println!("  1 thread + copy  {:8.2} ms", millis(copied));
+    println!("  1 thread         {:8.2} ms", millis(single));
+    println!("  {:2} threads       {:8.2} ms  {:.1}x", threads, millis(parallel), millis(single) / millis(parallel));
+}
+
+fn main() {
+    let mut particles = DEFAULT_PARTICLES;
+    let mut threads = billboard::worker_threads();
+    for arg in std::env::args().skip(1) {
+        if let Some(count) = arg.strip_prefix("--threads=") {
+            threads = count.parse().expect("Thread count expected");
+        } else {
+            particles = arg.parse().expect("Particle count expected");
+        }
+    }
+    let mut random = Lcg(1);
+
+    let billboards : Vec<Sprite> = (0..particles).map(|_| {
+        Sprite::Billboard { position : random.point(), size : 0.05, color : 0xffffffff }
+    }).collect();
+    bench("billboards", &billboards, threads);
+
+    // Half billboards, a quarter streaks and a quarter ribbons, as a script with trails would give.
+    let histories : Vec<VecDeque<Vec3>> = (0..particles / 4).map(|_| {
+        let start = random.point();
+        (0..RIBBON_POINTS).map(|i| start + vec3(0.0, i as f32 * 0.02, 0.0)).collect()
+    }).collect();
+    let mut mixed = Vec::with_capacity(particles);
+    for i in 0..particles {
+        let (position, size, color) = (random.point(), 0.05, 0xff80c0ff);
+        mixed.push(match i % 4 {
+            0 | 1 => Sprite::Billboard { position : position, size : size, color : color },
+            2 => Sprite::Stretched { position : position, velocity : vec3(1.0, 2.0, 0.0), length : 0.1, size : size, color : color },
+            _ => Sprite::Ribbon { history : &histories[i / 4], position : position, size : size, color : color },
+        });
+    }
+    bench("mixed", &mixed, threads);
+}
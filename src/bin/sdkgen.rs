@@ -0,0 +1,497 @@
+// Generates the guest SDKs in sdk/ from the host API table:
+//
+//   cargo run --bin sdkgen             rewrites sdk/webasm_scripting.h and sdk/rust/src/lib.rs
+//   cargo run --bin sdkgen -- --check  fails if they are out of date, for CI
+//
+// The declarations come from hostapi.rs, the typed vector and color helpers are fixed text below.
+
+use std::fmt::Write;
+use std::fs;
+use std::process;
+
+#[allow(dead_code)]
+#[path = "../hostapi.rs"]
+mod hostapi;
+
+use hostapi::{ArgType, Arg, ValueType, HOST_FUNCTIONS, SCRIPT_EXPORTS, HOST_NAMESPACE};
+
+const C_HEADER_PATH : &str = "sdk/webasm_scripting.h";
+const RUST_SDK_PATH : &str = "sdk/rust/src/lib.rs";
+
+fn lower(ty : ArgType) -> ValueType {
+    match ty {
+        ArgType::Float => ValueType::F32,
+        _ => ValueType::I32,
+    }
+}
+
+// The SDK spelling of a function has to lower to exactly the signature the host checks modules against.
+fn check_lowering(name : &str, args : &[Arg], result : Option<ArgType>, params : &[ValueType], returns : &[ValueType]) {
+    let lowered_params : Vec<ValueType> = args.iter().map(|arg| lower(arg.ty)).collect();
+    let lowered_returns : Vec<ValueType> = result.into_iter().map(lower).collect();
+
+    if lowered_params != params || lowered_returns != returns {
+        eprintln!("{}: SDK arguments lower to {}, the table says {}", name,
+            hostapi::format_signature(&lowered_params, &lowered_returns), hostapi::format_signature(params, returns));
+        process::exit(1);
+    }
+}
+
+fn c_type(ty : ArgType) -> &'static str {
+    match ty {
+        ArgType::Int => "int",
+        ArgType::UInt => "unsigned",
+        ArgType::Float => "float",
+        ArgType::Size => "__SIZE_TYPE__",
+        ArgType::Ptr => "void*",
+        ArgType::ConstPtr => "const void*",
+        ArgType::Str => "const char*",
+        ArgType::VarArgs => "...",
+    }
+}
+
+fn c_declaration(name : &str, args : &[Arg], result : Option<ArgType>) -> String {
+    let params : Vec<String> = args.iter().map(|arg| match arg.ty {
+        ArgType::VarArgs => "...".to_string(),
+        ty => format!("{} {}", c_type(ty), arg.name),
+    }).collect();
+    let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+
+    format!("{} {}({});", result.map_or("void", c_type), name, params)
+}
+
+fn rust_type(ty : ArgType) -> &'static str {
+    match ty {
+        ArgType::Int => "i32",
+        ArgType::UInt => "u32",
+        ArgType::Float => "f32",
+        ArgType::Size => "usize",
+        ArgType::Ptr => "*mut u8",
+        ArgType::ConstPtr => "*const u8",
+        ArgType::Str => "*const u8",
+        ArgType::VarArgs => "...",
+    }
+}
+
+fn rust_declaration(name : &str, args : &[Arg], result : Option<ArgType>) -> String {
+    let params : Vec<String> = args.iter().map(|arg| match arg.ty {
+        ArgType::VarArgs => "...".to_string(),
+        ty => format!("{}: {}", arg.name, rust_type(ty)),
+    }).collect();
+    let returns = result.map_or(String::new(), |ty| format!(" -> {}", rust_type(ty)));
+
+    format!("fn {}({}){}", name, params.join(", "), returns)
+}
+
+fn c_header() -> String {
+    let mut out = String::new();
+
+    writeln!(out, "// Generated by src/bin/sdkgen.rs from src/hostapi.rs, do not edit.").unwrap();
+    writeln!(out, "#pragma once").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#ifdef __cplusplus").unwrap();
+    writeln!(out, "extern \"C\" {{").unwrap();
+    writeln!(out, "#endif").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "// Host functions, imported from \"{}\".", HOST_NAMESPACE).unwrap();
+    for function in HOST_FUNCTIONS {
+        check_lowering(function.name, function.args, function.result, function.signature.params, function.signature.returns);
+        writeln!(out).unwrap();
+        if !function.doc.is_empty() {
+            writeln!(out, "// {}", function.doc).unwrap();
+        }
+        writeln!(out, "{}", c_declaration(function.name, function.args, function.result)).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "// Script exports, implemented by the script. Only tick is required.").unwrap();
+    for export in SCRIPT_EXPORTS {
+        check_lowering(export.name, export.args, None, export.signature.params, export.signature.returns);
+        writeln!(out).unwrap();
+        writeln!(out, "// {}", export.doc).unwrap();
+        writeln!(out, "{}", c_declaration(export.name, export.args, None)).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "#ifdef __cplusplus").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "#endif").unwrap();
+    out.push_str(C_HELPERS);
+
+    out
+}
+
+fn rust_sdk() -> String {
+    let mut out = String::new();
+
+    writeln!(out, "//! Generated by src/bin/sdkgen.rs from src/hostapi.rs, do not edit.").unwrap();
+    writeln!(out, "//!").unwrap();
+    writeln!(out, "//! Scripts implement these exports with `#[no_mangle] pub extern \"C\"`:").unwrap();
+    writeln!(out, "//!").unwrap();
+    for export in SCRIPT_EXPORTS {
+        let required = if export.required { "required" } else { "optional" };
+        writeln!(out, "//! - `{}` ({}): {}", rust_declaration(export.name, export.args, None), required, export.doc).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "#![no_std]").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "/// Raw host imports.").unwrap();
+    writeln!(out, "pub mod sys {{").unwrap();
+    writeln!(out, "    #[link(wasm_import_module = \"{}\")]", HOST_NAMESPACE).unwrap();
+    writeln!(out, "    extern \"C\" {{").unwrap();
+    for (index, function) in HOST_FUNCTIONS.iter().enumerate() {
+        if index > 0 {
+            writeln!(out).unwrap();
+        }
+        if !function.doc.is_empty() {
+            writeln!(out, "        /// {}", function.doc).unwrap();
+        }
+        writeln!(out, "        pub {};", rust_declaration(function.name, function.args, function.result)).unwrap();
+    }
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    out.push_str(RUST_HELPERS);
+
+    out
+}
+
+const C_HELPERS : &str = r#"
+// Helpers
+
+typedef struct ws_vec3 { float x, y, z; } ws_vec3;
+
+static inline ws_vec3 ws_vec3_make(float x, float y, float z) { ws_vec3 v = { x, y, z }; return v; }
+static inline ws_vec3 ws_vec3_add(ws_vec3 a, ws_vec3 b) { return ws_vec3_make(a.x + b.x, a.y + b.y, a.z + b.z); }
+static inline ws_vec3 ws_vec3_sub(ws_vec3 a, ws_vec3 b) { return ws_vec3_make(a.x - b.x, a.y - b.y, a.z - b.z); }
+static inline ws_vec3 ws_vec3_scale(ws_vec3 v, float s) { return ws_vec3_make(v.x * s, v.y * s, v.z * s); }
+static inline float ws_vec3_dot(ws_vec3 a, ws_vec3 b) { return a.x * b.x + a.y * b.y + a.z * b.z; }
+
+// add_particle colors are RGBA8 with red in the lowest byte, matching the Rgba8Unorm vertex attribute.
+typedef struct ws_color { unsigned char r, g, b, a; } ws_color;
+
+static inline ws_color ws_color_rgba(unsigned char r, unsigned char g, unsigned char b, unsigned char a) { ws_color c = { r, g, b, a }; return c; }
+
+static inline unsigned ws_pack_rgba(unsigned char r, unsigned char g, unsigned char b, unsigned char a) {
+  return (unsigned)r | ((unsigned)g << 8) | ((unsigned)b << 16) | ((unsigned)a << 24);
+}
+
+static inline unsigned ws_color_pack(ws_color c) { return ws_pack_rgba(c.r, c.g, c.b, c.a); }
+
+static inline void ws_set_camera(ws_vec3 pos, ws_vec3 lookat) { set_camera(pos.x, pos.y, pos.z, lookat.x, lookat.y, lookat.z); }
+
+static inline void ws_add_particle(ws_vec3 pos, float size, ws_color color) { add_particle(pos.x, pos.y, pos.z, size, ws_color_pack(color)); }
+
+static inline void ws_add_particle_rgba(ws_vec3 pos, float size, float r, float g, float b, float a) { add_particle_rgba(pos.x, pos.y, pos.z, size, r, g, b, a); }
+
+static inline void ws_add_particle_hsv(ws_vec3 pos, float size, float h, float s, float v, float a) { add_particle_hsv(pos.x, pos.y, pos.z, size, h, s, v, a); }
+
+// add_triangles and add_mesh vertices, 36 bytes. color is packed like add_particle colors.
+typedef struct ws_vertex { ws_vec3 position; ws_vec3 normal; float u, v; unsigned color; } ws_vertex;
+
+static inline ws_vertex ws_vertex_make(ws_vec3 position, ws_vec3 normal, float u, float v, ws_color color) {
+  ws_vertex vertex = { position, normal, u, v, ws_color_pack(color) };
+  return vertex;
+}
+
+// set_color_space values.
+#define WS_COLOR_SPACE_LINEAR 0
+#define WS_COLOR_SPACE_SRGB 1
+
+// set_emitter_callbacks flags.
+#define WS_CALLBACK_SPAWN 1
+#define WS_CALLBACK_DEATH 2
+
+// set_emitter_render_mode and set_particle_render_mode modes.
+#define WS_RENDER_BILLBOARD 0
+#define WS_RENDER_STRETCHED 1
+#define WS_RENDER_RIBBON 2
+
+static inline void ws_set_emitter_position(int emitter, ws_vec3 pos) { set_emitter_position(emitter, pos.x, pos.y, pos.z); }
+
+static inline void ws_set_emitter_velocity(int emitter, ws_vec3 velocity, float spread) { set_emitter_velocity(emitter, velocity.x, velocity.y, velocity.z, spread); }
+
+static inline void ws_set_emitter_appearance(int emitter, float size, ws_color color) { set_emitter_appearance(emitter, size, ws_color_pack(color)); }
+
+static inline void ws_set_emitter_physics(int emitter, ws_vec3 gravity, float drag) { set_emitter_physics(emitter, gravity.x, gravity.y, gravity.z, drag); }
+
+#define WS_LOG_ERROR 1
+#define WS_LOG_WARN 2
+#define WS_LOG_INFO 3
+#define WS_LOG_DEBUG 4
+#define WS_LOG_TRACE 5
+
+// Logs a NUL terminated string.
+static inline void ws_log(int level, const char* str) {
+  int len = 0;
+  while (str[len]) {
+    len++;
+  }
+  log_message(level, str, len);
+}
+"#;
+
+const RUST_HELPERS : &str = r#"
+use core::ops::{Add, Mul, Neg, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Vec3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vec3 {
+    pub const fn new(x: f32, y: f32, z: f32) -> Vec3 {
+        Vec3 { x, y, z }
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, s: f32) -> Vec3 {
+        Vec3::new(self.x * s, self.y * s, self.z * s)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 {
+        Vec3::new(-self.x, -self.y, -self.z)
+    }
+}
+
+/// An RGBA8 color. `add_particle` takes it packed with red in the lowest byte, matching the
+/// Rgba8Unorm vertex attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+        Color { r, g, b, a }
+    }
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, a: 255 }
+    }
+
+    pub const fn pack(self) -> u32 {
+        (self.r as u32) | ((self.g as u32) << 8) | ((self.b as u32) << 16) | ((self.a as u32) << 24)
+    }
+}
+
+/// A vertex for `add_triangles` and `add_mesh`, laid out as the host reads it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: [f32; 2],
+    /// Packed like `Color::pack`.
+    pub color: u32,
+}
+
+impl Vertex {
+    pub const fn new(position: Vec3, normal: Vec3, uv: [f32; 2], color: Color) -> Vertex {
+        Vertex { position, normal, uv, color: color.pack() }
+    }
+}
+
+/// How particle colors are encoded, see `set_color_space`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Linear = 0,
+    Srgb = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+pub fn set_camera(pos: Vec3, lookat: Vec3) {
+    unsafe { sys::set_camera(pos.x, pos.y, pos.z, lookat.x, lookat.y, lookat.z) }
+}
+
+pub fn add_particle(pos: Vec3, size: f32, color: Color) {
+    unsafe { sys::add_particle(pos.x, pos.y, pos.z, size, color.pack()) }
+}
+
+/// Channels in [0, 1].
+pub fn add_particle_rgba(pos: Vec3, size: f32, rgba: [f32; 4]) {
+    unsafe { sys::add_particle_rgba(pos.x, pos.y, pos.z, size, rgba[0], rgba[1], rgba[2], rgba[3]) }
+}
+
+/// Hue in degrees, saturation, value and alpha in [0, 1].
+pub fn add_particle_hsv(pos: Vec3, size: f32, h: f32, s: f32, v: f32, a: f32) {
+    unsafe { sys::add_particle_hsv(pos.x, pos.y, pos.z, size, h, s, v, a) }
+}
+
+pub fn set_color_space(space: ColorSpace) {
+    unsafe { sys::set_color_space(space as i32) }
+}
+
+pub const CALLBACK_SPAWN: i32 = 1;
+pub const CALLBACK_DEATH: i32 = 2;
+
+/// How host simulated particles are drawn, see `Emitter::set_render_mode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Billboard = 0,
+    /// Stretched along the velocity.
+    Stretched = 1,
+    /// A strip through the particle's past positions.
+    Ribbon = 2,
+}
+
+/// A host simulated particle emitter. Emitters live until destroyed or until the script is unloaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Emitter(pub i32);
+
+impl Emitter {
+    pub fn create() -> Emitter {
+        Emitter(unsafe { sys::create_emitter() })
+    }
+
+    pub fn destroy(self) {
+        unsafe { sys::destroy_emitter(self.0) }
+    }
+
+    pub fn emit(self, count: i32) {
+        unsafe { sys::emit_particles(self.0, count) }
+    }
+
+    pub fn set_position(self, pos: Vec3) {
+        unsafe { sys::set_emitter_position(self.0, pos.x, pos.y, pos.z) }
+    }
+
+    pub fn set_velocity(self, velocity: Vec3, spread: f32) {
+        unsafe { sys::set_emitter_velocity(self.0, velocity.x, velocity.y, velocity.z, spread) }
+    }
+
+    pub fn set_rate(self, particles_per_second: f32) {
+        unsafe { sys::set_emitter_rate(self.0, particles_per_second) }
+    }
+
+    pub fn set_lifetime(self, seconds: f32) {
+        unsafe { sys::set_emitter_lifetime(self.0, seconds) }
+    }
+
+    pub fn set_appearance(self, size: f32, color: Color) {
+        unsafe { sys::set_emitter_appearance(self.0, size, color.pack()) }
+    }
+
+    pub fn set_physics(self, gravity: Vec3, drag: f32) {
+        unsafe { sys::set_emitter_physics(self.0, gravity.x, gravity.y, gravity.z, drag) }
+    }
+
+    pub fn set_callbacks(self, flags: i32) {
+        unsafe { sys::set_emitter_callbacks(self.0, flags) }
+    }
+
+    /// `length` is the seconds of motion a streak or ribbon covers.
+    pub fn set_render_mode(self, mode: RenderMode, length: f32) {
+        unsafe { sys::set_emitter_render_mode(self.0, mode as i32, length) }
+    }
+}
+
+/// Every three vertices make a triangle.
+pub fn add_triangles(vertices: &[Vertex]) {
+    unsafe { sys::add_triangles(vertices.as_ptr() as *const u8, vertices.len() as i32) }
+}
+
+/// Three indices into `vertices` per triangle.
+pub fn add_mesh(vertices: &[Vertex], indices: &[u32]) {
+    unsafe {
+        sys::add_mesh(vertices.as_ptr() as *const u8, vertices.len() as i32, indices.as_ptr() as *const u8, indices.len() as i32)
+    }
+}
+
+pub fn sin(x: f32) -> f32 {
+    unsafe { sys::sinf(x) }
+}
+
+pub fn cos(x: f32) -> f32 {
+    unsafe { sys::cosf(x) }
+}
+
+pub fn rand() -> i32 {
+    unsafe { sys::rand() }
+}
+
+pub fn log(level: LogLevel, message: &str) {
+    unsafe { sys::log_message(level as i32, message.as_ptr(), message.len() as i32) }
+}
+
+pub fn draw_line(from: Vec3, to: Vec3, color: Color) {
+    unsafe { sys::draw_line(from.x, from.y, from.z, to.x, to.y, to.z, color.pack()) }
+}
+
+pub fn draw_box(min: Vec3, max: Vec3, color: Color) {
+    unsafe { sys::draw_box(min.x, min.y, min.z, max.x, max.y, max.z, color.pack()) }
+}
+
+pub fn draw_sphere_wire(center: Vec3, radius: f32, color: Color) {
+    unsafe { sys::draw_sphere_wire(center.x, center.y, center.z, radius, color.pack()) }
+}
+
+/// x and y in pixels from the top left of the window.
+pub fn draw_text_2d(x: f32, y: f32, text: &str, color: Color) {
+    unsafe { sys::draw_text_2d(x, y, text.as_ptr(), text.len() as i32, color.pack()) }
+}
+
+pub fn draw_text_3d(pos: Vec3, text: &str, color: Color) {
+    unsafe { sys::draw_text_3d(pos.x, pos.y, pos.z, text.as_ptr(), text.len() as i32, color.pack()) }
+}
+"#;
+
+fn main() {
+    let check = std::env::args().any(|arg| arg == "--check");
+    let outputs = [(C_HEADER_PATH, c_header()), (RUST_SDK_PATH, rust_sdk())];
+
+    let mut stale = false;
+    for (path, contents) in outputs.iter() {
+        let current = fs::read_to_string(path).unwrap_or_default();
+        if current == *contents {
+            continue;
+        }
+
+        if check {
+            println!("{} is out of date, run `cargo run --bin sdkgen`", path);
+            stale = true;
+        } else {
+            fs::write(path, contents).unwrap_or_else(|error| panic!("Can't write {}: {}", path, error));
+            println!("Wrote {}", path);
+        }
+    }
+
+    if stale {
+        process::exit(1);
+    }
+}
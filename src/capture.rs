@@ -0,0 +1,212 @@
+use hal::{
+    buffer,
+    command,
+    format as f,
+    image as i,
+    memory as m,
+    pass,
+    pso::PipelineStage,
+};
+use hal::{Device};
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use crate::allocator::{Allocation, GpuAllocator, MemoryUsage};
+use crate::backenderror::BackendDevice;
+use crate::upload::UploadBuffer;
+
+// Renders frames into an image instead of the swapchain and reads them back, for screenshots and
+// windowless rendering. The capture render pass comes from create_render_pass like the window's, which
+// makes the two compatible: the same pipelines draw into both, only the final layout differs.
+
+type Backend = back::Backend;
+type RenderPass = <Backend as hal::Backend>::RenderPass;
+
+const COLOR_RANGE : i::SubresourceRange = i::SubresourceRange {
+    aspects : f::Aspects::COLOR,
+    levels : 0 .. 1,
+    layers : 0 .. 1,
+};
+
+// A single color attachment, cleared on load. `final_layout` is Present for the swapchain and
+// TransferSrcOptimal for a capture target.
+pub fn create_render_pass(device : &BackendDevice, format : f::Format, final_layout : i::Layout) -> RenderPass {
+    let attachment = pass::Attachment {
+        format: Some(format),
+        samples: 1,
+        ops: pass::AttachmentOps::new(
+            pass::AttachmentLoadOp::Clear,
+            pass::AttachmentStoreOp::Store,
+        ),
+        stencil_ops: pass::AttachmentOps::DONT_CARE,
+        layouts: i::Layout::Undefined .. final_layout,
+    };
+
+    let subpass = pass::SubpassDesc {
+        colors: &[(0, i::Layout::ColorAttachmentOptimal)],
+        depth_stencil: None,
+        inputs: &[],
+        resolves: &[],
+        preserves: &[],
+    };
+
+    let dependency = pass::SubpassDependency {
+        passes: pass::SubpassRef::External .. pass::SubpassRef::Pass(0),
+        stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT
+            .. PipelineStage::COLOR_ATTACHMENT_OUTPUT,
+        accesses: i::Access::empty()
+            .. (i::Access::COLOR_ATTACHMENT_READ | i::Access::COLOR_ATTACHMENT_WRITE),
+    };
+
+    unsafe { device.create_render_pass(&[attachment], &[subpass], &[dependency]) }
+        .expect("Can't create render pass")
+}
+
+pub struct CaptureTarget
+{
+    pub extent : i::Extent,
+    pub render_pass : RenderPass,
+    pub framebuffer : <Backend as hal::Backend>::Framebuffer,
+    format : f::Format,
+    image : <Backend as hal::Backend>::Image,
+    image_memory : Allocation,
+    view : <Backend as hal::Backend>::ImageView,
+    readback : UploadBuffer,
+    // Bytes per row in the readback buffer, padded to the device's preferred copy pitch.
+    row_pitch : u64,
+}
+
+impl CaptureTarget
+{
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, limits : &hal::Limits, format : f::Format, extent : i::Extent) -> CaptureTarget {
+        let render_pass = create_render_pass(device, format, i::Layout::TransferSrcOptimal);
+
+        let mut image = unsafe {
+            device.create_image(
+                i::Kind::D2(extent.width, extent.height, 1, 1),
+                1,
+                format,
+                i::Tiling::Optimal,
+                i::Usage::COLOR_ATTACHMENT | i::Usage::TRANSFER_SRC,
+                i::ViewCapabilities::empty(),
+            )
+        }
+        .expect("Can't create capture image");
+
+        let image_req = unsafe { device.get_image_requirements(&image) };
+        let image_memory = allocator.allocate_image(device, &image_req, MemoryUsage::DeviceLocal)
+            .ok()
+            .expect("Can't allocate capture image");
+        unsafe { device.bind_image_memory(image_memory.memory(), image_memory.offset(), &mut image) }.expect("Can't bind capture image");
+
+        let view = unsafe { device.create_image_view(&image, i::ViewKind::D2, format, f::Swizzle::NO, COLOR_RANGE.clone()) }
+            .expect("Can't create capture image view");
+        let framebuffer = unsafe { device.create_framebuffer(&render_pass, Some(&view), extent) }
+            .expect("Can't create capture framebuffer");
+
+        let alignment = limits.optimal_buffer_copy_pitch_alignment.max(1);
+        let row_pitch = (extent.width as u64 * 4 + alignment - 1) / alignment * alignment;
+        let readback = UploadBuffer::new(device, allocator, row_pitch * extent.height as u64, buffer::Usage::TRANSFER_DST, MemoryUsage::Readback)
+            .ok()
+            .expect("Can't create capture readback buffer");
+
+        CaptureTarget {
+            extent : extent,
+            render_pass : render_pass,
+            framebuffer : framebuffer,
+            format : format,
+            image : image,
+            image_memory : image_memory,
+            view : view,
+            readback : readback,
+            row_pitch : row_pitch,
+        }
+    }
+
+    // Copies the image into the readback buffer. Record it after the render pass into `framebuffer`.
+    pub fn record_readback(&self, cmd_buffer : &mut command::CommandBuffer<Backend, hal::General, command::MultiShot>) {
+        unsafe {
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::COLOR_ATTACHMENT_OUTPUT .. PipelineStage::TRANSFER,
+                m::Dependencies::empty(),
+                &[m::Barrier::Image {
+                    states: (i::Access::COLOR_ATTACHMENT_WRITE, i::Layout::TransferSrcOptimal)
+                        .. (i::Access::TRANSFER_READ, i::Layout::TransferSrcOptimal),
+                    target: &self.image,
+                    families: None,
+                    range: COLOR_RANGE.clone(),
+                }],
+            );
+
+            cmd_buffer.copy_image_to_buffer(
+                &self.image,
+                i::Layout::TransferSrcOptimal,
+                &self.readback.device_buffer,
+                &[command::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width: (self.row_pitch / 4) as u32,
+                    buffer_height: self.extent.height,
+                    image_layers: i::SubresourceLayers {
+                        aspects: f::Aspects::COLOR,
+                        level: 0,
+                        layers: 0 .. 1,
+                    },
+                    image_offset: i::Offset { x: 0, y: 0, z: 0 },
+                    image_extent: self.extent,
+                }],
+            );
+
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::TRANSFER .. PipelineStage::HOST,
+                m::Dependencies::empty(),
+                &[m::Barrier::AllBuffers(buffer::Access::TRANSFER_WRITE .. buffer::Access::HOST_READ)],
+            );
+        }
+    }
+
+    // The last readback as tightly packed, opaque RGBA rows. Its submission must have completed.
+    pub fn read_pixels(&self, device : &BackendDevice) -> Vec<u8> {
+        let bgra = match self.format {
+            f::Format::Bgra8Srgb | f::Format::Bgra8Unorm => true,
+            _ => false,
+        };
+
+        let width = self.extent.width as usize;
+        let mut pixels = Vec::with_capacity(width * self.extent.height as usize * 4);
+        self.readback.read_with(device, self.readback.size as usize, |bytes : &[u8]| {
+            for row in bytes.chunks(self.row_pitch as usize) {
+                for pixel in row[.. width * 4].chunks(4) {
+                    let (r, b) = if bgra { (pixel[2], pixel[0]) } else { (pixel[0], pixel[2]) };
+                    // The clear color is transparent, the window shows it as black.
+                    pixels.extend_from_slice(&[r, pixel[1], b, 255]);
+                }
+            }
+        });
+        pixels
+    }
+
+    pub fn save_png(&self, device : &BackendDevice, path : &str) -> io::Result<()> {
+        write_png(path, self.extent.width, self.extent.height, &self.read_pixels(device))
+    }
+
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe {
+            device.destroy_framebuffer(self.framebuffer);
+            device.destroy_image_view(self.view);
+            device.destroy_image(self.image);
+            device.destroy_render_pass(self.render_pass);
+        }
+        allocator.free(device, self.image_memory);
+        self.readback.destroy(device, allocator);
+    }
+}
+
+pub fn write_png(path : &str, width : u32, height : u32, rgba : &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    Ok(())
+}
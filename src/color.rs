@@ -0,0 +1,91 @@
+// The particle color contract. Colors reach the vertex buffer as a u32 read by an Rgba8Unorm attribute,
+// so the canonical packing is RGBA8 with red in the lowest byte: r | g << 8 | b << 16 | a << 24.
+// The shader output goes to an sRGB swapchain, which makes the vertex color linear; scripts that author
+// colors in sRGB switch their color space and the host converts before the particle is stored.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace
+{
+    Linear,
+    Srgb,
+}
+
+impl ColorSpace
+{
+    // The values scripts pass to set_color_space.
+    pub fn from_i32(value : i32) -> Option<ColorSpace> {
+        match value {
+            0 => Some(ColorSpace::Linear),
+            1 => Some(ColorSpace::Srgb),
+            _ => None,
+        }
+    }
+}
+
+pub fn pack_rgba8(r : u8, g : u8, b : u8, a : u8) -> u32 {
+    (r as u32) | ((g as u32) << 8) | ((b as u32) << 16) | ((a as u32) << 24)
+}
+
+pub fn unpack_rgba8(color : u32) -> [u8; 4] {
+    [color as u8, (color >> 8) as u8, (color >> 16) as u8, (color >> 24) as u8]
+}
+
+fn to_unorm8(value : f32) -> u8 {
+    // NaN ends up as 0.
+    (value.max(0.0).min(1.0) * 255.0 + 0.5) as u8
+}
+
+pub fn pack_rgba(rgba : [f32; 4]) -> u32 {
+    pack_rgba8(to_unorm8(rgba[0]), to_unorm8(rgba[1]), to_unorm8(rgba[2]), to_unorm8(rgba[3]))
+}
+
+pub fn srgb_to_linear(value : f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Hue in degrees, wrapping; saturation, value and alpha in [0, 1].
+pub fn hsv_to_rgb(h : f32, s : f32, v : f32) -> [f32; 3] {
+    let h = (h % 360.0 + 360.0) % 360.0 / 60.0;
+    let s = s.max(0.0).min(1.0);
+    let v = v.max(0.0).min(1.0);
+
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let m = v - c;
+
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    [r + m, g + m, b + m]
+}
+
+// Converts a packed script color into the linear color the vertex buffer wants. Alpha is never
+// gamma encoded.
+pub fn packed_to_linear(color : u32, space : ColorSpace) -> u32 {
+    match space {
+        ColorSpace::Linear => color,
+        ColorSpace::Srgb => {
+            let [r, g, b, a] = unpack_rgba8(color);
+            let convert = |channel : u8| srgb_to_linear(channel as f32 / 255.0);
+            pack_rgba([convert(r), convert(g), convert(b), a as f32 / 255.0])
+        }
+    }
+}
+
+// Same for float channels, converted before quantizing so dark sRGB values keep their precision.
+pub fn rgba_to_linear(rgba : [f32; 4], space : ColorSpace) -> u32 {
+    match space {
+        ColorSpace::Linear => pack_rgba(rgba),
+        ColorSpace::Srgb => pack_rgba([srgb_to_linear(rgba[0]), srgb_to_linear(rgba[1]), srgb_to_linear(rgba[2]), rgba[3]]),
+    }
+}
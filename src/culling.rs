@@ -0,0 +1,312 @@
+use nalgebra_glm as glm;
+use glm::{Vec3, Vec4, vec4};
+
+use std::collections::BTreeMap;
+
+// Frustum culling and screen size LOD for particles, applied before they are expanded into quads.
+// Particles outside the view are skipped. Ones that would cover less than a pixel are merged with the
+// other tiny particles in the same few pixels of screen into one particle big enough to rasterize,
+// keeping their total coverage, and ones too small to matter even merged are dropped. Everything
+// here is plain math on the view projection, no GPU involved.
+
+// Radii in pixels below which particles are merged or dropped.
+const MERGE_PIXELS : f32 = 0.5;
+const DROP_PIXELS : f32 = 0.02;
+// Side of the screen cells tiny particles are merged within, in pixels.
+const MERGE_CELL : f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CullStats
+{
+    pub submitted : u32,
+    pub culled : u32,
+    pub dropped : u32,
+    // Particles folded into `merged_into` merged ones.
+    pub merged : u32,
+    pub merged_into : u32,
+}
+
+pub struct ViewVolume
+{
+    view_proj : glm::Mat4,
+    // Normalized so the plane distance is in world units, inside is positive.
+    planes : [Vec4; 6],
+    width : f32,
+    height : f32,
+    // Pixels covered by one world unit at one unit in front of the camera.
+    focal_pixels : f32,
+}
+
+impl ViewVolume
+{
+    // `fov_y` is the vertical field of view `view_proj` was built with, in radians.
+    pub fn new(view_proj : &glm::Mat4, fov_y : f32, width : f32, height : f32) -> ViewVolume {
+        // Gribb and Hartmann: the planes are sums and differences of the rows, with clip space depth in
+        // -1..1 as glm::perspective builds it.
+        let row = |i : usize| vec4(view_proj[(i, 0)], view_proj[(i, 1)], view_proj[(i, 2)], view_proj[(i, 3)]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let mut planes = [w + x, w - x, w + y, w - y, w + z, w - z];
+        for plane in planes.iter_mut() {
+            let length = glm::length(&plane.xyz());
+            if length > 0.0 {
+                *plane /= length;
+            }
+        }
+
+        ViewVolume {
+            view_proj : *view_proj,
+            planes : planes,
+            width : width,
+            height : height,
+            focal_pixels : height * 0.5 / (fov_y * 0.5).tan(),
+        }
+    }
+
+    pub fn contains_sphere(&self, center : &Vec3, radius : f32) -> bool {
+        self.planes.iter().all(|plane| plane.xyz().dot(center) + plane.w >= -radius)
+    }
+
+    // Pixels from the top left and depth in front of the camera, None behind it.
+    fn project(&self, point : &Vec3) -> Option<(f32, f32, f32)> {
+        let clip = self.view_proj * vec4(point.x, point.y, point.z, 1.0);
+        if clip.w <= 0.0 {
+            return None;
+        }
+        Some(((clip.x / clip.w + 1.0) * 0.5 * self.width, (1.0 - clip.y / clip.w) * 0.5 * self.height, clip.w))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LodParticle
+{
+    pub position : Vec3,
+    pub size : f32,
+    pub color : u32,
+}
+
+// Tiny particles collected in one screen cell.
+struct MergeCell
+{
+    // Weighted by coverage, see add.
+    position : Vec3,
+    rgb : [f32; 3],
+    // Sum of alpha (0..1) times pixel area.
+    coverage : f32,
+    weight : f32,
+    depth : f32,
+}
+
+// Feed every particle through `add`, draw what it returns, then draw what `finish` returns.
+pub struct ParticleLod<'a>
+{
+    view : &'a ViewVolume,
+    // Ordered, so merged particles come out the same way every frame for exports.
+    cells : BTreeMap<(i32, i32), MergeCell>,
+    pub stats : CullStats,
+}
+
+fn unpack(color : u32) -> [f32; 4] {
+    let channel = |shift : u32| ((color >> shift) & 0xff) as f32 / 255.0;
+    [channel(0), channel(8), channel(16), channel(24)]
+}
+
+fn pack(rgba : [f32; 4]) -> u32 {
+    let channel = |value : f32, shift : u32| ((value.max(0.0).min(1.0) * 255.0).round() as u32) << shift;
+    channel(rgba[0], 0) | channel(rgba[1], 8) | channel(rgba[2], 16) | channel(rgba[3], 24)
+}
+
+impl<'a> ParticleLod<'a>
+{
+    pub fn new(view : &'a ViewVolume) -> ParticleLod<'a> {
+        ParticleLod { view : view, cells : BTreeMap::new(), stats : CullStats::default() }
+    }
+
+    // Culls a streak or ribbon by its bounding sphere, they aren't merged.
+    pub fn visible(&mut self, center : &Vec3, radius : f32) -> bool {
+        self.stats.submitted += 1;
+        let visible = self.view.contains_sphere(center, radius);
+        if !visible {
+            self.stats.culled += 1;
+        }
+        visible
+    }
+
+    // The particle to draw as it is, None if it was culled, dropped or taken for merging.
+    pub fn add(&mut self, particle : LodParticle) -> Option<LodParticle> {
+        self.stats.submitted += 1;
+        if !self.view.contains_sphere(&particle.position, particle.size) {
+            self.stats.culled += 1;
+            return None;
+        }
+
+        let (x, y, depth) = match self.view.project(&particle.position) {
+            Some(projected) => projected,
+            // Straddling the camera plane, too close to be tiny.
+            None => return Some(particle),
+        };
+        let radius = particle.size * self.view.focal_pixels / depth;
+        if radius >= MERGE_PIXELS {
+            return Some(particle);
+        }
+        if radius < DROP_PIXELS {
+            self.stats.dropped += 1;
+            return None;
+        }
+
+        let rgba = unpack(particle.color);
+        let coverage = rgba[3] * radius * radius;
+        // Transparent particles still count for the position.
+        let weight = coverage.max(1e-6);
+        let cell = self.cells.entry(((x / MERGE_CELL).floor() as i32, (y / MERGE_CELL).floor() as i32)).or_insert(MergeCell {
+            position : glm::zero(),
+            rgb : [0.0; 3],
+            coverage : 0.0,
+            weight : 0.0,
+            depth : 0.0,
+        });
+        cell.position += particle.position * weight;
+        for i in 0..3 {
+            cell.rgb[i] += rgba[i] * weight;
+        }
+        cell.coverage += coverage;
+        cell.weight += weight;
+        cell.depth += depth * weight;
+        self.stats.merged += 1;
+        None
+    }
+
+    // One particle per screen cell with tiny particles, covering as much as they did together: MERGE_PIXELS
+    // in radius and translucent, or bigger and opaque for dense cells.
+    pub fn finish(mut self) -> (Vec<LodParticle>, CullStats) {
+        let focal_pixels = self.view.focal_pixels;
+        let merged : Vec<LodParticle> = self.cells.values().map(|cell| {
+            let depth = cell.depth / cell.weight;
+            let radius = MERGE_PIXELS.max(cell.coverage.sqrt());
+            let alpha = cell.coverage / (radius * radius);
+            LodParticle {
+                position : cell.position / cell.weight,
+                size : radius * depth / focal_pixels,
+                color : pack([cell.rgb[0] / cell.weight, cell.rgb[1] / cell.weight, cell.rgb[2] / cell.weight, alpha]),
+            }
+        }).collect();
+
+        self.stats.merged_into = merged.len() as u32;
+        (merged, self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use glm::vec3;
+
+    const WIDTH : f32 = 800.0;
+    const HEIGHT : f32 = 600.0;
+    const FOV : f32 = 1.0;
+
+    // At the origin looking down -z.
+    fn view() -> ViewVolume {
+        let proj = glm::perspective(WIDTH / HEIGHT, FOV, 0.1, 100.0);
+        let lookat = glm::look_at(&vec3(0.0, 0.0, 0.0), &vec3(0.0, 0.0, -1.0), &vec3(0.0, 1.0, 0.0));
+        ViewVolume::new(&(proj * lookat), FOV, WIDTH, HEIGHT)
+    }
+
+    // The world size of a particle `pixels` in radius at `depth`.
+    fn size_for(view : &ViewVolume, pixels : f32, depth : f32) -> f32 {
+        pixels * depth / view.focal_pixels
+    }
+
+    fn particle(position : Vec3, size : f32) -> LodParticle {
+        LodParticle { position : position, size : size, color : 0xffffffff }
+    }
+
+    #[test]
+    fn contains_sphere() {
+        let view = view();
+        assert!(view.contains_sphere(&vec3(0.0, 0.0, -10.0), 1.0));
+        assert!(!view.contains_sphere(&vec3(0.0, 0.0, 10.0), 1.0));
+        assert!(!view.contains_sphere(&vec3(100.0, 0.0, -10.0), 1.0));
+        assert!(!view.contains_sphere(&vec3(0.0, 0.0, -200.0), 1.0));
+        // The center is outside, the sphere reaches in.
+        assert!(view.contains_sphere(&vec3(100.0, 0.0, -10.0), 100.0));
+    }
+
+    #[test]
+    fn culled_particles_are_counted() {
+        let view = view();
+        let mut lod = ParticleLod::new(&view);
+        assert_eq!(lod.add(particle(vec3(0.0, 0.0, 10.0), 1.0)), None);
+        assert!(!lod.visible(&vec3(100.0, 0.0, -10.0), 1.0));
+        assert!(lod.visible(&vec3(0.0, 0.0, -10.0), 1.0));
+
+        let (merged, stats) = lod.finish();
+        assert!(merged.is_empty());
+        assert_eq!((stats.submitted, stats.culled), (3, 2));
+    }
+
+    #[test]
+    fn drop_threshold() {
+        let view = view();
+        let mut lod = ParticleLod::new(&view);
+        let position = vec3(0.0, 0.0, -10.0);
+        assert_eq!(lod.add(particle(position, size_for(&view, DROP_PIXELS * 0.9, 10.0))), None);
+        assert_eq!(lod.stats.dropped, 1);
+        assert_eq!(lod.add(particle(position, size_for(&view, DROP_PIXELS * 1.1, 10.0))), None);
+        assert_eq!((lod.stats.dropped, lod.stats.merged), (1, 1));
+    }
+
+    #[test]
+    fn merge_threshold() {
+        let view = view();
+        let mut lod = ParticleLod::new(&view);
+        let position = vec3(0.0, 0.0, -10.0);
+        let big = particle(position, size_for(&view, MERGE_PIXELS * 1.1, 10.0));
+        assert_eq!(lod.add(big), Some(big));
+        assert_eq!(lod.add(particle(position, size_for(&view, MERGE_PIXELS * 0.9, 10.0))), None);
+
+        // Further away the same particle gets merged.
+        let far = particle(vec3(0.0, 0.0, -20.0), big.size);
+        assert_eq!(lod.add(far), None);
+        assert_eq!((lod.stats.merged, lod.stats.dropped), (2, 0));
+    }
+
+    #[test]
+    fn tiny_particles_in_a_cell_merge() {
+        let view = view();
+        let mut lod = ParticleLod::new(&view);
+        // Off the middle of the screen so they don't straddle a cell border, within a pixel of each other.
+        let size = size_for(&view, MERGE_PIXELS * 0.5, 10.0);
+        let positions = [vec3(0.1, 0.1, -10.0), vec3(0.101, 0.1, -10.0), vec3(0.1, 0.101, -10.0), vec3(0.101, 0.101, -10.0)];
+        for position in positions.iter() {
+            assert_eq!(lod.add(particle(*position, size)), None);
+        }
+
+        let (merged, stats) = lod.finish();
+        assert_eq!((stats.submitted, stats.merged, stats.merged_into), (4, 4, 1));
+        assert_eq!(merged.len(), 1);
+
+        // Four times the area of one: twice its radius, and still opaque.
+        let merged = merged[0];
+        assert!(glm::distance(&merged.position, &vec3(0.1005, 0.1005, -10.0)) < 1e-4);
+        assert!((merged.size - size * 2.0).abs() < size * 0.01);
+        assert_eq!(merged.color, 0xffffffff);
+    }
+
+    #[test]
+    fn particles_in_different_cells_stay_apart() {
+        let view = view();
+        let mut lod = ParticleLod::new(&view);
+        let size = size_for(&view, MERGE_PIXELS * 0.5, 10.0);
+        lod.add(particle(vec3(0.1, 0.1, -10.0), size));
+        lod.add(particle(vec3(2.0, 0.1, -10.0), size));
+
+        let (merged, stats) = lod.finish();
+        assert_eq!((stats.merged, stats.merged_into), (2, 2));
+        // Alone in its cell a particle keeps the merged size and gives up opacity instead.
+        for particle in merged.iter() {
+            assert!((particle.size - size_for(&view, MERGE_PIXELS, 10.0)).abs() < size * 0.01);
+            assert!(particle.color >> 24 < 0xff);
+        }
+    }
+}
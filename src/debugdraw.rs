@@ -0,0 +1,116 @@
+use nalgebra_glm as glm;
+use glm::{Vec3, vec3, vec4};
+
+// Debug primitives scripts submit with draw_line, draw_box, draw_sphere_wire and draw_text_2d/3d, so
+// effect authors can see emitter volumes and velocities. Like the particles a script adds, they are
+// collected during a tick and replaced by the next one. Boxes and spheres are stored as their lines.
+
+// Primitives beyond these are dropped.
+pub const MAX_DEBUG_LINES : usize = 65536;
+pub const MAX_DEBUG_TEXTS : usize = 256;
+pub const MAX_DEBUG_TEXT_LEN : usize = 256;
+
+// Segments per circle of a wire sphere.
+const SPHERE_SEGMENTS : usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebugLine
+{
+    pub from : Vec3,
+    pub to : Vec3,
+    pub color : u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAnchor
+{
+    // Pixels from the top left of the window.
+    Screen(f32, f32),
+    // Follows a point in the world.
+    World(Vec3),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DebugText
+{
+    pub anchor : TextAnchor,
+    pub text : String,
+    pub color : u32,
+}
+
+pub struct DebugDraw
+{
+    pub lines : Vec<DebugLine>,
+    pub texts : Vec<DebugText>,
+}
+
+impl DebugDraw
+{
+    pub fn new() -> DebugDraw {
+        DebugDraw { lines : Vec::new(), texts : Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.texts.clear();
+    }
+
+    pub fn add_line(&mut self, from : Vec3, to : Vec3, color : u32) {
+        if self.lines.len() < MAX_DEBUG_LINES {
+            self.lines.push(DebugLine { from : from, to : to, color : color });
+        }
+    }
+
+    // The twelve edges of an axis aligned box.
+    pub fn add_box(&mut self, min : Vec3, max : Vec3, color : u32) {
+        let corner = |i : usize| vec3(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        );
+
+        for i in 0..8 {
+            for &axis in [1, 2, 4].iter() {
+                if i & axis == 0 {
+                    self.add_line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+    }
+
+    // Three circles, one around each axis.
+    pub fn add_sphere(&mut self, center : Vec3, radius : f32, color : u32) {
+        let point = |axis : usize, angle : f32| {
+            let (s, c) = (angle.sin() * radius, angle.cos() * radius);
+            center + match axis {
+                0 => vec3(0.0, c, s),
+                1 => vec3(c, 0.0, s),
+                _ => vec3(c, s, 0.0),
+            }
+        };
+
+        let step = std::f32::consts::PI * 2.0 / SPHERE_SEGMENTS as f32;
+        for axis in 0..3 {
+            for segment in 0..SPHERE_SEGMENTS {
+                self.add_line(point(axis, segment as f32 * step), point(axis, (segment + 1) as f32 * step), color);
+            }
+        }
+    }
+
+    pub fn add_text(&mut self, anchor : TextAnchor, text : &str, color : u32) {
+        if self.texts.len() < MAX_DEBUG_TEXTS {
+            self.texts.push(DebugText { anchor : anchor, text : text.chars().take(MAX_DEBUG_TEXT_LEN).collect(), color : color });
+        }
+    }
+}
+
+// Where `point` lands in a `width` x `height` window, in pixels from the top left, or None behind the
+// camera. Matches the y flip in data/quad.vert.
+pub fn screen_position(view_proj : &glm::Mat4, point : &Vec3, width : f32, height : f32) -> Option<(f32, f32)> {
+    let clip = view_proj * vec4(point.x, point.y, point.z, 1.0);
+    if clip.w <= 0.0 {
+        return None;
+    }
+    let (x, y) = (clip.x / clip.w, clip.y / clip.w);
+    Some(((x + 1.0) * 0.5 * width, (1.0 - y) * 0.5 * height))
+}
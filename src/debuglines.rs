@@ -0,0 +1,145 @@
+use hal::{
+    buffer,
+    command,
+    format as f,
+    pass::Subpass,
+    pso,
+    pso::VertexInputRate,
+    Primitive,
+};
+use hal::{Device};
+
+use crate::allocator::{GpuAllocator, MemoryUsage};
+use crate::backenderror::BackendDevice;
+use crate::debugdraw::{DebugLine, MAX_DEBUG_LINES};
+use crate::shader::load_shader;
+use crate::upload::UploadBuffer;
+
+// Draws the script's debug lines (WorldState::debug) as a line list, with the same view projection
+// uniform as the particles.
+
+type Backend = back::Backend;
+type DescriptorSetLayout = <Backend as hal::Backend>::DescriptorSetLayout;
+type DescriptorSet = <Backend as hal::Backend>::DescriptorSet;
+
+const ENTRY_NAME : &str = "main";
+
+#[derive(Debug, Clone, Copy)]
+struct LineVertex
+{
+    position : [f32; 3],
+    color : u32,
+}
+
+pub struct DebugLines
+{
+    // One per frame in flight.
+    vbuffers : Vec<UploadBuffer>,
+    vertex_counts : Vec<u32>,
+    layout : <Backend as hal::Backend>::PipelineLayout,
+    pipeline : <Backend as hal::Backend>::GraphicsPipeline,
+}
+
+impl DebugLines
+{
+    // `locals_layout` is the set with the view projection uniform, bound as set 0 when drawing.
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, render_pass : &<Backend as hal::Backend>::RenderPass,
+               locals_layout : &DescriptorSetLayout, frames_in_flight : usize) -> DebugLines {
+        let stride = std::mem::size_of::<LineVertex>() as u64;
+        let vbuffers = (0..frames_in_flight)
+            .map(|_| UploadBuffer::new(device, allocator, MAX_DEBUG_LINES as u64 * 2 * stride, buffer::Usage::VERTEX, MemoryUsage::Upload)
+                .ok().expect("Can't create debug line vertex buffer"))
+            .collect();
+
+        let layout = unsafe { device.create_pipeline_layout(std::iter::once(locals_layout), &[]) }
+            .expect("Can't create pipeline layout");
+
+        let pipeline = {
+            let vs_module = load_shader(device, "data/debugline.vert", glsl_to_spirv::ShaderType::Vertex);
+            let fs_module = load_shader(device, "data/overlay.frag", glsl_to_spirv::ShaderType::Fragment);
+
+            let shader_entries = pso::GraphicsShaderSet {
+                vertex : pso::EntryPoint { entry : ENTRY_NAME, module : &vs_module, specialization : pso::Specialization::default() },
+                hull : None,
+                domain : None,
+                geometry : None,
+                fragment : Some(pso::EntryPoint { entry : ENTRY_NAME, module : &fs_module, specialization : pso::Specialization::default() }),
+            };
+
+            let subpass = Subpass { index : 0, main_pass : render_pass };
+            let mut pipeline_desc = pso::GraphicsPipelineDesc::new(shader_entries, Primitive::LineList, pso::Rasterizer::FILL, &layout, subpass);
+            pipeline_desc.blender.targets.push(pso::ColorBlendDesc {
+                mask : pso::ColorMask::ALL,
+                blend : Some(pso::BlendState::ALPHA),
+            });
+            pipeline_desc.vertex_buffers.push(pso::VertexBufferDesc {
+                binding : 0,
+                stride : stride as u32,
+                rate : VertexInputRate::Vertex,
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 0,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgb32Sfloat, offset : 0 },
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 1,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgba8Unorm, offset : 12 },
+            });
+
+            let pipeline = unsafe { device.create_graphics_pipeline(&pipeline_desc, None) };
+            unsafe {
+                device.destroy_shader_module(vs_module);
+                device.destroy_shader_module(fs_module);
+            }
+            pipeline.expect("Can't create debug line pipeline")
+        };
+
+        DebugLines {
+            vbuffers : vbuffers,
+            vertex_counts : vec![0; frames_in_flight],
+            layout : layout,
+            pipeline : pipeline,
+        }
+    }
+
+    // The frame's previous submission must have completed.
+    pub fn write_lines(&mut self, device : &BackendDevice, frame_idx : usize, lines : &[DebugLine]) {
+        let lines = &lines[.. lines.len().min(MAX_DEBUG_LINES)];
+        self.vertex_counts[frame_idx] = lines.len() as u32 * 2;
+        if lines.is_empty() {
+            return;
+        }
+
+        self.vbuffers[frame_idx].write_with(device, lines.len() * 2, |vertices : &mut [LineVertex]| {
+            for (i, line) in lines.iter().enumerate() {
+                vertices[i * 2] = LineVertex { position : [line.from.x, line.from.y, line.from.z], color : line.color };
+                vertices[i * 2 + 1] = LineVertex { position : [line.to.x, line.to.y, line.to.z], color : line.color };
+            }
+        });
+    }
+
+    pub fn record_draw(&self, encoder : &mut command::RenderPassInlineEncoder<Backend>, frame_idx : usize, locals : &DescriptorSet) {
+        if self.vertex_counts[frame_idx] == 0 {
+            return;
+        }
+
+        unsafe {
+            encoder.bind_graphics_pipeline(&self.pipeline);
+            encoder.bind_graphics_descriptor_sets(&self.layout, 0, Some(locals), &[]);
+            encoder.bind_vertex_buffers(0, Some((&self.vbuffers[frame_idx].device_buffer, 0)));
+            encoder.draw(0 .. self.vertex_counts[frame_idx], 0 .. 1);
+        }
+    }
+
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe {
+            device.destroy_graphics_pipeline(self.pipeline);
+            device.destroy_pipeline_layout(self.layout);
+        }
+        for upload in self.vbuffers {
+            upload.destroy(device, allocator);
+        }
+    }
+}
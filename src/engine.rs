@@ -0,0 +1,78 @@
+use crate::host::HostContext;
+use crate::validate::ModuleSignature;
+use crate::vm::VMError;
+
+// Abstraction over the wasm runtime, so VMInstance doesn't care whether scripts are JIT compiled or
+// interpreted. An engine compiles bytecode into a module, a module is instantiated against the host
+// API from hostapi.rs, and an instance runs exports with a HostContext bound for the duration of the
+// call.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptValue
+{
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+// Engines and compiled scripts cross threads, scripts compile on a worker while the old one runs.
+pub trait ScriptEngine : Send + Sync
+{
+    fn name(&self) -> &'static str;
+    // Part of the script cache key, artifacts from another version are never loaded.
+    fn version(&self) -> &'static str;
+    fn compile(&self, bytecode : &[u8]) -> Result<Box<dyn CompiledScript>, VMError>;
+
+    // Engines that compile ahead of time can reload what CompiledScript::artifact produced.
+    fn caches_artifacts(&self) -> bool {
+        false
+    }
+
+    fn load_artifact(&self, _artifact : &[u8]) -> Result<Box<dyn CompiledScript>, VMError> {
+        Err(VMError::CompileError(format!("{} doesn't support cached artifacts", self.name())))
+    }
+}
+
+pub trait CompiledScript : Send
+{
+    fn instantiate(&self) -> Result<Box<dyn ScriptInstance>, VMError>;
+
+    fn artifact(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+pub trait ScriptInstance
+{
+    // None if the instance has no function export with this name.
+    fn export_signature(&self, name : &str) -> Option<ModuleSignature>;
+    fn call(&mut self, host : &mut HostContext, name : &str, args : &[ScriptValue]) -> Result<Vec<ScriptValue>, VMError>;
+
+    // Exported linear memory, 0 sized if the script doesn't export one.
+    fn memory_size(&self) -> usize;
+    fn read_memory(&self, offset : usize, buffer : &mut [u8]) -> bool;
+    fn write_memory(&mut self, offset : usize, data : &[u8]) -> bool;
+}
+
+#[cfg(feature = "jit")]
+pub const DEFAULT_ENGINE : &str = "wasmer";
+#[cfg(not(feature = "jit"))]
+pub const DEFAULT_ENGINE : &str = "wasmi";
+
+pub fn engine_names() -> Vec<&'static str> {
+    let mut names = Vec::new();
+    #[cfg(feature = "jit")]
+    names.push("wasmer");
+    names.push("wasmi");
+    names
+}
+
+pub fn create_engine(name : &str) -> Option<Box<dyn ScriptEngine>> {
+    match name {
+        #[cfg(feature = "jit")]
+        "wasmer" => Some(Box::new(crate::wasmerengine::WasmerEngine::new())),
+        "wasmi" => Some(Box::new(crate::wasmiengine::WasmiEngine::new())),
+        _ => None,
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+
+use crate::capture;
+
+// Frame sequence export (--export-frames). Renders a fixed number of frames, each advancing the world by
+// exactly 1/fps seconds regardless of how long it took to render, so the same script and seed always
+// export the same frames. Frames go to a numbered PNG sequence, to a video encoder's stdin as raw RGBA,
+// or both.
+
+pub struct FrameExport
+{
+    directory : Option<PathBuf>,
+    encoder : Option<Child>,
+    frame_count : u32,
+    fps : f32,
+    written : u32,
+}
+
+// "ffmpeg -f rawvideo -pix_fmt rgba -s {width}x{height} -r {fps} -i - out.mp4", split on whitespace.
+fn spawn_encoder(command_line : &str, width : u32, height : u32, fps : f32) -> io::Result<Child> {
+    let args : Vec<String> = command_line
+        .split_whitespace()
+        .map(|arg| arg
+            .replace("{width}", &width.to_string())
+            .replace("{height}", &height.to_string())
+            .replace("{fps}", &fps.to_string()))
+        .collect();
+
+    if args.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty encoder command"));
+    }
+
+    Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .spawn()
+}
+
+impl FrameExport
+{
+    pub fn new(directory : Option<&str>, encoder : Option<&str>, frame_count : u32, fps : f32, width : u32, height : u32) -> io::Result<FrameExport> {
+        let directory = match directory {
+            Some(directory) => {
+                fs::create_dir_all(directory)?;
+                Some(PathBuf::from(directory))
+            }
+            None => None,
+        };
+
+        let encoder = match encoder {
+            Some(command_line) => Some(spawn_encoder(command_line, width, height, fps)?),
+            None => None,
+        };
+
+        Ok(FrameExport { directory : directory, encoder : encoder, frame_count : frame_count, fps : fps, written : 0 })
+    }
+
+    // Simulation time covered by each frame.
+    pub fn frame_length(&self) -> f32 {
+        1.0 / self.fps
+    }
+
+    pub fn finished(&self) -> bool {
+        self.written >= self.frame_count
+    }
+
+    // `rgba` is tightly packed rows, as CaptureTarget::read_pixels returns them.
+    pub fn write_frame(&mut self, width : u32, height : u32, rgba : &[u8]) -> io::Result<()> {
+        if let Some(directory) = &self.directory {
+            let path = directory.join(format!("frame-{:05}.png", self.written));
+            capture::write_png(&path.to_string_lossy(), width, height, rgba)?;
+        }
+
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.stdin.as_mut().expect("encoder stdin is piped").write_all(rgba)?;
+        }
+
+        self.written += 1;
+        Ok(())
+    }
+
+    // Closes the encoder's input and waits for it to finish the file. Returns the number of frames written.
+    pub fn finish(self) -> io::Result<u32> {
+        if let Some(mut encoder) = self.encoder {
+            drop(encoder.stdin.take());
+            let status = encoder.wait()?;
+            if !status.success() {
+                return Err(io::Error::new(io::ErrorKind::Other, format!("encoder exited with {}", status)));
+            }
+        }
+        Ok(self.written)
+    }
+}
@@ -0,0 +1,109 @@
+// Built-in 5x7 bitmap font for the debug overlay: upper case letters, digits and common punctuation.
+// Lower case is drawn as upper case, control characters as blanks and anything else missing as '?'.
+
+// Cell size, including the gap to the next character and line.
+pub const ADVANCE : u32 = 6;
+pub const LINE_HEIGHT : u32 = 9;
+
+const GLYPHS : &[(char, [&str; 7])] = &[
+    ('A', [".###.", "#...#", "#...#", "#####", "#...#", "#...#", "#...#"]),
+    ('B', ["####.", "#...#", "#...#", "####.", "#...#", "#...#", "####."]),
+    ('C', [".###.", "#...#", "#....", "#....", "#....", "#...#", ".###."]),
+    ('D', ["####.", "#...#", "#...#", "#...#", "#...#", "#...#", "####."]),
+    ('E', ["#####", "#....", "#....", "####.", "#....", "#....", "#####"]),
+    ('F', ["#####", "#....", "#....", "####.", "#....", "#....", "#...."]),
+    ('G', [".###.", "#...#", "#....", "#.###", "#...#", "#...#", ".####"]),
+    ('H', ["#...#", "#...#", "#...#", "#####", "#...#", "#...#", "#...#"]),
+    ('I', [".###.", "..#..", "..#..", "..#..", "..#..", "..#..", ".###."]),
+    ('J', ["..###", "...#.", "...#.", "...#.", "...#.", "#..#.", ".##.."]),
+    ('K', ["#...#", "#..#.", "#.#..", "##...", "#.#..", "#..#.", "#...#"]),
+    ('L', ["#....", "#....", "#....", "#....", "#....", "#....", "#####"]),
+    ('M', ["#...#", "##.##", "#.#.#", "#.#.#", "#...#", "#...#", "#...#"]),
+    ('N', ["#...#", "#...#", "##..#", "#.#.#", "#..##", "#...#", "#...#"]),
+    ('O', [".###.", "#...#", "#...#", "#...#", "#...#", "#...#", ".###."]),
+    ('P', ["####.", "#...#", "#...#", "####.", "#....", "#....", "#...."]),
+    ('Q', [".###.", "#...#", "#...#", "#...#", "#.#.#", "#..#.", ".##.#"]),
+    ('R', ["####.", "#...#", "#...#", "####.", "#.#..", "#..#.", "#...#"]),
+    ('S', [".####", "#....", "#....", ".###.", "....#", "....#", "####."]),
+    ('T', ["#####", "..#..", "..#..", "..#..", "..#..", "..#..", "..#.."]),
+    ('U', ["#...#", "#...#", "#...#", "#...#", "#...#", "#...#", ".###."]),
+    ('V', ["#...#", "#...#", "#...#", "#...#", "#...#", ".#.#.", "..#.."]),
+    ('W', ["#...#", "#...#", "#...#", "#.#.#", "#.#.#", "#.#.#", ".#.#."]),
+    ('X', ["#...#", "#...#", ".#.#.", "..#..", ".#.#.", "#...#", "#...#"]),
+    ('Y', ["#...#", "#...#", ".#.#.", "..#..", "..#..", "..#..", "..#.."]),
+    ('Z', ["#####", "....#", "...#.", "..#..", ".#...", "#....", "#####"]),
+    ('0', [".###.", "#...#", "#..##", "#.#.#", "##..#", "#...#", ".###."]),
+    ('1', ["..#..", ".##..", "..#..", "..#..", "..#..", "..#..", ".###."]),
+    ('2', [".###.", "#...#", "....#", "...#.", "..#..", ".#...", "#####"]),
+    ('3', ["#####", "...#.", "..#..", "...#.", "....#", "#...#", ".###."]),
+    ('4', ["...#.", "..##.", ".#.#.", "#..#.", "#####", "...#.", "...#."]),
+    ('5', ["#####", "#....", "####.", "....#", "....#", "#...#", ".###."]),
+    ('6', ["..##.", ".#...", "#....", "####.", "#...#", "#...#", ".###."]),
+    ('7', ["#####", "....#", "...#.", "..#..", ".#...", ".#...", ".#..."]),
+    ('8', [".###.", "#...#", "#...#", ".###.", "#...#", "#...#", ".###."]),
+    ('9', [".###.", "#...#", "#...#", ".####", "....#", "...#.", ".##.."]),
+    ('.', [".....", ".....", ".....", ".....", ".....", ".##..", ".##.."]),
+    (',', [".....", ".....", ".....", ".....", ".##..", "..#..", ".#..."]),
+    (':', [".....", ".##..", ".##..", ".....", ".##..", ".##..", "....."]),
+    (';', [".....", ".##..", ".##..", ".....", ".##..", "..#..", ".#..."]),
+    ('/', [".....", "....#", "...#.", "..#..", ".#...", "#....", "....."]),
+    ('%', ["##...", "##..#", "...#.", "..#..", ".#...", "#..##", "...##"]),
+    ('-', [".....", ".....", ".....", "#####", ".....", ".....", "....."]),
+    ('+', [".....", "..#..", "..#..", "#####", "..#..", "..#..", "....."]),
+    ('(', ["...#.", "..#..", ".#...", ".#...", ".#...", "..#..", "...#."]),
+    (')', [".#...", "..#..", "...#.", "...#.", "...#.", "..#..", ".#..."]),
+    ('[', [".###.", ".#...", ".#...", ".#...", ".#...", ".#...", ".###."]),
+    (']', [".###.", "...#.", "...#.", "...#.", "...#.", "...#.", ".###."]),
+    ('=', [".....", ".....", "#####", ".....", "#####", ".....", "....."]),
+    ('_', [".....", ".....", ".....", ".....", ".....", ".....", "#####"]),
+    ('?', [".###.", "#...#", "....#", "...#.", "..#..", ".....", "..#.."]),
+    ('!', ["..#..", "..#..", "..#..", "..#..", "..#..", ".....", "..#.."]),
+    ('\'', ["..#..", "..#..", ".#...", ".....", ".....", ".....", "....."]),
+    ('<', ["...#.", "..#..", ".#...", "#....", ".#...", "..#..", "...#."]),
+    ('>', [".#...", "..#..", "...#.", "....#", "...#.", "..#..", ".#..."]),
+    ('*', [".....", "..#..", "#.#.#", ".###.", "#.#.#", "..#..", "....."]),
+    ('#', [".#.#.", ".#.#.", "#####", ".#.#.", "#####", ".#.#.", ".#.#."]),
+];
+
+fn glyph(c : char) -> Option<&'static [&'static str; 7]> {
+    if c.is_whitespace() || c.is_control() {
+        return None;
+    }
+    let c = c.to_ascii_uppercase();
+    GLYPHS.iter()
+        .find(|(g, _)| *g == c)
+        .or_else(|| GLYPHS.iter().find(|(g, _)| *g == '?'))
+        .map(|(_, rows)| rows)
+}
+
+// Calls `pixel(x, y)` for every lit pixel of `text`, in font pixels from its top left corner. '\n'
+// starts a new line.
+pub fn for_each_pixel<F : FnMut(u32, u32)>(text : &str, mut pixel : F) {
+    let mut column = 0;
+    let mut line = 0;
+    for c in text.chars() {
+        if c == '\n' {
+            column = 0;
+            line += 1;
+            continue;
+        }
+
+        if let Some(rows) = glyph(c) {
+            for (y, row) in rows.iter().enumerate() {
+                for (x, bit) in row.bytes().enumerate() {
+                    if bit == b'#' {
+                        pixel(column * ADVANCE + x as u32, line * LINE_HEIGHT + y as u32);
+                    }
+                }
+            }
+        }
+        column += 1;
+    }
+}
+
+// Width and height of `text` in font pixels, including the spacing after the last column and line.
+pub fn text_size(text : &str) -> (u32, u32) {
+    let columns = text.split('\n').map(|line| line.chars().count() as u32).max().unwrap_or(0);
+    let lines = text.split('\n').count() as u32;
+    (columns * ADVANCE, lines * LINE_HEIGHT)
+}
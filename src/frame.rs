@@ -4,13 +4,15 @@ pub struct Frame
 {
     pub desc_set : Option<<back::Backend as hal::Backend>::DescriptorSet>,
     pub ubuffer : Option<UploadBuffer>,
-    pub vbuffer : Option<UploadBuffer>
+    pub vbuffer : Option<UploadBuffer>,
+    // Vertices written to vbuffer for the frame.
+    pub vertex_count : u32,
 }
 
 impl Frame
 {
     pub fn new() -> Frame 
     {
-        Frame { desc_set : None, ubuffer : None, vbuffer : None }
+        Frame { desc_set : None, ubuffer : None, vbuffer : None, vertex_count : 0 }
     }
 }
\ No newline at end of file
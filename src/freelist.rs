@@ -0,0 +1,145 @@
+use std::ops::Range;
+
+// First fit allocation of ranges inside one block of device memory. Free ranges are kept sorted and
+// merged with their neighbours, so freeing everything gives back the whole block.
+
+pub struct FreeList
+{
+    size : u64,
+    // Sorted by offset, never touching each other.
+    free : Vec<Range<u64>>,
+}
+
+pub fn align_up(offset : u64, alignment : u64) -> u64 {
+    let alignment = alignment.max(1);
+    (offset + alignment - 1) / alignment * alignment
+}
+
+impl FreeList
+{
+    pub fn new(size : u64) -> FreeList {
+        FreeList { size : size, free : std::iter::once(0 .. size).collect() }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_unused(&self) -> bool {
+        self.free.len() == 1 && self.free[0] == (0 .. self.size)
+    }
+
+    // The lowest range of `size` bytes starting at a multiple of `alignment`, None if no gap is big enough.
+    pub fn allocate(&mut self, size : u64, alignment : u64) -> Option<Range<u64>> {
+        let size = size.max(1);
+        for index in 0..self.free.len() {
+            let gap = self.free[index].clone();
+            let start = align_up(gap.start, alignment);
+            if start >= gap.end || gap.end - start < size {
+                continue;
+            }
+
+            // The alignment padding and whatever is left after the range stay free.
+            let end = start + size;
+            let rest : Vec<Range<u64>> = [gap.start .. start, end .. gap.end].iter()
+                .filter(|range| range.start < range.end)
+                .cloned()
+                .collect();
+            self.free.splice(index ..= index, rest);
+            return Some(start .. end);
+        }
+        None
+    }
+
+    // Gives back a range `allocate` returned.
+    pub fn free(&mut self, range : Range<u64>) {
+        let index = self.free.iter().position(|free| free.start > range.start).unwrap_or(self.free.len());
+        self.free.insert(index, range);
+
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn align_up_rounds_to_multiples() {
+        assert_eq!(align_up(0, 256), 0);
+        assert_eq!(align_up(1, 256), 256);
+        assert_eq!(align_up(256, 256), 256);
+        assert_eq!(align_up(7, 0), 7);
+    }
+
+    #[test]
+    fn aligned_allocation_leaves_the_padding_free() {
+        let mut list = FreeList::new(1024);
+        assert_eq!(list.allocate(10, 1), Some(0 .. 10));
+        assert_eq!(list.allocate(100, 256), Some(256 .. 356));
+        assert_eq!(list.free, vec![10 .. 256, 356 .. 1024]);
+
+        // First fit: the padding takes what fits in it before the space after.
+        assert_eq!(list.allocate(200, 16), Some(16 .. 216));
+        assert_eq!(list.allocate(100, 1), Some(356 .. 456));
+        assert_eq!(list.allocate(40, 1), Some(216 .. 256));
+        assert_eq!(list.free, vec![10 .. 16, 456 .. 1024]);
+    }
+
+    #[test]
+    fn oversize_requests_fail() {
+        let mut list = FreeList::new(1024);
+        assert_eq!(list.allocate(1025, 1), None);
+        assert_eq!(list.allocate(1000, 1), Some(0 .. 1000));
+        assert_eq!(list.allocate(24, 1), Some(1000 .. 1024));
+        assert_eq!(list.allocate(1, 1), None);
+        assert!(!list.is_unused());
+
+        // Enough bytes left, but not at that alignment.
+        let mut list = FreeList::new(1024);
+        list.allocate(1, 1);
+        assert_eq!(list.allocate(1000, 512), None);
+    }
+
+    #[test]
+    fn zero_sized_requests_still_take_a_byte() {
+        let mut list = FreeList::new(16);
+        let a = list.allocate(0, 1).unwrap();
+        let b = list.allocate(0, 1).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn freeing_in_any_order_merges_back() {
+        let orders : [[usize; 4]; 6] = [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2], [2, 0, 3, 1], [1, 2, 0, 3], [0, 3, 1, 2]];
+        for order in orders.iter() {
+            let mut list = FreeList::new(4096);
+            let ranges : Vec<Range<u64>> = [(100, 1), (300, 256), (50, 64), (1000, 1024)].iter()
+                .map(|&(size, alignment)| list.allocate(size, alignment).unwrap())
+                .collect();
+            for &index in order.iter() {
+                assert!(!list.is_unused());
+                list.free(ranges[index].clone());
+            }
+            assert!(list.is_unused(), "order {:?} left {:?}", order, list.free);
+        }
+    }
+
+    #[test]
+    fn freed_ranges_are_reused() {
+        let mut list = FreeList::new(1024);
+        let a = list.allocate(256, 1).unwrap();
+        let b = list.allocate(256, 1).unwrap();
+        let _c = list.allocate(256, 1).unwrap();
+        list.free(a);
+        list.free(b);
+        assert_eq!(list.free, vec![0 .. 512, 768 .. 1024]);
+        assert_eq!(list.allocate(512, 1), Some(0 .. 512));
+    }
+}
@@ -0,0 +1,370 @@
+use hal::{
+    buffer,
+    command,
+    memory as m,
+    pass::Subpass,
+    pso,
+    pso::{PipelineStage, ShaderStageFlags},
+    Primitive,
+};
+use hal::{DescriptorPool, Device};
+
+use std::ops::Range;
+
+use crate::allocator::{GpuAllocator, MemoryUsage};
+use crate::backenderror::BackendDevice;
+use crate::particles::{self, SimParticle, MAX_PARTICLES};
+use crate::shader::load_shader;
+use crate::upload::UploadBuffer;
+
+// Optional GPU simulation of the host particles (--gpu-particles). Emitters still run on the CPU; the
+// particles they spawn each frame are copied into a ring buffer on the GPU, where data/particles.comp
+// integrates them and data/gpuparticle.vert draws them straight from the buffer. particles::integrate
+// remains the reference, check_step compares a GPU step against it on readback. The particles live in
+// device local memory, a check copies them into a host visible buffer before and after the step.
+
+type Backend = back::Backend;
+type DescriptorSetLayout = <Backend as hal::Backend>::DescriptorSetLayout;
+type DescriptorSet = <Backend as hal::Backend>::DescriptorSet;
+
+const ENTRY_NAME : &str = "main";
+const WORKGROUP_SIZE : u32 = 64;
+
+// Particles spawned per frame beyond this are dropped.
+pub const MAX_SPAWNS_PER_FRAME : usize = 16384;
+
+const MODE_CLEAR : u32 = 0;
+const MODE_INTEGRATE : u32 = 1;
+const MODE_SPAWN : u32 = 2;
+
+pub struct GpuParticles
+{
+    particles : UploadBuffer,
+    // The particles before and after a checked step, created on the first check.
+    readback : Option<UploadBuffer>,
+    check_requested : bool,
+    // One per frame in flight, written by the CPU while the other frames are in flight.
+    spawns : Vec<UploadBuffer>,
+    spawn_counts : Vec<u32>,
+    // Kill every particle before the next simulation step.
+    clear : bool,
+    // Next ring buffer slot to spawn into.
+    head : u32,
+    // Slots overwritten by the last recorded spawn pass, check_step skips them.
+    last_spawned : Range<u32>,
+
+    desc_pool : <Backend as hal::Backend>::DescriptorPool,
+    compute_set_layout : DescriptorSetLayout,
+    compute_sets : Vec<DescriptorSet>,
+    compute_layout : <Backend as hal::Backend>::PipelineLayout,
+    compute_pipeline : <Backend as hal::Backend>::ComputePipeline,
+    render_set_layout : DescriptorSetLayout,
+    render_set : DescriptorSet,
+    render_layout : <Backend as hal::Backend>::PipelineLayout,
+    render_pipeline : <Backend as hal::Backend>::GraphicsPipeline,
+}
+
+fn storage_binding(binding : u32, stage_flags : ShaderStageFlags) -> pso::DescriptorSetLayoutBinding {
+    pso::DescriptorSetLayoutBinding {
+        binding : binding,
+        ty : pso::DescriptorType::StorageBuffer,
+        count : 1,
+        stage_flags : stage_flags,
+        immutable_samplers : false,
+    }
+}
+
+impl GpuParticles
+{
+    // `locals_layout` is the set with the view projection uniform the regular particles use, bound as
+    // set 0 when drawing.
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, render_pass : &<Backend as hal::Backend>::RenderPass,
+               locals_layout : &DescriptorSetLayout, frames_in_flight : usize) -> GpuParticles {
+        let stride = std::mem::size_of::<SimParticle>() as u64;
+
+        let particles = UploadBuffer::new(device, allocator, MAX_PARTICLES as u64 * stride, buffer::Usage::STORAGE | buffer::Usage::TRANSFER_SRC,
+                                          MemoryUsage::DeviceLocal)
+            .ok().expect("Can't create GPU particle buffer");
+        let spawns : Vec<UploadBuffer> = (0..frames_in_flight)
+            .map(|_| UploadBuffer::new(device, allocator, MAX_SPAWNS_PER_FRAME as u64 * stride, buffer::Usage::STORAGE, MemoryUsage::Upload)
+                .ok().expect("Can't create GPU particle spawn buffer"))
+            .collect();
+
+        let compute_set_layout = unsafe {
+            device.create_descriptor_set_layout(&[storage_binding(0, ShaderStageFlags::COMPUTE), storage_binding(1, ShaderStageFlags::COMPUTE)], &[])
+        }
+        .expect("Can't create descriptor set layout");
+        let render_set_layout = unsafe {
+            device.create_descriptor_set_layout(&[storage_binding(0, ShaderStageFlags::VERTEX)], &[])
+        }
+        .expect("Can't create descriptor set layout");
+
+        let mut desc_pool = unsafe {
+            device.create_descriptor_pool(
+                frames_in_flight + 1,
+                &[pso::DescriptorRangeDesc { ty : pso::DescriptorType::StorageBuffer, count : frames_in_flight * 2 + 1 }],
+                pso::DescriptorPoolCreateFlags::empty(),
+            )
+        }
+        .expect("Can't create descriptor pool");
+
+        let compute_sets : Vec<DescriptorSet> = (0..frames_in_flight)
+            .map(|_| unsafe { desc_pool.allocate_set(&compute_set_layout) }.unwrap())
+            .collect();
+        let render_set = unsafe { desc_pool.allocate_set(&render_set_layout) }.unwrap();
+
+        unsafe {
+            for (set, spawn_buffer) in compute_sets.iter().zip(spawns.iter()) {
+                device.write_descriptor_sets(vec![
+                    pso::DescriptorSetWrite {
+                        set : set,
+                        binding : 0,
+                        array_offset : 0,
+                        descriptors : Some(pso::Descriptor::Buffer(&particles.device_buffer, None..None)),
+                    },
+                    pso::DescriptorSetWrite {
+                        set : set,
+                        binding : 1,
+                        array_offset : 0,
+                        descriptors : Some(pso::Descriptor::Buffer(&spawn_buffer.device_buffer, None..None)),
+                    },
+                ]);
+            }
+            device.write_descriptor_sets(vec![
+                pso::DescriptorSetWrite {
+                    set : &render_set,
+                    binding : 0,
+                    array_offset : 0,
+                    descriptors : Some(pso::Descriptor::Buffer(&particles.device_buffer, None..None)),
+                },
+            ]);
+        }
+
+        // dt, capacity, head, spawn_count, mode
+        let compute_layout = unsafe {
+            device.create_pipeline_layout(std::iter::once(&compute_set_layout), &[(ShaderStageFlags::COMPUTE, 0 .. 5)])
+        }
+        .expect("Can't create pipeline layout");
+
+        let compute_pipeline = {
+            let module = load_shader(device, "data/particles.comp", glsl_to_spirv::ShaderType::Compute);
+            let entry = pso::EntryPoint { entry : ENTRY_NAME, module : &module, specialization : pso::Specialization::default() };
+            let pipeline = unsafe { device.create_compute_pipeline(&pso::ComputePipelineDesc::new(entry, &compute_layout), None) };
+            unsafe { device.destroy_shader_module(module) };
+            pipeline.expect("Can't create particle compute pipeline")
+        };
+
+        // Camera position and up vector, two vec4s.
+        let render_layout = unsafe {
+            device.create_pipeline_layout(vec![locals_layout, &render_set_layout], &[(ShaderStageFlags::VERTEX, 0 .. 8)])
+        }
+        .expect("Can't create pipeline layout");
+
+        let render_pipeline = {
+            let vs_module = load_shader(device, "data/gpuparticle.vert", glsl_to_spirv::ShaderType::Vertex);
+            let fs_module = load_shader(device, "data/quad.frag", glsl_to_spirv::ShaderType::Fragment);
+
+            let shader_entries = pso::GraphicsShaderSet {
+                vertex : pso::EntryPoint { entry : ENTRY_NAME, module : &vs_module, specialization : pso::Specialization::default() },
+                hull : None,
+                domain : None,
+                geometry : None,
+                fragment : Some(pso::EntryPoint { entry : ENTRY_NAME, module : &fs_module, specialization : pso::Specialization::default() }),
+            };
+
+            let subpass = Subpass { index : 0, main_pass : render_pass };
+            let mut pipeline_desc = pso::GraphicsPipelineDesc::new(shader_entries, Primitive::TriangleList, pso::Rasterizer::FILL, &render_layout, subpass);
+            pipeline_desc.blender.targets.push(pso::ColorBlendDesc {
+                mask : pso::ColorMask::ALL,
+                blend : Some(pso::BlendState::ALPHA),
+            });
+
+            let pipeline = unsafe { device.create_graphics_pipeline(&pipeline_desc, None) };
+            unsafe {
+                device.destroy_shader_module(vs_module);
+                device.destroy_shader_module(fs_module);
+            }
+            pipeline.expect("Can't create GPU particle pipeline")
+        };
+
+        GpuParticles {
+            particles : particles,
+            readback : None,
+            check_requested : false,
+            spawns : spawns,
+            spawn_counts : vec![0; frames_in_flight],
+            // The particle buffer starts out as garbage.
+            clear : true,
+            head : 0,
+            last_spawned : 0 .. 0,
+            desc_pool : desc_pool,
+            compute_set_layout : compute_set_layout,
+            compute_sets : compute_sets,
+            compute_layout : compute_layout,
+            compute_pipeline : compute_pipeline,
+            render_set_layout : render_set_layout,
+            render_set : render_set,
+            render_layout : render_layout,
+            render_pipeline : render_pipeline,
+        }
+    }
+
+    // Stages the particles spawned by the last update for the frame's simulation pass. Call once the
+    // frame's previous submission has completed. `clear` kills every particle on the GPU first.
+    pub fn write_spawns(&mut self, device : &BackendDevice, frame_idx : usize, spawned : &[SimParticle], clear : bool) {
+        let count = spawned.len().min(MAX_SPAWNS_PER_FRAME);
+        if count < spawned.len() {
+            println!("GPU particles: dropping {} spawned particles", spawned.len() - count);
+        }
+
+        if count > 0 {
+            self.spawns[frame_idx].write(device, &spawned[.. count]);
+        }
+
+        self.spawn_counts[frame_idx] = count as u32;
+        self.clear = self.clear || clear;
+    }
+
+    unsafe fn dispatch(&self, cmd_buffer : &mut command::CommandBuffer<Backend, hal::General, command::MultiShot>, dt : f32, spawn_count : u32, mode : u32, threads : u32) {
+        let constants = [dt.to_bits(), MAX_PARTICLES as u32, self.head, spawn_count, mode];
+        cmd_buffer.push_compute_constants(&self.compute_layout, 0, &constants);
+        cmd_buffer.dispatch([(threads + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1]);
+    }
+
+    // Copies the whole ring buffer into the readback buffer at `offset`, after the stages in `after`.
+    unsafe fn record_readback(&self, cmd_buffer : &mut command::CommandBuffer<Backend, hal::General, command::MultiShot>, offset : u64, after : PipelineStage) {
+        let readback = self.readback.as_ref().unwrap();
+        cmd_buffer.pipeline_barrier(
+            after .. PipelineStage::TRANSFER,
+            m::Dependencies::empty(),
+            &[m::Barrier::AllBuffers(buffer::Access::SHADER_WRITE .. buffer::Access::TRANSFER_READ)],
+        );
+        cmd_buffer.copy_buffer(&self.particles.device_buffer, &readback.device_buffer, &[command::BufferCopy {
+            src : 0,
+            dst : offset,
+            size : self.particles.size,
+        }]);
+        // The simulation must not overwrite the particles before they are copied.
+        cmd_buffer.pipeline_barrier(
+            PipelineStage::TRANSFER .. PipelineStage::COMPUTE_SHADER,
+            m::Dependencies::empty(),
+            &[m::Barrier::AllBuffers(buffer::Access::TRANSFER_READ .. buffer::Access::SHADER_WRITE)],
+        );
+    }
+
+    // Has the next recorded step copy the particles before and after it for check_step, see read_check.
+    pub fn request_check(&mut self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        if self.readback.is_none() {
+            self.readback = Some(UploadBuffer::new(device, allocator, self.particles.size * 2, buffer::Usage::TRANSFER_DST, MemoryUsage::Readback)
+                .ok().expect("Can't create GPU particle readback buffer"));
+        }
+        self.check_requested = true;
+    }
+
+    // Records one simulation step: integrate the live particles by `dt`, then add this frame's spawns.
+    // Goes in the frame's command buffer ahead of the render pass that draws them.
+    pub fn record_simulation(&mut self, cmd_buffer : &mut command::CommandBuffer<Backend, hal::General, command::MultiShot>, frame_idx : usize, dt : f32) {
+        let spawn_count = self.spawn_counts[frame_idx];
+        let shader_access = buffer::Access::SHADER_READ | buffer::Access::SHADER_WRITE;
+        let compute_barrier = m::Barrier::AllBuffers(shader_access .. shader_access);
+        let check = self.check_requested;
+
+        unsafe {
+            if check {
+                self.record_readback(cmd_buffer, 0, PipelineStage::VERTEX_SHADER | PipelineStage::COMPUTE_SHADER);
+            }
+
+            // The previous frame's draw reads the buffer we are about to write.
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::VERTEX_SHADER | PipelineStage::COMPUTE_SHADER .. PipelineStage::COMPUTE_SHADER,
+                m::Dependencies::empty(),
+                &[compute_barrier.clone()],
+            );
+
+            cmd_buffer.bind_compute_pipeline(&self.compute_pipeline);
+            cmd_buffer.bind_compute_descriptor_sets(&self.compute_layout, 0, Some(&self.compute_sets[frame_idx]), &[]);
+
+            if self.clear {
+                self.dispatch(cmd_buffer, dt, 0, MODE_CLEAR, MAX_PARTICLES as u32);
+                cmd_buffer.pipeline_barrier(PipelineStage::COMPUTE_SHADER .. PipelineStage::COMPUTE_SHADER, m::Dependencies::empty(), &[compute_barrier.clone()]);
+            }
+
+            self.dispatch(cmd_buffer, dt, 0, MODE_INTEGRATE, MAX_PARTICLES as u32);
+
+            if spawn_count > 0 {
+                cmd_buffer.pipeline_barrier(PipelineStage::COMPUTE_SHADER .. PipelineStage::COMPUTE_SHADER, m::Dependencies::empty(), &[compute_barrier.clone()]);
+                self.dispatch(cmd_buffer, dt, spawn_count, MODE_SPAWN, spawn_count);
+            }
+
+            cmd_buffer.pipeline_barrier(
+                PipelineStage::COMPUTE_SHADER .. PipelineStage::VERTEX_SHADER,
+                m::Dependencies::empty(),
+                &[m::Barrier::AllBuffers(buffer::Access::SHADER_WRITE .. buffer::Access::SHADER_READ)],
+            );
+
+            if check {
+                self.record_readback(cmd_buffer, self.particles.size, PipelineStage::COMPUTE_SHADER);
+                cmd_buffer.pipeline_barrier(
+                    PipelineStage::TRANSFER .. PipelineStage::HOST,
+                    m::Dependencies::empty(),
+                    &[m::Barrier::AllBuffers(buffer::Access::TRANSFER_WRITE .. buffer::Access::HOST_READ)],
+                );
+            }
+        }
+
+        self.check_requested = false;
+        self.clear = false;
+        self.last_spawned = self.head .. self.head + spawn_count;
+        self.head = (self.head + spawn_count) % MAX_PARTICLES as u32;
+    }
+
+    // Draws every slot of the ring buffer, the vertex shader discards the dead ones. `locals` is the
+    // frame's view projection descriptor set.
+    pub fn record_draw(&self, encoder : &mut command::RenderPassInlineEncoder<Backend>, locals : &DescriptorSet, camera_position : [f32; 3], camera_up : [f32; 3]) {
+        let camera = [
+            camera_position[0].to_bits(), camera_position[1].to_bits(), camera_position[2].to_bits(), 0,
+            camera_up[0].to_bits(), camera_up[1].to_bits(), camera_up[2].to_bits(), 0,
+        ];
+
+        unsafe {
+            encoder.bind_graphics_pipeline(&self.render_pipeline);
+            encoder.bind_graphics_descriptor_sets(&self.render_layout, 0, vec![locals, &self.render_set], &[]);
+            encoder.push_graphics_constants(&self.render_layout, ShaderStageFlags::VERTEX, 0, &camera);
+            encoder.draw(0 .. MAX_PARTICLES as u32 * 6, 0 .. 1);
+        }
+    }
+
+    // The whole ring buffer before and after the checked step. Its submission must have completed.
+    pub fn read_check(&self, device : &BackendDevice) -> (Vec<SimParticle>, Vec<SimParticle>) {
+        let readback = self.readback.as_ref().expect("No GPU particle check was recorded");
+        readback.read_with(device, MAX_PARTICLES * 2, |particles : &[SimParticle]| {
+            let (before, after) = particles.split_at(MAX_PARTICLES);
+            (before.to_vec(), after.to_vec())
+        })
+    }
+
+    // Compares the GPU's last step against particles::integrate. `before` is a readback taken before
+    // that step, `after` one taken after it. Returns the number of live particles compared and the
+    // largest position difference.
+    pub fn check_step(&self, before : &[SimParticle], after : &[SimParticle], dt : f32) -> (usize, f32) {
+        particles::compare_step(before, after, dt, |index| {
+            let index = index as u32;
+            self.last_spawned.contains(&index) || self.last_spawned.contains(&(index + MAX_PARTICLES as u32))
+        })
+    }
+
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe {
+            device.destroy_graphics_pipeline(self.render_pipeline);
+            device.destroy_pipeline_layout(self.render_layout);
+            device.destroy_compute_pipeline(self.compute_pipeline);
+            device.destroy_pipeline_layout(self.compute_layout);
+            device.destroy_descriptor_pool(self.desc_pool);
+            device.destroy_descriptor_set_layout(self.render_set_layout);
+            device.destroy_descriptor_set_layout(self.compute_set_layout);
+        }
+        for upload in self.spawns.into_iter().chain(std::iter::once(self.particles)).chain(self.readback) {
+            upload.destroy(device, allocator);
+        }
+    }
+}
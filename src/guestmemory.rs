@@ -0,0 +1,74 @@
+use std::cell::Cell;
+
+// Access to a script's linear memory. Every access is bounds checked, a script handing the host a
+// bad pointer gets its string truncated instead of crashing the host.
+pub trait GuestMemory
+{
+    fn size(&self) -> usize;
+    fn read(&self, offset : usize, buffer : &mut [u8]) -> bool;
+    fn write(&self, offset : usize, data : &[u8]) -> bool;
+}
+
+impl GuestMemory for [Cell<u8>]
+{
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn read(&self, offset : usize, buffer : &mut [u8]) -> bool {
+        let end = match offset.checked_add(buffer.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return false,
+        };
+
+        for (dst, src) in buffer.iter_mut().zip(self[offset..end].iter()) {
+            *dst = src.get();
+        }
+        true
+    }
+
+    fn write(&self, offset : usize, data : &[u8]) -> bool {
+        let end = match offset.checked_add(data.len()) {
+            Some(end) if end <= self.len() => end,
+            _ => return false,
+        };
+
+        for (dst, src) in self[offset..end].iter().zip(data.iter()) {
+            dst.set(*src);
+        }
+        true
+    }
+}
+
+pub fn read_u32<M : GuestMemory + ?Sized>(memory : &M, offset : usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    if memory.read(offset, &mut bytes) { Some(u32::from_le_bytes(bytes)) } else { None }
+}
+
+pub fn read_u64<M : GuestMemory + ?Sized>(memory : &M, offset : usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if memory.read(offset, &mut bytes) { Some(u64::from_le_bytes(bytes)) } else { None }
+}
+
+// Reads `len` bytes at `ptr` as UTF-8, replacing invalid sequences.
+pub fn read_string<M : GuestMemory + ?Sized>(memory : &M, ptr : u32, len : u32) -> String {
+    let start = (ptr as usize).min(memory.size());
+    let len = (len as usize).min(memory.size() - start);
+
+    let mut bytes = vec![0u8; len];
+    memory.read(start, &mut bytes);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Reads a NUL-terminated string at `ptr`, stopping at the end of memory or after `max_len` bytes.
+pub fn read_c_string<M : GuestMemory + ?Sized>(memory : &M, ptr : u32, max_len : usize) -> String {
+    let mut bytes = Vec::new();
+    let mut byte = [0u8; 1];
+    let mut offset = ptr as usize;
+
+    while bytes.len() < max_len && memory.read(offset, &mut byte) && byte[0] != 0 {
+        bytes.push(byte[0]);
+        offset += 1;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,319 @@
+use nalgebra_glm as glm;
+use glm::vec3;
+
+use rand::Rng;
+
+use crate::WorldState;
+use crate::world::Particle;
+use crate::guestmemory::{self, GuestMemory};
+use crate::scriptlog::{self, ScriptLog};
+use crate::engine::ScriptValue;
+use crate::color::{self, ColorSpace};
+use crate::particles::{EmitterParams, RenderMode};
+use crate::debugdraw::{self, TextAnchor};
+use crate::mesh::{self, MeshVertex};
+
+// Implementations of the functions listed in hostapi::HOST_FUNCTIONS. Engines only translate their
+// calling convention into these; the behaviour lives here once.
+
+// What host functions see while a script call is running.
+pub struct HostContext<'a>
+{
+    pub world : &'a mut WorldState,
+    pub log : &'a mut ScriptLog,
+    pub color_space : &'a mut ColorSpace,
+}
+
+pub fn set_camera(host : &mut HostContext, x0 : f32, y0: f32, z0: f32, x1 : f32, y1: f32, z1: f32) {
+    host.world.set_camera(vec3(x0, y0, z0), vec3(x1, y1, z1));
+}
+
+pub fn add_particle(host : &mut HostContext, x0 : f32, y0: f32, z0: f32, size : f32, color : u32) {
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.add_particle(Particle{ position : vec3(x0, y0, z0), size : size, color : color });
+}
+
+pub fn add_particle_rgba(host : &mut HostContext, x0 : f32, y0: f32, z0: f32, size : f32, r : f32, g : f32, b : f32, a : f32) {
+    let color = color::rgba_to_linear([r, g, b, a], *host.color_space);
+    host.world.add_particle(Particle{ position : vec3(x0, y0, z0), size : size, color : color });
+}
+
+pub fn add_particle_hsv(host : &mut HostContext, x0 : f32, y0: f32, z0: f32, size : f32, h : f32, s : f32, v : f32, a : f32) {
+    let [r, g, b] = color::hsv_to_rgb(h, s, v);
+    add_particle_rgba(host, x0, y0, z0, size, r, g, b, a);
+}
+
+pub fn set_color_space(host : &mut HostContext, space : i32) {
+    match ColorSpace::from_i32(space) {
+        Some(space) => *host.color_space = space,
+        None => host.log.log(2, &format!("set_color_space: unknown color space {}", space)),
+    }
+}
+
+pub fn create_emitter(host : &mut HostContext) -> i32 {
+    host.world.particle_system.create_emitter()
+}
+
+pub fn destroy_emitter(host : &mut HostContext, emitter : i32) {
+    if !host.world.particle_system.destroy_emitter(emitter) {
+        host.log.log(2, &format!("destroy_emitter: unknown emitter {}", emitter));
+    }
+}
+
+pub fn emit_particles(host : &mut HostContext, emitter : i32, count : i32) {
+    if !host.world.particle_system.emit(emitter, count.max(0) as u32) {
+        host.log.log(2, &format!("emit_particles: unknown emitter {}", emitter));
+    }
+}
+
+// Applies `update` to an emitter's parameters, unknown ids are reported to the script log.
+fn update_emitter<F : FnOnce(&mut EmitterParams, ColorSpace)>(host : &mut HostContext, function : &str, emitter : i32, update : F) {
+    let color_space = *host.color_space;
+    match host.world.particle_system.emitter_params_mut(emitter) {
+        Some(params) => update(params, color_space),
+        None => host.log.log(2, &format!("{}: unknown emitter {}", function, emitter)),
+    }
+}
+
+pub fn set_emitter_position(host : &mut HostContext, emitter : i32, x : f32, y : f32, z : f32) {
+    update_emitter(host, "set_emitter_position", emitter, |params, _| params.position = vec3(x, y, z));
+}
+
+pub fn set_emitter_velocity(host : &mut HostContext, emitter : i32, x : f32, y : f32, z : f32, spread : f32) {
+    update_emitter(host, "set_emitter_velocity", emitter, |params, _| {
+        params.velocity = vec3(x, y, z);
+        params.spread = spread.abs();
+    });
+}
+
+pub fn set_emitter_rate(host : &mut HostContext, emitter : i32, particles_per_second : f32) {
+    update_emitter(host, "set_emitter_rate", emitter, |params, _| params.rate = particles_per_second.max(0.0));
+}
+
+pub fn set_emitter_lifetime(host : &mut HostContext, emitter : i32, seconds : f32) {
+    update_emitter(host, "set_emitter_lifetime", emitter, |params, _| params.lifetime = seconds);
+}
+
+pub fn set_emitter_appearance(host : &mut HostContext, emitter : i32, size : f32, color : u32) {
+    update_emitter(host, "set_emitter_appearance", emitter, |params, color_space| {
+        params.size = size;
+        params.color = color::packed_to_linear(color, color_space);
+    });
+}
+
+pub fn set_emitter_physics(host : &mut HostContext, emitter : i32, gx : f32, gy : f32, gz : f32, drag : f32) {
+    update_emitter(host, "set_emitter_physics", emitter, |params, _| {
+        params.gravity = vec3(gx, gy, gz);
+        params.drag = drag.max(0.0);
+    });
+}
+
+pub fn set_emitter_callbacks(host : &mut HostContext, emitter : i32, flags : i32) {
+    update_emitter(host, "set_emitter_callbacks", emitter, |params, _| params.callbacks = flags);
+}
+
+pub fn set_emitter_render_mode(host : &mut HostContext, emitter : i32, mode : i32, length : f32) {
+    let mode = match RenderMode::from_i32(mode) {
+        Some(mode) => mode,
+        None => return host.log.log(2, &format!("set_emitter_render_mode: unknown mode {}", mode)),
+    };
+    update_emitter(host, "set_emitter_render_mode", emitter, |params, _| {
+        params.render_mode = mode;
+        params.trail_length = length.max(0.0);
+    });
+}
+
+pub fn set_particle_velocity(host : &mut HostContext, particle : i32, x : f32, y : f32, z : f32) {
+    match host.world.particle_system.particle_mut(particle) {
+        Some(particle) => particle.velocity = vec3(x, y, z),
+        None => host.log.log(2, &format!("set_particle_velocity: unknown particle {}", particle)),
+    }
+}
+
+pub fn set_particle_color(host : &mut HostContext, particle : i32, color : u32) {
+    let color = color::packed_to_linear(color, *host.color_space);
+    match host.world.particle_system.particle_mut(particle) {
+        Some(particle) => particle.color = color,
+        None => host.log.log(2, &format!("set_particle_color: unknown particle {}", particle)),
+    }
+}
+
+pub fn set_particle_render_mode(host : &mut HostContext, particle : i32, mode : i32, length : f32) {
+    let mode = match RenderMode::from_i32(mode) {
+        Some(mode) => mode,
+        None => return host.log.log(2, &format!("set_particle_render_mode: unknown mode {}", mode)),
+    };
+    match host.world.particle_system.trail_mut(particle) {
+        Some(trail) => {
+            trail.mode = mode;
+            trail.length = length.max(0.0);
+        }
+        None => host.log.log(2, &format!("set_particle_render_mode: unknown particle {}", particle)),
+    }
+}
+
+pub fn draw_line(host : &mut HostContext, x0 : f32, y0 : f32, z0 : f32, x1 : f32, y1 : f32, z1 : f32, color : u32) {
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.debug.add_line(vec3(x0, y0, z0), vec3(x1, y1, z1), color);
+}
+
+pub fn draw_box(host : &mut HostContext, minx : f32, miny : f32, minz : f32, maxx : f32, maxy : f32, maxz : f32, color : u32) {
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.debug.add_box(vec3(minx, miny, minz), vec3(maxx, maxy, maxz), color);
+}
+
+pub fn draw_sphere_wire(host : &mut HostContext, x : f32, y : f32, z : f32, radius : f32, color : u32) {
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.debug.add_sphere(vec3(x, y, z), radius, color);
+}
+
+pub fn draw_text_2d<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, x : f32, y : f32, ptr : i32, len : i32, color : u32) {
+    let text = guestmemory::read_string(memory, ptr as u32, (len.max(0) as u32).min(debugdraw::MAX_DEBUG_TEXT_LEN as u32));
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.debug.add_text(TextAnchor::Screen(x, y), &text, color);
+}
+
+pub fn draw_text_3d<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, x : f32, y : f32, z : f32, ptr : i32, len : i32, color : u32) {
+    let text = guestmemory::read_string(memory, ptr as u32, (len.max(0) as u32).min(debugdraw::MAX_DEBUG_TEXT_LEN as u32));
+    let color = color::packed_to_linear(color, *host.color_space);
+    host.world.debug.add_text(TextAnchor::World(vec3(x, y, z)), &text, color);
+}
+
+// Reads a script's vertices, with the colors converted like particle colors.
+fn read_mesh_vertices<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, function : &str, ptr : i32, count : i32) -> Option<Vec<MeshVertex>> {
+    match mesh::read_vertices(memory, ptr as u32, count.max(0) as u32) {
+        Some(mut vertices) => {
+            for vertex in vertices.iter_mut() {
+                vertex.color = color::packed_to_linear(vertex.color, *host.color_space);
+            }
+            Some(vertices)
+        }
+        None => {
+            host.log.log(2, &format!("{}: {} vertices at {} are outside memory or too many", function, count, ptr));
+            None
+        }
+    }
+}
+
+pub fn add_triangles<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, ptr : i32, count : i32) {
+    if let Some(vertices) = read_mesh_vertices(host, memory, "add_triangles", ptr, count) {
+        if !host.world.meshes.add_triangles(&vertices) {
+            host.log.log(2, "add_triangles: mesh budget exceeded, triangles dropped");
+        }
+    }
+}
+
+pub fn add_mesh<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, vertices_ptr : i32, vertex_count : i32, indices_ptr : i32, index_count : i32) {
+    let vertices = match read_mesh_vertices(host, memory, "add_mesh", vertices_ptr, vertex_count) {
+        Some(vertices) => vertices,
+        None => return,
+    };
+    let indices = match mesh::read_indices(memory, indices_ptr as u32, index_count.max(0) as u32) {
+        Some(indices) => indices,
+        None => {
+            host.log.log(2, &format!("add_mesh: {} indices at {} are outside memory or too many", index_count, indices_ptr));
+            return;
+        }
+    };
+
+    if !host.world.meshes.add_indexed(&vertices, &indices) {
+        host.log.log(2, "add_mesh: mesh budget exceeded, mesh dropped");
+    }
+}
+
+pub fn sinf(x : f32) -> f32 {
+    x.sin()
+}
+
+pub fn cosf(x : f32) -> f32 {
+    x.cos()
+}
+
+pub fn memcpy<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, dst : i32, src : i32, len : i32) -> i32 {
+    // Checked before allocating, `len` is up to the script.
+    let (dst_start, src_start, len) = (dst as u32 as usize, src as u32 as usize, len.max(0) as usize);
+    let inside = |start : usize| start.checked_add(len).map_or(false, |end| end <= memory.size());
+    if !inside(dst_start) || !inside(src_start) {
+        host.log.log(2, &format!("memcpy: {} bytes from {} to {} are outside memory, nothing copied", len, src_start, dst_start));
+        return dst;
+    }
+
+    let mut bytes = vec![0u8; len];
+    memory.read(src_start, &mut bytes);
+    memory.write(dst_start, &bytes);
+
+    dst
+}
+
+// From the world's seeded generator, so recorded sessions replay the same numbers.
+pub fn rand(host : &mut HostContext) -> i32 {
+    host.world.rng.gen()
+}
+
+pub fn log_message<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, level : i32, ptr : i32, len : i32) {
+    let message = guestmemory::read_string(memory, ptr as u32, len as u32);
+    host.log.log(level, &message);
+}
+
+pub fn log_printf<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, level : i32, format_ptr : i32, args_ptr : i32) {
+    let format = guestmemory::read_c_string(memory, format_ptr as u32, 4096);
+    let message = scriptlog::format_printf(memory, &format, args_ptr as u32);
+    host.log.log(level, &message);
+}
+
+fn arg_i32(args : &[ScriptValue], index : usize) -> Result<i32, String> {
+    match args.get(index) {
+        Some(ScriptValue::I32(value)) => Ok(*value),
+        other => Err(format!("argument {}: expected i32, got {:?}", index, other)),
+    }
+}
+
+fn arg_f32(args : &[ScriptValue], index : usize) -> Result<f32, String> {
+    match args.get(index) {
+        Some(ScriptValue::F32(value)) => Ok(*value),
+        other => Err(format!("argument {}: expected f32, got {:?}", index, other)),
+    }
+}
+
+// Calls a host function by name with untyped arguments, for engines that can't bind typed Rust
+// functions directly.
+pub fn dispatch<M : GuestMemory + ?Sized>(host : &mut HostContext, memory : &M, name : &str, args : &[ScriptValue]) -> Result<Option<ScriptValue>, String> {
+    let a = args;
+    let result = match name {
+        "set_camera" => { set_camera(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?, arg_f32(a, 5)?); None }
+        "add_particle" => { add_particle(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_i32(a, 4)? as u32); None }
+        "add_particle_rgba" => { add_particle_rgba(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?, arg_f32(a, 5)?, arg_f32(a, 6)?, arg_f32(a, 7)?); None }
+        "add_particle_hsv" => { add_particle_hsv(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?, arg_f32(a, 5)?, arg_f32(a, 6)?, arg_f32(a, 7)?); None }
+        "set_color_space" => { set_color_space(host, arg_i32(a, 0)?); None }
+        "create_emitter" => Some(ScriptValue::I32(create_emitter(host))),
+        "destroy_emitter" => { destroy_emitter(host, arg_i32(a, 0)?); None }
+        "emit_particles" => { emit_particles(host, arg_i32(a, 0)?, arg_i32(a, 1)?); None }
+        "set_emitter_position" => { set_emitter_position(host, arg_i32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?); None }
+        "set_emitter_velocity" => { set_emitter_velocity(host, arg_i32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?); None }
+        "set_emitter_rate" => { set_emitter_rate(host, arg_i32(a, 0)?, arg_f32(a, 1)?); None }
+        "set_emitter_lifetime" => { set_emitter_lifetime(host, arg_i32(a, 0)?, arg_f32(a, 1)?); None }
+        "set_emitter_appearance" => { set_emitter_appearance(host, arg_i32(a, 0)?, arg_f32(a, 1)?, arg_i32(a, 2)? as u32); None }
+        "set_emitter_physics" => { set_emitter_physics(host, arg_i32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?); None }
+        "set_emitter_callbacks" => { set_emitter_callbacks(host, arg_i32(a, 0)?, arg_i32(a, 1)?); None }
+        "set_particle_velocity" => { set_particle_velocity(host, arg_i32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?); None }
+        "set_particle_color" => { set_particle_color(host, arg_i32(a, 0)?, arg_i32(a, 1)? as u32); None }
+        "set_emitter_render_mode" => { set_emitter_render_mode(host, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_f32(a, 2)?); None }
+        "set_particle_render_mode" => { set_particle_render_mode(host, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_f32(a, 2)?); None }
+        "draw_line" => { draw_line(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?, arg_f32(a, 5)?, arg_i32(a, 6)? as u32); None }
+        "draw_box" => { draw_box(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_f32(a, 4)?, arg_f32(a, 5)?, arg_i32(a, 6)? as u32); None }
+        "draw_sphere_wire" => { draw_sphere_wire(host, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_f32(a, 3)?, arg_i32(a, 4)? as u32); None }
+        "draw_text_2d" => { draw_text_2d(host, memory, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_i32(a, 2)?, arg_i32(a, 3)?, arg_i32(a, 4)? as u32); None }
+        "draw_text_3d" => { draw_text_3d(host, memory, arg_f32(a, 0)?, arg_f32(a, 1)?, arg_f32(a, 2)?, arg_i32(a, 3)?, arg_i32(a, 4)?, arg_i32(a, 5)? as u32); None }
+        "add_triangles" => { add_triangles(host, memory, arg_i32(a, 0)?, arg_i32(a, 1)?); None }
+        "add_mesh" => { add_mesh(host, memory, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_i32(a, 2)?, arg_i32(a, 3)?); None }
+        "cosf" => Some(ScriptValue::F32(cosf(arg_f32(a, 0)?))),
+        "sinf" => Some(ScriptValue::F32(sinf(arg_f32(a, 0)?))),
+        "memcpy" => Some(ScriptValue::I32(memcpy(host, memory, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_i32(a, 2)?))),
+        "rand" => Some(ScriptValue::I32(rand(host))),
+        "log_message" => { log_message(host, memory, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_i32(a, 2)?); None }
+        "log_printf" => { log_printf(host, memory, arg_i32(a, 0)?, arg_i32(a, 1)?, arg_i32(a, 2)?); None }
+        _ => return Err(format!("unknown host function `{}`", name)),
+    };
+
+    Ok(result)
+}
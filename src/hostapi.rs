@@ -0,0 +1,395 @@
+use std::fmt;
+
+// Table of everything that crosses the host/script boundary. The import object in vm.rs, the
+// pre-instantiation validator and the guest SDKs in sdk/ (generated by src/bin/sdkgen.rs) are all
+// driven from here, so adding a host function starts here.
+//
+// Keep this file free of crate dependencies, sdkgen includes it on its own.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType
+{
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature
+{
+    pub params : &'static [ValueType],
+    pub returns : &'static [ValueType],
+}
+
+// How an argument is spelled in the guest SDKs. Each one lowers to a single wasm value, which has to
+// agree with the signature next to it; sdkgen checks that. The SDK-only parts of the tables aren't read
+// by the host itself, hence the dead_code allowances.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum ArgType
+{
+    Int,
+    UInt,
+    Float,
+    Size,
+    Ptr,
+    ConstPtr,
+    Str,
+    // C varargs, passed by clang as a pointer to the packed arguments.
+    VarArgs,
+}
+
+#[allow(dead_code)]
+pub struct Arg
+{
+    pub name : &'static str,
+    pub ty : ArgType,
+}
+
+pub struct HostFunction
+{
+    pub name : &'static str,
+    pub signature : FunctionSignature,
+    #[allow(dead_code)]
+    pub args : &'static [Arg],
+    #[allow(dead_code)]
+    pub result : Option<ArgType>,
+    #[allow(dead_code)]
+    pub doc : &'static str,
+}
+
+pub struct ScriptExport
+{
+    pub name : &'static str,
+    pub signature : FunctionSignature,
+    pub required : bool,
+    // An older signature that is still accepted. Such exports are called with the leading arguments only.
+    pub legacy_signature : Option<FunctionSignature>,
+    #[allow(dead_code)]
+    pub args : &'static [Arg],
+    #[allow(dead_code)]
+    pub doc : &'static str,
+}
+
+use ValueType::*;
+use ArgType::*;
+
+pub const HOST_NAMESPACE : &str = "env";
+
+const fn arg(name : &'static str, ty : ArgType) -> Arg {
+    Arg { name : name, ty : ty }
+}
+
+pub const HOST_FUNCTIONS : &[HostFunction] = &[
+    HostFunction {
+        name : "set_camera",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, F32, F32], returns : &[] },
+        args : &[arg("posx", Float), arg("posy", Float), arg("posz", Float), arg("lookatx", Float), arg("lookaty", Float), arg("lookatz", Float)],
+        result : None,
+        doc : "Places the camera at pos, looking at lookat.",
+    },
+    HostFunction {
+        name : "add_particle",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, I32], returns : &[] },
+        args : &[arg("posx", Float), arg("posy", Float), arg("posz", Float), arg("size", Float), arg("color", UInt)],
+        result : None,
+        doc : "Draws a particle this frame. color is RGBA8 with red in the lowest byte.",
+    },
+    HostFunction {
+        name : "add_particle_rgba",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, F32, F32, F32, F32], returns : &[] },
+        args : &[arg("posx", Float), arg("posy", Float), arg("posz", Float), arg("size", Float), arg("r", Float), arg("g", Float), arg("b", Float), arg("a", Float)],
+        result : None,
+        doc : "add_particle with float channels in [0, 1].",
+    },
+    HostFunction {
+        name : "add_particle_hsv",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, F32, F32, F32, F32], returns : &[] },
+        args : &[arg("posx", Float), arg("posy", Float), arg("posz", Float), arg("size", Float), arg("h", Float), arg("s", Float), arg("v", Float), arg("a", Float)],
+        result : None,
+        doc : "add_particle with the hue in degrees and saturation, value and alpha in [0, 1].",
+    },
+    HostFunction {
+        name : "set_color_space",
+        signature : FunctionSignature { params : &[I32], returns : &[] },
+        args : &[arg("space", Int)],
+        result : None,
+        doc : "How the script's particle colors are encoded, 0 = linear (the default) or 1 = sRGB. sRGB colors are converted to linear by the host.",
+    },
+    HostFunction {
+        name : "cosf",
+        signature : FunctionSignature { params : &[F32], returns : &[F32] },
+        args : &[arg("x", Float)],
+        result : Some(Float),
+        doc : "",
+    },
+    HostFunction {
+        name : "sinf",
+        signature : FunctionSignature { params : &[F32], returns : &[F32] },
+        args : &[arg("x", Float)],
+        result : Some(Float),
+        doc : "",
+    },
+    HostFunction {
+        name : "memcpy",
+        signature : FunctionSignature { params : &[I32, I32, I32], returns : &[I32] },
+        args : &[arg("dst", Ptr), arg("src", ConstPtr), arg("len", Size)],
+        result : Some(Ptr),
+        doc : "",
+    },
+    HostFunction {
+        name : "rand",
+        signature : FunctionSignature { params : &[], returns : &[I32] },
+        args : &[],
+        result : Some(Int),
+        doc : "",
+    },
+    HostFunction {
+        name : "log_message",
+        signature : FunctionSignature { params : &[I32, I32, I32], returns : &[] },
+        args : &[arg("level", Int), arg("str", Str), arg("len", Int)],
+        result : None,
+        doc : "Logs len bytes of str. Levels are 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace.",
+    },
+    HostFunction {
+        name : "log_printf",
+        signature : FunctionSignature { params : &[I32, I32, I32], returns : &[] },
+        args : &[arg("level", Int), arg("format", Str), arg("args", VarArgs)],
+        result : None,
+        doc : "Logs a printf style message, see log_message for the levels.",
+    },
+    HostFunction {
+        name : "create_emitter",
+        signature : FunctionSignature { params : &[], returns : &[I32] },
+        args : &[],
+        result : Some(Int),
+        doc : "Creates a host simulated particle emitter and returns its id. Emitters are destroyed when the script is unloaded.",
+    },
+    HostFunction {
+        name : "destroy_emitter",
+        signature : FunctionSignature { params : &[I32], returns : &[] },
+        args : &[arg("emitter", Int)],
+        result : None,
+        doc : "Stops an emitter, its particles live out their lifetime.",
+    },
+    HostFunction {
+        name : "emit_particles",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        args : &[arg("emitter", Int), arg("count", Int)],
+        result : None,
+        doc : "Emits count particles at once on the next update, on top of the rate.",
+    },
+    HostFunction {
+        name : "set_emitter_position",
+        signature : FunctionSignature { params : &[I32, F32, F32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("x", Float), arg("y", Float), arg("z", Float)],
+        result : None,
+        doc : "",
+    },
+    HostFunction {
+        name : "set_emitter_velocity",
+        signature : FunctionSignature { params : &[I32, F32, F32, F32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("x", Float), arg("y", Float), arg("z", Float), arg("spread", Float)],
+        result : None,
+        doc : "Initial velocity in units per second, each component randomized by up to +-spread.",
+    },
+    HostFunction {
+        name : "set_emitter_rate",
+        signature : FunctionSignature { params : &[I32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("particles_per_second", Float)],
+        result : None,
+        doc : "",
+    },
+    HostFunction {
+        name : "set_emitter_lifetime",
+        signature : FunctionSignature { params : &[I32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("seconds", Float)],
+        result : None,
+        doc : "",
+    },
+    HostFunction {
+        name : "set_emitter_appearance",
+        signature : FunctionSignature { params : &[I32, F32, I32], returns : &[] },
+        args : &[arg("emitter", Int), arg("size", Float), arg("color", UInt)],
+        result : None,
+        doc : "color is packed like add_particle colors, in the script's color space.",
+    },
+    HostFunction {
+        name : "set_emitter_physics",
+        signature : FunctionSignature { params : &[I32, F32, F32, F32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("gravityx", Float), arg("gravityy", Float), arg("gravityz", Float), arg("drag", Float)],
+        result : None,
+        doc : "Acceleration in units per second squared, drag is the fraction of velocity lost per second.",
+    },
+    HostFunction {
+        name : "set_emitter_callbacks",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        args : &[arg("emitter", Int), arg("flags", Int)],
+        result : None,
+        doc : "Calls on_particle_spawn (flag 1) and on_particle_death (flag 2) for this emitter's particles.",
+    },
+    HostFunction {
+        name : "set_particle_velocity",
+        signature : FunctionSignature { params : &[I32, F32, F32, F32], returns : &[] },
+        args : &[arg("particle", Int), arg("x", Float), arg("y", Float), arg("z", Float)],
+        result : None,
+        doc : "Changes a particle from on_particle_spawn, which passes its index.",
+    },
+    HostFunction {
+        name : "set_emitter_render_mode",
+        signature : FunctionSignature { params : &[I32, I32, F32], returns : &[] },
+        args : &[arg("emitter", Int), arg("mode", Int), arg("length", Float)],
+        result : None,
+        doc : "Draws this emitter's particles as billboards (mode 0), stretched along their velocity (1) or as ribbons through their past positions (2), length seconds long.",
+    },
+    HostFunction {
+        name : "set_particle_render_mode",
+        signature : FunctionSignature { params : &[I32, I32, F32], returns : &[] },
+        args : &[arg("particle", Int), arg("mode", Int), arg("length", Float)],
+        result : None,
+        doc : "Like set_emitter_render_mode for one particle, from on_particle_spawn.",
+    },
+    HostFunction {
+        name : "set_particle_color",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        args : &[arg("particle", Int), arg("color", UInt)],
+        result : None,
+        doc : "Changes a particle from on_particle_spawn, which passes its index.",
+    },
+    HostFunction {
+        name : "draw_line",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, F32, F32, I32], returns : &[] },
+        args : &[arg("x0", Float), arg("y0", Float), arg("z0", Float), arg("x1", Float), arg("y1", Float), arg("z1", Float), arg("color", UInt)],
+        result : None,
+        doc : "Draws a debug line this frame. Debug colors are packed like add_particle colors.",
+    },
+    HostFunction {
+        name : "draw_box",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, F32, F32, I32], returns : &[] },
+        args : &[arg("minx", Float), arg("miny", Float), arg("minz", Float), arg("maxx", Float), arg("maxy", Float), arg("maxz", Float), arg("color", UInt)],
+        result : None,
+        doc : "Draws the edges of an axis aligned box this frame.",
+    },
+    HostFunction {
+        name : "draw_sphere_wire",
+        signature : FunctionSignature { params : &[F32, F32, F32, F32, I32], returns : &[] },
+        args : &[arg("x", Float), arg("y", Float), arg("z", Float), arg("radius", Float), arg("color", UInt)],
+        result : None,
+        doc : "Draws a sphere as three circles this frame.",
+    },
+    HostFunction {
+        name : "draw_text_2d",
+        signature : FunctionSignature { params : &[F32, F32, I32, I32, I32], returns : &[] },
+        args : &[arg("x", Float), arg("y", Float), arg("str", Str), arg("len", Int), arg("color", UInt)],
+        result : None,
+        doc : "Draws len bytes of str this frame, x and y in pixels from the top left of the window.",
+    },
+    HostFunction {
+        name : "draw_text_3d",
+        signature : FunctionSignature { params : &[F32, F32, F32, I32, I32, I32], returns : &[] },
+        args : &[arg("x", Float), arg("y", Float), arg("z", Float), arg("str", Str), arg("len", Int), arg("color", UInt)],
+        result : None,
+        doc : "Draws len bytes of str this frame, starting at a point in the world.",
+    },
+    HostFunction {
+        name : "add_triangles",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        args : &[arg("vertices", ConstPtr), arg("count", Int)],
+        result : None,
+        doc : "Draws count vertices (ws_vertex) as triangles this frame, every three making one.",
+    },
+    HostFunction {
+        name : "add_mesh",
+        signature : FunctionSignature { params : &[I32, I32, I32, I32], returns : &[] },
+        args : &[arg("vertices", ConstPtr), arg("vertex_count", Int), arg("indices", ConstPtr), arg("index_count", Int)],
+        result : None,
+        doc : "Draws an indexed mesh this frame. indices are unsigned ints into vertices (ws_vertex), three per triangle.",
+    },
+];
+
+pub const SCRIPT_EXPORTS : &[ScriptExport] = &[
+    ScriptExport {
+        name : "tick",
+        signature : FunctionSignature { params : &[F32, F32], returns : &[] },
+        required : true,
+        legacy_signature : Some(FunctionSignature { params : &[F32], returns : &[] }),
+        args : &[arg("t", Float), arg("dt", Float)],
+        doc : "Called every fixed simulation step with the simulation time and the step length, in seconds.",
+    },
+    ScriptExport {
+        name : "init",
+        signature : FunctionSignature { params : &[], returns : &[] },
+        required : false,
+        legacy_signature : None,
+        args : &[],
+        doc : "Called after the script is loaded.",
+    },
+    ScriptExport {
+        name : "shutdown",
+        signature : FunctionSignature { params : &[], returns : &[] },
+        required : false,
+        legacy_signature : None,
+        args : &[],
+        doc : "Called before the script is unloaded or reloaded.",
+    },
+    ScriptExport {
+        name : "on_resize",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        required : false,
+        legacy_signature : None,
+        args : &[arg("width", Int), arg("height", Int)],
+        doc : "Called when the swapchain is recreated.",
+    },
+    ScriptExport {
+        name : "on_particle_spawn",
+        signature : FunctionSignature { params : &[I32, I32], returns : &[] },
+        required : false,
+        legacy_signature : None,
+        args : &[arg("emitter", Int), arg("particle", Int)],
+        doc : "Called for particles of emitters with the spawn callback flag.",
+    },
+    ScriptExport {
+        name : "on_particle_death",
+        signature : FunctionSignature { params : &[I32, F32, F32, F32], returns : &[] },
+        required : false,
+        legacy_signature : None,
+        args : &[arg("emitter", Int), arg("x", Float), arg("y", Float), arg("z", Float)],
+        doc : "Called where a particle of an emitter with the death callback flag expired.",
+    },
+];
+
+pub fn host_function(name : &str) -> Option<&'static HostFunction> {
+    HOST_FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+pub fn script_export(name : &str) -> Option<&'static ScriptExport> {
+    SCRIPT_EXPORTS.iter().find(|e| e.name == name)
+}
+
+impl fmt::Display for ValueType
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            I32 => "i32",
+            I64 => "i64",
+            F32 => "f32",
+            F64 => "f64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn format_signature(params : &[ValueType], returns : &[ValueType]) -> String {
+    fn join(types : &[ValueType]) -> String {
+        types.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")
+    }
+    format!("({})->({})", join(params), join(returns))
+}
+
+impl fmt::Display for FunctionSignature
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_signature(self.params, self.returns))
+    }
+}
@@ -30,7 +30,6 @@ use hal::{
     format as f,
     image as i,
     memory as m,
-    pass,
     pool,
     pso,
     window::Extent2D,
@@ -45,6 +44,9 @@ use std::time::{Duration, Instant};
 mod backenderror;
 use backenderror::{BackendDevice};
 
+mod freelist;
+mod allocator;
+use allocator::{AllocatorStats, GpuAllocator, MemoryUsage};
 mod upload;
 use upload::{UploadBuffer};
 
@@ -54,21 +56,68 @@ use frame::{Frame};
 mod world;
 use world::{WorldState};
 
+mod hostapi;
+mod validate;
+mod guestmemory;
+mod scriptlog;
+mod color;
+mod particles;
+use particles::{RenderMode};
+mod billboard;
+use billboard::{Sprite, Vertex};
+mod culling;
+use culling::{CullStats, LodParticle, ParticleLod, ViewVolume};
+mod debugdraw;
+use debugdraw::{TextAnchor};
+mod mesh;
+mod gpuparticles;
+mod shader;
+mod font;
+mod host;
+
+mod engine;
+mod scriptcache;
+mod scriptbuild;
+#[cfg(feature = "jit")]
+mod wasmerengine;
+mod wasmiengine;
+
 mod vm;
-use vm::VMInstance;
+use vm::{VMInstance, VMError};
+
+mod replay;
+use replay::{InputEvent, FrameRecord, RecordingHeader, Recorder};
+
+mod snapshot;
+use snapshot::{Snapshot};
+
+mod capture;
+use capture::{CaptureTarget};
+
+mod export;
+use export::{FrameExport};
+
+mod overlay;
+use overlay::{Overlay, FrameStats};
+mod debuglines;
+use debuglines::{DebugLines};
+mod meshrender;
+use meshrender::{MeshRenderer};
+
+use gpuparticles::{GpuParticles};
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 const DIMS: Extent2D = Extent2D { width: 1024,height: 768 };
 
 const ENTRY_NAME: &str = "main";
 
-#[derive(Debug, Clone, Copy)]
-#[allow(non_snake_case)]
-struct Vertex {
-    a_Pos: [f32; 4],
-    a_Uv: [f32; 2],
-    a_Color: u32,
-}
+// Capacity of the per-frame vertex buffers, six per particle or ribbon segment.
+const MAX_VERTICES : usize = 6 * 1024 * 1024;
+
+const WINDOW_TITLE: &str = "quad";
+
+// Vertical field of view, in radians.
+const FIELD_OF_VIEW : f32 = std::f32::consts::FRAC_PI_2 * 0.8;
 
 const COLOR_RANGE: i::SubresourceRange = i::SubresourceRange {
     aspects: f::Aspects::COLOR,
@@ -76,6 +125,79 @@ const COLOR_RANGE: i::SubresourceRange = i::SubresourceRange {
     layers: 0 .. 1,
 };
 
+// Script load and build errors also go to the window title, the console is easy to miss while editing.
+fn script_error(result : &Result<(), VMError>) -> Option<String> {
+    match result {
+        Ok(()) => None,
+        Err(error) => {
+            println!("{}", error);
+            Some(error.to_string().lines().next().unwrap_or("").to_string())
+        }
+    }
+}
+
+fn create_world() -> WorldState {
+    WorldState::new(vec3(0.0, 0.0, -10.0), vec3(0.0, 0.0, 0.0), vec3(0., 1., 0.))
+}
+
+fn create_vm_instance() -> VMInstance {
+    // --engine=<name> picks the wasm backend, e.g. the wasmi interpreter where the JIT isn't available.
+    let engine_name = std::env::args()
+        .find(|arg| arg.starts_with("--engine="))
+        .map_or(engine::DEFAULT_ENGINE.to_string(), |arg| arg["--engine=".len()..].to_string());
+    let script_engine = engine::create_engine(&engine_name)
+        .unwrap_or_else(|| panic!("Unknown script engine `{}`, available: {:?}", engine_name, engine::engine_names()));
+    println!("Script engine: {}", script_engine.name());
+
+    // Compiled scripts are cached in ./cache unless --no-cache is given.
+    let script_cache = if std::env::args().any(|arg| arg == "--no-cache") { None } else { Some(scriptcache::ScriptCache::new("cache")) };
+
+    let mut vm_instance = VMInstance::new(script_engine, script_cache);
+    if let Some(arg) = std::env::args().find(|arg| arg.starts_with("--script=")) {
+        vm_instance.set_source_file(&arg["--script=".len()..]);
+    }
+    vm_instance
+}
+
+fn view_projection(world : &WorldState, aspect_ratio : f32) -> glm::Mat4 {
+    let proj = glm::perspective(aspect_ratio, FIELD_OF_VIEW, 1.0 / 16.0, 1024.);
+    let lookat = glm::look_at(&world.camera_position, &world.camera_lookat, &world.camera_up);
+    proj * lookat
+}
+
+fn megabytes(bytes : u64) -> f32 {
+    bytes as f32 / (1024.0 * 1024.0)
+}
+
+// What the F3 overlay shows. `quads` is what the frame's vertex buffer holds, streaks and ribbon
+// segments included.
+fn overlay_text(stats : &FrameStats, world : &WorldState, vm : &VMInstance, quads : u32, culling : &CullStats, memory : &AllocatorStats) -> String {
+    let fps = if stats.frame_ms > 0.0 { 1000.0 / stats.frame_ms } else { 0.0 };
+    let host = if world.particle_system.host_integration() {
+        format!("{} / {}", world.particle_system.particles().len(), particles::MAX_PARTICLES)
+    } else {
+        "on the GPU".to_string()
+    };
+
+    format!("frame   {:6.2} ms {:4.0} fps\nscript  {:6.2} ms\nupload  {:6.2} ms\nquads   {} / {}\nhost    {}\nculled  {} off screen, {} tiny\nmerged  {} into {}\nmeshes  {} tris\nvm mem  {} KB\ngpu mem {:.1} / {:.1} MB, {} allocations in {} blocks ({} dedicated)",
+        stats.frame_ms, fps, stats.script_ms, stats.upload_ms, quads, MAX_VERTICES / 6, host, culling.culled, culling.dropped,
+        culling.merged, culling.merged_into, world.meshes.triangle_count(), vm.memory_size() / 1024,
+        megabytes(memory.used), megabytes(memory.reserved), memory.allocations, memory.blocks, memory.dedicated)
+}
+
+// "quad [paused] - <script error>"
+fn window_title(world : &WorldState, script_error : &Option<String>) -> String {
+    let mut title = WINDOW_TITLE.to_string();
+    let status = world.time_status();
+    if !status.is_empty() {
+        title += &format!(" [{}]", status);
+    }
+    if let Some(error) = script_error {
+        title += &format!(" - {}", error);
+    }
+    title
+}
+
 #[cfg(any(
     feature = "vulkan",
     feature = "dx11",
@@ -85,16 +207,49 @@ const COLOR_RANGE: i::SubresourceRange = i::SubresourceRange {
 fn main() {
     env_logger::init();
 
+    // --replay=<file> checks a recording made with --record against the script, without a window.
+    if let Some(arg) = std::env::args().find(|arg| arg.starts_with("--replay=")) {
+        let mut world = create_world();
+        let mut vm_instance = create_vm_instance();
+        match replay::replay(&arg["--replay=".len()..], &mut vm_instance, &mut world) {
+            Ok(frames) => println!("Replayed {} frames, all matched", frames),
+            Err(error) => {
+                println!("Replay failed: {}", error);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // --screenshot=<file.png> renders into an offscreen target without showing the window, saves the
+    // first frame at or after --screenshot-time=<seconds> of simulation time and exits.
+    let screenshot_path = std::env::args()
+        .find(|arg| arg.starts_with("--screenshot="))
+        .map(|arg| arg["--screenshot=".len()..].to_string());
+    let screenshot_time = std::env::args()
+        .find(|arg| arg.starts_with("--screenshot-time="))
+        .and_then(|arg| arg["--screenshot-time=".len()..].parse::<f32>().ok())
+        .unwrap_or(0.0);
+
+    // --export-frames=<n> renders n frames offscreen, each advancing the simulation by 1/--export-fps=<fps>
+    // seconds (60 by default), to --export-dir=<dir> as PNGs and/or to --export-encoder=<command>, then exits.
+    let export_frames = std::env::args()
+        .find(|arg| arg.starts_with("--export-frames="))
+        .and_then(|arg| arg["--export-frames=".len()..].parse::<u32>().ok());
+
+    let offscreen = screenshot_path.is_some() || export_frames.is_some();
+
     let mut events_loop = winit::EventsLoop::new();
     let wb = winit::WindowBuilder::new()
+        .with_visibility(!offscreen)
         .with_min_dimensions(winit::dpi::LogicalSize::new(1.0, 1.0))
         .with_dimensions(winit::dpi::LogicalSize::new(
             DIMS.width as _,
             DIMS.height as _,
         ))
-        .with_title("quad".to_string());
+        .with_title(WINDOW_TITLE.to_string());
     // instantiate backend
-    let (_window, _instance, mut adapters, mut surface) = {
+    let (window, _instance, mut adapters, mut surface) = {
         let window = wb.build(&events_loop).unwrap();
         let instance = back::Instance::create("gfx-rs quad", 1);
         let surface = instance.create_surface(&window);
@@ -110,9 +265,11 @@ fn main() {
     let memory_types = adapter.physical_device.memory_properties().memory_types;
     let limits = adapter.physical_device.limits();
 
-    // Build a new device and associated command queues
+    // Build a new device and associated command queues. One General queue on purpose: the particle
+    // compute pass goes in the frame's command buffer ahead of the draws, so it needs no semaphores or
+    // buffer ownership transfers between queue families.
     let (device, mut queue_group) = adapter
-        .open_with::<_, hal::Graphics>(1, |family| surface.supports_queue_family(family))
+        .open_with::<_, hal::General>(1, |family| surface.supports_queue_family(family))
         .unwrap();
 
     let mut command_pool = unsafe {
@@ -120,6 +277,9 @@ fn main() {
     }
     .expect("Can't create command pool");
 
+    // Every buffer and the capture image get their memory from here.
+    let mut allocator = GpuAllocator::new(&adapter.physical_device.memory_properties(), &limits);
+
     // Setup renderpass and pipeline
     let set_layout = unsafe {
         device.create_descriptor_set_layout(
@@ -165,18 +325,16 @@ fn main() {
     // Buffer allocations
     println!("Memory types: {:?}", memory_types);
 
-    const MAX_VERTICES : usize = 6 * 1024 * 1024;
-
     let vbuffer_stride = std::mem::size_of::<Vertex>() as u64;
     let vbuffer_len = MAX_VERTICES as u64 * vbuffer_stride;
 
     assert_ne!(vbuffer_len, 0);
 
     for i in 0..FRAMES_IN_FLIGHT {
-        let vbuffer = UploadBuffer::new(&device, &adapter.physical_device.memory_properties(), vbuffer_len, buffer::Usage::VERTEX);
+        let vbuffer = UploadBuffer::new(&device, &mut allocator, vbuffer_len, buffer::Usage::VERTEX, MemoryUsage::Upload);
         frames[i].vbuffer = vbuffer.ok();
 
-        let ubuffer = UploadBuffer::new(&device, &adapter.physical_device.memory_properties(), 64, buffer::Usage::UNIFORM);
+        let ubuffer = UploadBuffer::new(&device, &mut allocator, 64, buffer::Usage::UNIFORM, MemoryUsage::Upload);
         frames[i].ubuffer = ubuffer.ok();
     }
 
@@ -211,37 +369,7 @@ fn main() {
         unsafe { device.create_swapchain(&mut surface, swap_config, None) }
             .expect("Can't create swapchain");
 
-    let render_pass = {
-        let attachment = pass::Attachment {
-            format: Some(format),
-            samples: 1,
-            ops: pass::AttachmentOps::new(
-                pass::AttachmentLoadOp::Clear,
-                pass::AttachmentStoreOp::Store,
-            ),
-            stencil_ops: pass::AttachmentOps::DONT_CARE,
-            layouts: i::Layout::Undefined .. i::Layout::Present,
-        };
-
-        let subpass = pass::SubpassDesc {
-            colors: &[(0, i::Layout::ColorAttachmentOptimal)],
-            depth_stencil: None,
-            inputs: &[],
-            resolves: &[],
-            preserves: &[],
-        };
-
-        let dependency = pass::SubpassDependency {
-            passes: pass::SubpassRef::External .. pass::SubpassRef::Pass(0),
-            stages: PipelineStage::COLOR_ATTACHMENT_OUTPUT
-                .. PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-            accesses: i::Access::empty()
-                .. (i::Access::COLOR_ATTACHMENT_READ | i::Access::COLOR_ATTACHMENT_WRITE),
-        };
-
-        unsafe { device.create_render_pass(&[attachment], &[subpass], &[dependency]) }
-            .expect("Can't create render pass")
-    };
+    let render_pass = capture::create_render_pass(&device, format, i::Layout::Present);
 
     let (mut frame_images, mut framebuffers) = {
         let pairs = backbuffer
@@ -446,10 +574,140 @@ fn main() {
         depth: 0.0 .. 1.0,
     };
 
+    // --gpu-particles integrates the host particles in a compute pass instead of on the CPU.
+    let mut gpu_particles = if std::env::args().any(|arg| arg == "--gpu-particles") {
+        Some(GpuParticles::new(&device, &mut allocator, &render_pass, &set_layout, FRAMES_IN_FLIGHT))
+    } else {
+        None
+    };
+
+    // F3 or --overlay shows frame stats.
+    let mut overlay = Overlay::new(&device, &mut allocator, &render_pass, FRAMES_IN_FLIGHT);
+    let mut show_overlay = std::env::args().any(|arg| arg == "--overlay");
+    let mut frame_stats = FrameStats::new();
+
+    // Threads that write the particle quads into the vertex buffer.
+    let vertex_threads = billboard::worker_threads();
+
+    // Triangles from the script's add_triangles and add_mesh.
+    let mut mesh_renderer = MeshRenderer::new(&device, &mut allocator, &render_pass, &set_layout, FRAMES_IN_FLIGHT);
+
+    // Lines from the script's draw_line, draw_box and draw_sphere_wire; its texts go through the overlay.
+    let mut debug_lines = DebugLines::new(&device, &mut allocator, &render_pass, &set_layout, FRAMES_IN_FLIGHT);
+
+    // The windowless target. F12 screenshots create one at the window's size when needed.
+    let mut capture_target = if offscreen {
+        Some(CaptureTarget::new(&device, &mut allocator, &limits, format, extent))
+    } else {
+        None
+    };
+
+    let mut frame_export = export_frames.map(|frame_count| {
+        let fps = std::env::args()
+            .find(|arg| arg.starts_with("--export-fps="))
+            .and_then(|arg| arg["--export-fps=".len()..].parse::<f32>().ok())
+            .filter(|fps| *fps > 0.0)
+            .unwrap_or(60.0);
+        let encoder = std::env::args()
+            .find(|arg| arg.starts_with("--export-encoder="))
+            .map(|arg| arg["--export-encoder=".len()..].to_string());
+        // PNGs go to ./export unless only an encoder was asked for.
+        let directory = std::env::args()
+            .find(|arg| arg.starts_with("--export-dir="))
+            .map(|arg| arg["--export-dir=".len()..].to_string())
+            .or_else(|| if encoder.is_none() { Some("export".to_string()) } else { None });
+
+        match FrameExport::new(directory.as_ref().map(|d| d.as_str()), encoder.as_ref().map(|e| e.as_str()), frame_count, fps, extent.width, extent.height) {
+            Ok(frame_export) => {
+                println!("Exporting {} frames of {}x{} at {} fps", frame_count, extent.width, extent.height, fps);
+                frame_export
+            }
+            Err(error) => {
+                println!("Can't start export: {}", error);
+                std::process::exit(1);
+            }
+        }
+    });
+
     //
-    let mut world = WorldState::new(vec3(0.0, 0.0, -10.0), vec3(0.0, 0.0, 0.0), vec3(0., 1., 0.));
-    let mut vm_instance = VMInstance::new();
-    vm_instance.load_script();
+    let mut world = create_world();
+
+    // --seed=<n> fixes what rand() returns, otherwise every run differs. Exports default to seed 0 so
+    // that they are reproducible.
+    let seed = std::env::args()
+        .find(|arg| arg.starts_with("--seed="))
+        .and_then(|arg| arg["--seed=".len()..].parse::<u64>().ok())
+        .unwrap_or_else(|| if frame_export.is_some() { 0 } else { rand::random() });
+    world.reseed(seed);
+
+    if gpu_particles.is_some() {
+        world.particle_system.set_host_integration(false);
+    }
+
+    // --time-scale=<x> starts at another speed, --paused starts paused.
+    if let Some(arg) = std::env::args().find(|arg| arg.starts_with("--time-scale=")) {
+        match arg["--time-scale=".len()..].parse::<f32>() {
+            Ok(scale) => world.set_time_scale(scale),
+            _ => println!("Ignoring invalid {}", arg),
+        }
+    }
+    if std::env::args().any(|arg| arg == "--paused") {
+        world.set_paused(true);
+    }
+
+    // --tick-rate=<hz> sets how many fixed steps the script runs per second.
+    if let Some(arg) = std::env::args().find(|arg| arg.starts_with("--tick-rate=")) {
+        match arg["--tick-rate=".len()..].parse::<f32>() {
+            Ok(rate) if rate > 0.0 => world.set_tick_rate(rate),
+            _ => println!("Ignoring invalid {}", arg),
+        }
+    }
+
+    let mut vm_instance = create_vm_instance();
+
+    // --load-snapshot=<file> shows a snapshot saved with F5 instead of running a script.
+    let mut last_script_error = match std::env::args().find(|arg| arg.starts_with("--load-snapshot=")) {
+        Some(arg) => {
+            let path = &arg["--load-snapshot=".len()..];
+            match Snapshot::load(path) {
+                Ok(snapshot) => {
+                    snapshot.restore(&mut world);
+                    // Ticking would clear the script particles.
+                    world.set_paused(true);
+                    None
+                }
+                Err(error) => Some(format!("{}: {}", path, error)),
+            }
+        }
+        None => script_error(&vm_instance.load_script(&mut world)),
+    };
+    let mut title = WINDOW_TITLE.to_string();
+
+    // --record=<file> saves the session for --replay.
+    let mut recorder = std::env::args().find(|arg| arg.starts_with("--record=")).and_then(|arg| {
+        let path = &arg["--record=".len()..];
+        let header = RecordingHeader {
+            module_hash : vm_instance.module_hash().unwrap_or("").to_string(),
+            engine : vm_instance.engine_name().to_string(),
+            seed : world.seed(),
+            tick_length : world.tick_length,
+            time_scale : world.time_scale(),
+            paused : world.paused(),
+            host_integration : world.particle_system.host_integration(),
+        };
+        match Recorder::create(path, &header) {
+            Ok(recorder) => {
+                println!("Recording to {} with seed {}", path, seed);
+                Some(recorder)
+            }
+            Err(error) => {
+                println!("Can't record to {}: {}", path, error);
+                None
+            }
+        }
+    });
+    // Applied at the start of the next simulated frame, and recorded with it.
+    let mut input_events : Vec<InputEvent> = Vec::new();
 
     //
     let mut running = true;
@@ -459,7 +717,15 @@ fn main() {
         height: 0,
     };
     let now = Instant::now();
+    let mut last_frame_start = now;
     let mut frame: u64 = 0;
+    // F9 compares the next GPU particle step against the CPU integrator.
+    let mut check_gpu_particles = false;
+    // F5 saves the world as snapshot-<frame>.json and .bin.
+    let mut save_snapshot = false;
+    // F12 saves the next frame as screenshot-<frame>.png.
+    let mut take_screenshot = false;
+    let mut export_failed = false;
     while running {
         running = true;
         events_loop.poll_events(|event| {
@@ -482,7 +748,66 @@ fn main() {
                                 ..
                             },
                         ..
-                    } => { vm_instance.load_script(); () },
+                    } => vm_instance.reload_script_async(),
+                    winit::WindowEvent::KeyboardInput {
+                        input:
+                            winit::KeyboardInput {
+                                virtual_keycode: Some(winit::VirtualKeyCode::F9),
+                                state: winit::ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => check_gpu_particles = true,
+                    winit::WindowEvent::KeyboardInput {
+                        input:
+                            winit::KeyboardInput {
+                                virtual_keycode: Some(winit::VirtualKeyCode::F5),
+                                state: winit::ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => save_snapshot = true,
+                    winit::WindowEvent::KeyboardInput {
+                        input:
+                            winit::KeyboardInput {
+                                virtual_keycode: Some(winit::VirtualKeyCode::F12),
+                                state: winit::ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => take_screenshot = true,
+                    winit::WindowEvent::KeyboardInput {
+                        input:
+                            winit::KeyboardInput {
+                                virtual_keycode: Some(winit::VirtualKeyCode::F3),
+                                state: winit::ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => show_overlay = !show_overlay,
+                    // P pauses, period runs a single tick, brackets slow down and speed up.
+                    winit::WindowEvent::KeyboardInput {
+                        input:
+                            winit::KeyboardInput {
+                                virtual_keycode: Some(key),
+                                state: winit::ElementState::Pressed,
+                                ..
+                            },
+                        ..
+                    } => match key {
+                        winit::VirtualKeyCode::P => {
+                            // Toggling twice within one frame ends up where it started.
+                            let paused = input_events.iter().fold(world.paused(), |paused, event| match event {
+                                InputEvent::SetPaused(value) => *value,
+                                _ => paused,
+                            });
+                            input_events.push(InputEvent::SetPaused(!paused));
+                        }
+                        winit::VirtualKeyCode::Period => input_events.push(InputEvent::Step),
+                        winit::VirtualKeyCode::LBracket => input_events.push(InputEvent::ChangeTimeScale { faster : false }),
+                        winit::VirtualKeyCode::RBracket => input_events.push(InputEvent::ChangeTimeScale { faster : true }),
+                        _ => (),
+                    },
                     winit::WindowEvent::Resized(dims) => {
                         println!("resized to {:?}", dims);
                         recreate_swapchain = true;
@@ -556,25 +881,32 @@ fn main() {
             viewport.rect.w = extent.width as _;
             viewport.rect.h = extent.height as _;
             recreate_swapchain = false;
+
+            input_events.push(InputEvent::Resize { width : extent.width, height : extent.height });
         }
 
         // Use guaranteed unused acquire semaphore to get the index of the next frame we will render to
-        // by using acquire_image
-        let swap_image = unsafe {
-            match swap_chain.acquire_image(!0, Some(&free_acquire_semaphore), None) {
-                Ok((i, _)) => i as usize,
-                Err(_) => {
-                    recreate_swapchain = true;
-                    continue;
+        // by using acquire_image. Nothing is presented when rendering offscreen.
+        let swap_image = if offscreen {
+            None
+        } else {
+            let swap_image = unsafe {
+                match swap_chain.acquire_image(!0, Some(&free_acquire_semaphore), None) {
+                    Ok((i, _)) => i as usize,
+                    Err(_) => {
+                        recreate_swapchain = true;
+                        continue;
+                    }
                 }
-            }
-        };
+            };
 
-        // Swap the acquire semaphore with the one previously associated with the image we are acquiring
-        core::mem::swap(
-            &mut free_acquire_semaphore,
-            &mut image_acquire_semaphores[swap_image],
-        );
+            // Swap the acquire semaphore with the one previously associated with the image we are acquiring
+            core::mem::swap(
+                &mut free_acquire_semaphore,
+                &mut image_acquire_semaphores[swap_image],
+            );
+            Some(swap_image)
+        };
 
         // Compute index into our resource ring buffers based on the frame number
         // and number of frames in flight. Pay close attention to where this index is needed
@@ -584,68 +916,121 @@ fn main() {
         let elapsed_sec = now.elapsed().as_micros() as f32 / 1000000.;
         let t = elapsed_sec;
 
-        world.tick(&mut vm_instance, t);
-
-        fn update_current_frame(device : &BackendDevice, frame : &mut Frame, time : f32, world : &WorldState, aspect_ratio : f32) {
-            let proj = glm::perspective(aspect_ratio, glm::half_pi::<f32>() * 0.8, 1.0 / 16.0, 1024.);
-            let lookat = glm::look_at(&world.camera_position, &world.camera_lookat, &world.camera_up);
-            let view_proj =  proj * lookat;
-
-            let uniform_mvp: [[f32; 4]; 4] = view_proj.into();
-
-            unsafe {
-                let mut constants = device
-                    .acquire_mapping_writer::<[[f32; 4]; 4]>(&frame.ubuffer.as_ref().unwrap().device_memory, 0 .. 64)
-                    .unwrap();
+        let frame_start = Instant::now();
+        let frame_time = frame_start - last_frame_start;
+        last_frame_start = frame_start;
 
-                constants[0] = uniform_mvp;
-                    
-                device.release_mapping_writer(constants).unwrap();
+        // A script compiled in the background gets swapped in here, between two ticks.
+        vm_instance.reload_if_changed();
+        if let Some(result) = vm_instance.poll_reload(&mut world) {
+            if result.is_ok() {
+                input_events.push(InputEvent::ScriptReloaded);
             }
+            last_script_error = script_error(&result);
+        }
 
-            let mut vertices_list = Vec::<Vertex>::new();
-            vertices_list.reserve_exact(world.particles_list.len() * 6);
+        let new_title = window_title(&world, &last_script_error);
+        if new_title != title {
+            window.set_title(&new_title);
+            title = new_title;
+        }
 
-            fn add_particle(vec : &mut Vec::<Vertex>, world_position : glm::Vec3, size : f32, color : u32, camera_position : glm::Vec3, up : glm::Vec3) {
-                let n = camera_position - world_position;
-                let n = glm::normalize(&n);
+        for event in &input_events {
+            event.apply(&mut world, &mut vm_instance);
+        }
+        let frame_length = match frame_export.as_ref() {
+            Some(active) => {
+                let length = active.frame_length();
+                world.advance_by(&mut vm_instance, length);
+                length
+            }
+            None => world.advance(&mut vm_instance, t),
+        };
 
-                let r = n.cross(&up);
-                let u = r.cross(&n);
+        if let Some(active) = recorder.as_mut() {
+            let record = FrameRecord { elapsed : frame_length, dt : world.frame_dt, checksum : world.checksum(), events : input_events.clone() };
+            if let Err(error) = active.record_frame(&record) {
+                println!("Recording stopped: {}", error);
+                recorder = None;
+            }
+        }
+        input_events.clear();
+
+        if save_snapshot {
+            let snapshot = Snapshot::capture(&world);
+            for path in [format!("snapshot-{}.json", frame), format!("snapshot-{}.bin", frame)].iter() {
+                match snapshot.save(path) {
+                    Ok(()) => println!("Saved {}", path),
+                    Err(error) => println!("Can't save {}: {}", path, error),
+                }
+            }
+            save_snapshot = false;
+        }
 
-                let v0 = world_position - r * size + u * size;
-                let v1 = world_position + r * size + u * size;
-                let v2 = world_position + r * size - u * size;
-                let v3 = world_position - r * size - u * size;
+        // Returns what culling and LOD left out.
+        fn update_current_frame(device : &BackendDevice, frame : &mut Frame, time : f32, world : &WorldState, width : f32, height : f32,
+                                threads : usize) -> CullStats {
+            let view_proj = view_projection(world, width / height);
+            let uniform_mvp: [[f32; 4]; 4] = view_proj.into();
 
-                vec.push(Vertex { a_Pos: [ v0.x, v0.y, v0.z , 1.0 ], a_Uv: [0.0, 1.0], a_Color: color });
-                vec.push(Vertex { a_Pos: [ v1.x, v1.y, v1.z , 1.0 ], a_Uv: [1.0, 1.0], a_Color: color });
-                vec.push(Vertex { a_Pos: [ v2.x, v2.y, v2.z , 1.0 ], a_Uv: [1.0, 0.0], a_Color: color });
+            frame.ubuffer.as_mut().unwrap().write(device, &[uniform_mvp]);
 
-                vec.push(Vertex { a_Pos: [ v0.x, v0.y, v0.z , 1.0 ], a_Uv: [0.0, 1.0], a_Color: color });
-                vec.push(Vertex { a_Pos: [ v2.x, v2.y, v2.z , 1.0 ], a_Uv: [1.0, 0.0], a_Color: color });
-                vec.push(Vertex { a_Pos: [ v3.x, v3.y, v3.z , 1.0 ], a_Uv: [0.0, 0.0], a_Color: color });
-            }
+            // Culling and merging decide what to draw, then the quads are written straight into the mapped
+            // buffer by worker threads.
+            let mut sprites = Vec::with_capacity(world.particle_count());
 
+            let (camera_position, up) = (world.camera_position, world.camera_up);
+            let view = ViewVolume::new(&view_proj, FIELD_OF_VIEW, width, height);
+            let mut lod = ParticleLod::new(&view);
             for p in &world.particles_list {
-                add_particle(&mut vertices_list, p.position, p.size, p.color, world.camera_position, world.camera_up);
+                if let Some(particle) = lod.add(LodParticle { position : p.position, size : p.size, color : p.color }) {
+                    sprites.push(Sprite::Billboard { position : particle.position, size : particle.size, color : particle.color });
+                }
             }
-
-            unsafe {
-                let stride = std::mem::size_of::<Vertex>() as u64;
-                let mut vertices = device
-                    .acquire_mapping_writer::<Vertex>(&frame.vbuffer.as_ref().unwrap().device_memory, 0 .. stride * vertices_list.len() as u64) // todo: wrong bounds, whatevs
-                    .unwrap();
-
-                for (i, v) in vertices_list.iter().enumerate() {
-                    vertices[i] = *v;
+            // With --gpu-particles the compute pass draws these, always as billboards.
+            if world.particle_system.host_integration() {
+                for (index, p) in world.particle_system.particles().iter().enumerate() {
+                    let position = world.particle_system.interpolated_position(index, world.alpha);
+                    let trail = world.particle_system.trail(index);
+                    match trail.mode {
+                        RenderMode::Billboard => {
+                            if let Some(particle) = lod.add(LodParticle { position : position, size : p.size, color : p.color }) {
+                                sprites.push(Sprite::Billboard { position : particle.position, size : particle.size, color : particle.color });
+                            }
+                        }
+                        RenderMode::Stretched => {
+                            let streak = p.velocity * trail.length;
+                            if lod.visible(&(position - streak * 0.5), glm::length(&streak) * 0.5 + p.size) {
+                                sprites.push(Sprite::Stretched { position : position, velocity : p.velocity, length : trail.length, size : p.size, color : p.color });
+                            }
+                        }
+                        RenderMode::Ribbon => {
+                            let extent = trail.history.iter().map(|point| glm::distance(point, &position)).fold(0.0, f32::max);
+                            if lod.visible(&position, extent + p.size) {
+                                sprites.push(Sprite::Ribbon { history : &trail.history, position : position, size : p.size, color : p.color });
+                            }
+                        }
+                    }
                 }
-                    
-                device.release_mapping_writer(vertices).unwrap();
             }
+            let (merged, cull_stats) = lod.finish();
+            for particle in merged {
+                sprites.push(Sprite::Billboard { position : particle.position, size : particle.size, color : particle.color });
+            }
+
+            let vertex_count = sprites.iter().map(Sprite::vertex_count).sum::<usize>().min(MAX_VERTICES);
+            let written = frame.vbuffer.as_mut().unwrap().write_with(device, vertex_count, |vertices : &mut [Vertex]| {
+                billboard::write_sprites(&sprites, vertices, camera_position, up, threads)
+            });
+            frame.vertex_count = written as u32;
+
+            cull_stats
         }
 
-        update_current_frame(&device, &mut frames[frame_idx], t, &world, viewport.rect.w as f32 / viewport.rect.h as f32);
+        let upload_start = Instant::now();
+        let cull_stats = update_current_frame(&device, &mut frames[frame_idx], t, &world, viewport.rect.w as f32, viewport.rect.h as f32,
+                                              vertex_threads);
+        frame_stats.add(frame_time, world.script_time, upload_start.elapsed());
 
         // Wait for the fence of the previous submission of this frame and reset it; ensures we are
         // submitting only up to maximum number of FRAMES_IN_FLIGHT if we are submitting faster than
@@ -662,48 +1047,156 @@ fn main() {
             cmd_pools[frame_idx].reset(false);
         }
 
+        // The frame's spawn buffer is free now its previous submission has completed.
+        if let Some(gpu_particles) = gpu_particles.as_mut() {
+            let clear = world.particle_system.take_cleared();
+            gpu_particles.write_spawns(&device, frame_idx, world.particle_system.particles(), clear);
+            world.particle_system.hand_over();
+        }
+
+        mesh_renderer.write_meshes(&device, frame_idx, &world.meshes);
+        debug_lines.write_lines(&device, frame_idx, &world.debug.lines);
+        {
+            let (width, height) = (viewport.rect.w as f32, viewport.rect.h as f32);
+            let view_proj = view_projection(&world, width / height);
+            let labels : Vec<(f32, f32, &str, u32)> = world.debug.texts.iter()
+                .filter_map(|text| {
+                    let (x, y) = match text.anchor {
+                        TextAnchor::Screen(x, y) => (x, y),
+                        TextAnchor::World(position) => debugdraw::screen_position(&view_proj, &position, width, height)?,
+                    };
+                    Some((x, y, text.text.as_str(), text.color))
+                })
+                .collect();
+            let panel = if show_overlay { Some(overlay_text(&frame_stats, &world, &vm_instance, frames[frame_idx].vertex_count / 6, &cull_stats, &allocator.stats())) } else { None };
+            overlay.write(&device, frame_idx, panel.as_ref().map(|text| text.as_str()), &labels, width, height);
+        }
+
+        // The simulation pass copies the particles out before and after its step.
+        let checking_gpu_particles = match gpu_particles.as_mut() {
+            Some(gpu_particles) if check_gpu_particles => {
+                gpu_particles.request_check(&device, &mut allocator);
+                true
+            }
+            _ => false,
+        };
+
+        // Exports capture every frame, --screenshot the first one at --screenshot-time.
+        let capture_frame = take_screenshot
+            || frame_export.is_some()
+            || (screenshot_path.is_some() && world.time >= screenshot_time);
+        if take_screenshot && capture_target.is_none() {
+            let window_extent = i::Extent { width: viewport.rect.w as u32, height: viewport.rect.h as u32, depth: 1 };
+            capture_target = Some(CaptureTarget::new(&device, &mut allocator, &limits, format, window_extent));
+        }
+
+        // Draws the frame into a render pass compatible with `render_pass`, the overlay last.
+        unsafe fn record_scene(encoder : &mut command::RenderPassInlineEncoder<back::Backend>, pipeline : &<back::Backend as hal::Backend>::GraphicsPipeline,
+            pipeline_layout : &<back::Backend as hal::Backend>::PipelineLayout, frame : &Frame, frame_idx : usize, world : &WorldState,
+            gpu_particles : Option<&GpuParticles>, mesh_renderer : &MeshRenderer, debug_lines : &DebugLines, overlay : &Overlay) {
+            mesh_renderer.record_draw(encoder, frame_idx, frame.desc_set.as_ref().unwrap());
+
+            encoder.bind_graphics_pipeline(pipeline);
+            encoder.bind_vertex_buffers(0, Some((&frame.vbuffer.as_ref().unwrap().device_buffer, 0)));
+            encoder.bind_graphics_descriptor_sets(pipeline_layout, 0, frame.desc_set.as_ref(), &[]);
+            encoder.draw(0 .. frame.vertex_count, 0 .. 1);
+
+            if let Some(gpu_particles) = gpu_particles {
+                let camera_position = [world.camera_position.x, world.camera_position.y, world.camera_position.z];
+                let camera_up = [world.camera_up.x, world.camera_up.y, world.camera_up.z];
+                gpu_particles.record_draw(encoder, frame.desc_set.as_ref().unwrap(), camera_position, camera_up);
+            }
+
+            debug_lines.record_draw(encoder, frame_idx, frame.desc_set.as_ref().unwrap());
+            overlay.record_draw(encoder, frame_idx);
+        }
+
         // Rendering
+        let clear_values = [command::ClearValue::Color(command::ClearColor::Sfloat([0.0, 0.0, 0.0, 0.0]))];
         let cmd_buffer = &mut cmd_buffers[frame_idx];
         unsafe {
             cmd_buffer.begin(false);
 
+            if let Some(gpu_particles) = gpu_particles.as_mut() {
+                gpu_particles.record_simulation(cmd_buffer, frame_idx, world.frame_dt);
+            }
+
             cmd_buffer.set_viewports(0, &[viewport.clone()]);
             cmd_buffer.set_scissors(0, &[viewport.rect]);
-            cmd_buffer.bind_graphics_pipeline(&pipeline);
-            cmd_buffer.bind_vertex_buffers(0, Some((&frames[frame_idx].vbuffer.as_ref().unwrap().device_buffer, 0)));
-            cmd_buffer.bind_graphics_descriptor_sets(&pipeline_layout, 0, frames[frame_idx].desc_set.as_ref(), &[]);
-
-            {
-                let mut encoder = cmd_buffer.begin_render_pass_inline(
-                    &render_pass,
-                    &framebuffers[swap_image],
-                    viewport.rect,
-                    &[command::ClearValue::Color(command::ClearColor::Sfloat([
-                        0.0, 0.0, 0.0, 0.0,
-                    ]))],
-                );
-                encoder.draw(0 .. world.particles_list.len() as u32 * 6, 0 .. 1);
+
+            if let Some(swap_image) = swap_image {
+                let mut encoder = cmd_buffer.begin_render_pass_inline(&render_pass, &framebuffers[swap_image], viewport.rect, &clear_values);
+                record_scene(&mut encoder, &pipeline, &pipeline_layout, &frames[frame_idx], frame_idx, &world, gpu_particles.as_ref(), &mesh_renderer, &debug_lines, &overlay);
+            }
+
+            // Offscreen the capture target stands in for the window, otherwise it is only drawn for a screenshot.
+            if let Some(target) = capture_target.as_ref().filter(|_| offscreen || capture_frame) {
+                {
+                    let mut encoder = cmd_buffer.begin_render_pass_inline(&target.render_pass, &target.framebuffer, viewport.rect, &clear_values);
+                    record_scene(&mut encoder, &pipeline, &pipeline_layout, &frames[frame_idx], frame_idx, &world, gpu_particles.as_ref(), &mesh_renderer, &debug_lines, &overlay);
+                }
+                if capture_frame {
+                    target.record_readback(cmd_buffer);
+                }
             }
 
             cmd_buffer.finish();
 
             let submission = Submission {
                 command_buffers: Some(&*cmd_buffer),
-                wait_semaphores: Some((
-                    &image_acquire_semaphores[swap_image],
-                    PipelineStage::COLOR_ATTACHMENT_OUTPUT,
-                )),
-                signal_semaphores: Some(&submission_complete_semaphores[frame_idx]),
+                wait_semaphores: swap_image.map(|swap_image| (&image_acquire_semaphores[swap_image], PipelineStage::COLOR_ATTACHMENT_OUTPUT)),
+                signal_semaphores: swap_image.map(|_| &submission_complete_semaphores[frame_idx]),
             };
             queue_group.queues[0].submit(submission, Some(&submission_complete_fences[frame_idx]));
 
+            if capture_frame {
+                device
+                    .wait_for_fence(&submission_complete_fences[frame_idx], !0)
+                    .expect("Failed to wait for fence");
+
+                let target = capture_target.as_ref().unwrap();
+                if let Some(active) = frame_export.as_mut() {
+                    let pixels = target.read_pixels(&device);
+                    if let Err(error) = active.write_frame(target.extent.width, target.extent.height, &pixels) {
+                        println!("Export failed: {}", error);
+                        export_failed = true;
+                    }
+                    if export_failed || active.finished() {
+                        running = false;
+                    }
+                } else {
+                    let path = screenshot_path.clone().unwrap_or_else(|| format!("screenshot-{}.png", frame));
+                    match target.save_png(&device, &path) {
+                        Ok(()) => println!("Saved {} ({}x{})", path, target.extent.width, target.extent.height),
+                        Err(error) => println!("Can't save {}: {}", path, error),
+                    }
+
+                    if offscreen {
+                        running = false;
+                    } else {
+                        capture_target.take().unwrap().destroy(&device, &mut allocator);
+                    }
+                }
+                take_screenshot = false;
+            }
+
+            if let Some(gpu_particles) = gpu_particles.as_ref().filter(|_| checking_gpu_particles) {
+                device.wait_idle().unwrap();
+                let (before, after) = gpu_particles.read_check(&device);
+                let (compared, max_error) = gpu_particles.check_step(&before, &after, world.frame_dt);
+                println!("GPU particles: {} particles checked against the CPU integrator, max position error {}", compared, max_error);
+            }
+            check_gpu_particles = false;
+
             // present frame
-            if let Err(_) = swap_chain.present(
-                &mut queue_group.queues[0],
-                swap_image as hal::SwapImageIndex,
-                Some(&submission_complete_semaphores[frame_idx]),
-            ) {
-                recreate_swapchain = true;
+            if let Some(swap_image) = swap_image {
+                if let Err(_) = swap_chain.present(
+                    &mut queue_group.queues[0],
+                    swap_image as hal::SwapImageIndex,
+                    Some(&submission_complete_semaphores[frame_idx]),
+                ) {
+                    recreate_swapchain = true;
+                }
             }
         }
         // Increment our frame
@@ -711,12 +1204,44 @@ fn main() {
     }
 
     // cleanup!
+    vm_instance.unload_script(&mut world);
+
+    if let Some(recorder) = recorder {
+        if let Err(error) = recorder.finish() {
+            println!("Can't finish recording: {}", error);
+        }
+    }
+
+    if let Some(frame_export) = frame_export {
+        match frame_export.finish() {
+            Ok(frames) => println!("Exported {} frames", frames),
+            Err(error) => {
+                println!("Export failed: {}", error);
+                export_failed = true;
+            }
+        }
+    }
+
     device.wait_idle().unwrap();
+    if let Some(gpu_particles) = gpu_particles {
+        gpu_particles.destroy(&device, &mut allocator);
+    }
+    if let Some(capture_target) = capture_target {
+        capture_target.destroy(&device, &mut allocator);
+    }
+    overlay.destroy(&device, &mut allocator);
+    debug_lines.destroy(&device, &mut allocator);
+    mesh_renderer.destroy(&device, &mut allocator);
+    for frame in frames.iter_mut() {
+        for upload in frame.vbuffer.take().into_iter().chain(frame.ubuffer.take()) {
+            upload.destroy(&device, &mut allocator);
+        }
+    }
+    allocator.destroy(&device);
     unsafe {
         device.destroy_descriptor_pool(desc_pool);
         device.destroy_descriptor_set_layout(set_layout);
 
-        //device.destroy_buffer(vertex_buffer);
         device.destroy_semaphore(free_acquire_semaphore);
         for p in cmd_pools {
             device.destroy_command_pool(p.into_raw());
@@ -731,8 +1256,6 @@ fn main() {
             device.destroy_fence(f);
         }
         device.destroy_render_pass(render_pass);
-        //device.free_memory(vbuffer_memory);
-        //device.free_memory(cbuffer_memory);
         device.destroy_graphics_pipeline(pipeline);
         device.destroy_pipeline_layout(pipeline_layout);
         for framebuffer in framebuffers {
@@ -744,6 +1267,10 @@ fn main() {
 
         device.destroy_swapchain(swap_chain);
     }
+
+    if export_failed {
+        std::process::exit(1);
+    }
 }
 
 #[cfg(not(any(
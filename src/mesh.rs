@@ -0,0 +1,116 @@
+use nalgebra_glm as glm;
+use glm::{Vec3, vec3};
+
+use crate::guestmemory::{self, GuestMemory};
+
+// Triangles scripts submit with add_triangles and add_mesh, for ribbons, trails and ground planes. Like
+// the particles a script adds, they are collected during a tick and replaced by the next one. Every
+// submission is appended to one indexed batch, which the renderer draws before the particles.
+//
+// In guest memory a vertex is 36 bytes: position and normal (3 x f32 each), uv (2 x f32) and a color
+// packed like add_particle colors (u32). Indices are u32 and count from the start of their own mesh.
+
+pub const VERTEX_SIZE : usize = 36;
+
+// Triangles beyond these are dropped.
+pub const MAX_MESH_VERTICES : usize = 65536;
+pub const MAX_MESH_INDICES : usize = 3 * 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshVertex
+{
+    pub position : Vec3,
+    pub normal : Vec3,
+    pub uv : [f32; 2],
+    pub color : u32,
+}
+
+pub struct MeshBatch
+{
+    pub vertices : Vec<MeshVertex>,
+    pub indices : Vec<u32>,
+}
+
+impl MeshBatch
+{
+    pub fn new() -> MeshBatch {
+        MeshBatch { vertices : Vec::new(), indices : Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+        self.indices.clear();
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // Every three vertices are a triangle, a trailing partial triangle is ignored.
+    pub fn add_triangles(&mut self, vertices : &[MeshVertex]) -> bool {
+        let vertices = &vertices[.. vertices.len() / 3 * 3];
+        let indices : Vec<u32> = (0..vertices.len() as u32).collect();
+        self.add_indexed(vertices, &indices)
+    }
+
+    // Triangles with an index outside `vertices` are skipped. Returns false if the mesh didn't fit.
+    pub fn add_indexed(&mut self, vertices : &[MeshVertex], indices : &[u32]) -> bool {
+        if self.vertices.len() + vertices.len() > MAX_MESH_VERTICES {
+            return false;
+        }
+
+        let base = self.vertices.len() as u32;
+        let count = vertices.len() as u32;
+        let start = self.indices.len();
+        for triangle in indices.chunks_exact(3) {
+            if triangle.iter().any(|&index| index >= count) {
+                continue;
+            }
+            if self.indices.len() + 3 > MAX_MESH_INDICES {
+                self.indices.truncate(start);
+                return false;
+            }
+            self.indices.extend(triangle.iter().map(|&index| base + index));
+        }
+
+        self.vertices.extend_from_slice(vertices);
+        true
+    }
+}
+
+fn read_f32s<M : GuestMemory + ?Sized>(memory : &M, offset : usize, values : &mut [f32]) -> Option<()> {
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = f32::from_bits(guestmemory::read_u32(memory, offset + i * 4)?);
+    }
+    Some(())
+}
+
+// Reads `count` vertices at `ptr`, None if any of them is outside memory. Colors are returned as the
+// script packed them.
+pub fn read_vertices<M : GuestMemory + ?Sized>(memory : &M, ptr : u32, count : u32) -> Option<Vec<MeshVertex>> {
+    if count as usize > MAX_MESH_VERTICES {
+        return None;
+    }
+
+    let mut vertices = Vec::with_capacity(count as usize);
+    for i in 0..count as usize {
+        let offset = ptr as usize + i * VERTEX_SIZE;
+        let mut values = [0.0f32; 8];
+        read_f32s(memory, offset, &mut values)?;
+        vertices.push(MeshVertex {
+            position : vec3(values[0], values[1], values[2]),
+            normal : vec3(values[3], values[4], values[5]),
+            uv : [values[6], values[7]],
+            color : guestmemory::read_u32(memory, offset + 32)?,
+        });
+    }
+    Some(vertices)
+}
+
+pub fn read_indices<M : GuestMemory + ?Sized>(memory : &M, ptr : u32, count : u32) -> Option<Vec<u32>> {
+    if count as usize > MAX_MESH_INDICES {
+        return None;
+    }
+
+    (0..count as usize).map(|i| guestmemory::read_u32(memory, ptr as usize + i * 4)).collect()
+}
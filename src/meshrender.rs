@@ -0,0 +1,170 @@
+use hal::{
+    buffer,
+    command,
+    format as f,
+    pass::Subpass,
+    pso,
+    pso::VertexInputRate,
+    IndexType,
+    Primitive,
+};
+use hal::{Device};
+
+use crate::allocator::{GpuAllocator, MemoryUsage};
+use crate::backenderror::BackendDevice;
+use crate::mesh::{MeshBatch, MAX_MESH_INDICES, MAX_MESH_VERTICES};
+use crate::shader::load_shader;
+use crate::upload::UploadBuffer;
+
+// Draws the script's triangles (WorldState::meshes) next to the quad pipeline, with the same view
+// projection uniform. There is no depth buffer, so triangles are drawn in the order they were added,
+// before the particles, and blended like them.
+
+type Backend = back::Backend;
+type DescriptorSetLayout = <Backend as hal::Backend>::DescriptorSetLayout;
+type DescriptorSet = <Backend as hal::Backend>::DescriptorSet;
+
+const ENTRY_NAME : &str = "main";
+
+// The guest layout, so uv is there once meshes can be textured. The shaders don't read it yet.
+#[derive(Debug, Clone, Copy)]
+struct GpuVertex
+{
+    position : [f32; 3],
+    normal : [f32; 3],
+    uv : [f32; 2],
+    color : u32,
+}
+
+pub struct MeshRenderer
+{
+    // One vertex and index buffer per frame in flight.
+    vbuffers : Vec<UploadBuffer>,
+    ibuffers : Vec<UploadBuffer>,
+    index_counts : Vec<u32>,
+    layout : <Backend as hal::Backend>::PipelineLayout,
+    pipeline : <Backend as hal::Backend>::GraphicsPipeline,
+}
+
+impl MeshRenderer
+{
+    // `locals_layout` is the set with the view projection uniform, bound as set 0 when drawing.
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, render_pass : &<Backend as hal::Backend>::RenderPass,
+               locals_layout : &DescriptorSetLayout, frames_in_flight : usize) -> MeshRenderer {
+        let stride = std::mem::size_of::<GpuVertex>() as u64;
+        let vbuffers = (0..frames_in_flight)
+            .map(|_| UploadBuffer::new(device, allocator, MAX_MESH_VERTICES as u64 * stride, buffer::Usage::VERTEX, MemoryUsage::Upload)
+                .ok().expect("Can't create mesh vertex buffer"))
+            .collect();
+        let ibuffers = (0..frames_in_flight)
+            .map(|_| UploadBuffer::new(device, allocator, MAX_MESH_INDICES as u64 * 4, buffer::Usage::INDEX, MemoryUsage::Upload)
+                .ok().expect("Can't create mesh index buffer"))
+            .collect();
+
+        let layout = unsafe { device.create_pipeline_layout(std::iter::once(locals_layout), &[]) }
+            .expect("Can't create pipeline layout");
+
+        let pipeline = {
+            let vs_module = load_shader(device, "data/mesh.vert", glsl_to_spirv::ShaderType::Vertex);
+            let fs_module = load_shader(device, "data/overlay.frag", glsl_to_spirv::ShaderType::Fragment);
+
+            let shader_entries = pso::GraphicsShaderSet {
+                vertex : pso::EntryPoint { entry : ENTRY_NAME, module : &vs_module, specialization : pso::Specialization::default() },
+                hull : None,
+                domain : None,
+                geometry : None,
+                fragment : Some(pso::EntryPoint { entry : ENTRY_NAME, module : &fs_module, specialization : pso::Specialization::default() }),
+            };
+
+            let subpass = Subpass { index : 0, main_pass : render_pass };
+            let mut pipeline_desc = pso::GraphicsPipelineDesc::new(shader_entries, Primitive::TriangleList, pso::Rasterizer::FILL, &layout, subpass);
+            pipeline_desc.blender.targets.push(pso::ColorBlendDesc {
+                mask : pso::ColorMask::ALL,
+                blend : Some(pso::BlendState::ALPHA),
+            });
+            pipeline_desc.vertex_buffers.push(pso::VertexBufferDesc {
+                binding : 0,
+                stride : stride as u32,
+                rate : VertexInputRate::Vertex,
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 0,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgb32Sfloat, offset : 0 },
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 1,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgb32Sfloat, offset : 12 },
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 2,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgba8Unorm, offset : 32 },
+            });
+
+            let pipeline = unsafe { device.create_graphics_pipeline(&pipeline_desc, None) };
+            unsafe {
+                device.destroy_shader_module(vs_module);
+                device.destroy_shader_module(fs_module);
+            }
+            pipeline.expect("Can't create mesh pipeline")
+        };
+
+        MeshRenderer {
+            vbuffers : vbuffers,
+            ibuffers : ibuffers,
+            index_counts : vec![0; frames_in_flight],
+            layout : layout,
+            pipeline : pipeline,
+        }
+    }
+
+    // The frame's previous submission must have completed.
+    pub fn write_meshes(&mut self, device : &BackendDevice, frame_idx : usize, meshes : &MeshBatch) {
+        self.index_counts[frame_idx] = meshes.indices.len() as u32;
+        if meshes.indices.is_empty() {
+            return;
+        }
+
+        self.vbuffers[frame_idx].write_with(device, meshes.vertices.len(), |vertices : &mut [GpuVertex]| {
+            for (i, v) in meshes.vertices.iter().enumerate() {
+                vertices[i] = GpuVertex {
+                    position : [v.position.x, v.position.y, v.position.z],
+                    normal : [v.normal.x, v.normal.y, v.normal.z],
+                    uv : v.uv,
+                    color : v.color,
+                };
+            }
+        });
+        self.ibuffers[frame_idx].write(device, &meshes.indices);
+    }
+
+    pub fn record_draw(&self, encoder : &mut command::RenderPassInlineEncoder<Backend>, frame_idx : usize, locals : &DescriptorSet) {
+        if self.index_counts[frame_idx] == 0 {
+            return;
+        }
+
+        unsafe {
+            encoder.bind_graphics_pipeline(&self.pipeline);
+            encoder.bind_graphics_descriptor_sets(&self.layout, 0, Some(locals), &[]);
+            encoder.bind_vertex_buffers(0, Some((&self.vbuffers[frame_idx].device_buffer, 0)));
+            encoder.bind_index_buffer(buffer::IndexBufferView {
+                buffer : &self.ibuffers[frame_idx].device_buffer,
+                offset : 0,
+                index_type : IndexType::U32,
+            });
+            encoder.draw_indexed(0 .. self.index_counts[frame_idx], 0, 0 .. 1);
+        }
+    }
+
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe {
+            device.destroy_graphics_pipeline(self.pipeline);
+            device.destroy_pipeline_layout(self.layout);
+        }
+        for upload in self.vbuffers.into_iter().chain(self.ibuffers) {
+            upload.destroy(device, allocator);
+        }
+    }
+}
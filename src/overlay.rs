@@ -0,0 +1,203 @@
+use hal::{
+    buffer,
+    command,
+    format as f,
+    pass::Subpass,
+    pso,
+    pso::VertexInputRate,
+    Primitive,
+};
+use hal::{Device};
+
+use std::time::Duration;
+
+use crate::allocator::{GpuAllocator, MemoryUsage};
+use crate::backenderror::BackendDevice;
+use crate::font;
+use crate::shader::load_shader;
+use crate::upload::UploadBuffer;
+
+// Debug overlay (F3): text in the top left corner over a translucent panel, drawn with the built-in
+// bitmap font after the particles, plus the labels scripts draw with draw_text_2d/3d. Every lit font
+// pixel is a quad, laid out on the CPU straight in normalized device coordinates, so the pipeline needs
+// no uniforms.
+
+type Backend = back::Backend;
+
+const ENTRY_NAME : &str = "main";
+
+// Text beyond this is cut off.
+const MAX_VERTICES : usize = 6 * 16384;
+// Screen pixels per font pixel.
+const SCALE : f32 = 2.0;
+const MARGIN : f32 = 8.0;
+const TEXT_COLOR : u32 = 0xffff_ffff;
+const PANEL_COLOR : u32 = 0xa000_0000;
+
+#[derive(Debug, Clone, Copy)]
+struct OverlayVertex
+{
+    position : [f32; 2],
+    color : u32,
+}
+
+pub struct Overlay
+{
+    // One per frame in flight.
+    vbuffers : Vec<UploadBuffer>,
+    vertex_counts : Vec<u32>,
+    layout : <Backend as hal::Backend>::PipelineLayout,
+    pipeline : <Backend as hal::Backend>::GraphicsPipeline,
+}
+
+// Two triangles covering the rectangle, in pixels from the top left of a `width` x `height` viewport.
+fn add_rect(vertices : &mut Vec<OverlayVertex>, x : f32, y : f32, w : f32, h : f32, color : u32, width : f32, height : f32) {
+    let ndc = |px : f32, py : f32| OverlayVertex { position : [px / width * 2.0 - 1.0, py / height * 2.0 - 1.0], color : color };
+    let (v0, v1, v2, v3) = (ndc(x, y), ndc(x + w, y), ndc(x + w, y + h), ndc(x, y + h));
+    vertices.extend_from_slice(&[v0, v1, v2, v0, v2, v3]);
+}
+
+impl Overlay
+{
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, render_pass : &<Backend as hal::Backend>::RenderPass,
+               frames_in_flight : usize) -> Overlay {
+        let stride = std::mem::size_of::<OverlayVertex>() as u64;
+        let vbuffers = (0..frames_in_flight)
+            .map(|_| UploadBuffer::new(device, allocator, MAX_VERTICES as u64 * stride, buffer::Usage::VERTEX, MemoryUsage::Upload)
+                .ok().expect("Can't create overlay vertex buffer"))
+            .collect();
+
+        let layout = unsafe {
+            device.create_pipeline_layout(std::iter::empty::<<Backend as hal::Backend>::DescriptorSetLayout>(), &[])
+        }
+        .expect("Can't create pipeline layout");
+
+        let pipeline = {
+            let vs_module = load_shader(device, "data/overlay.vert", glsl_to_spirv::ShaderType::Vertex);
+            let fs_module = load_shader(device, "data/overlay.frag", glsl_to_spirv::ShaderType::Fragment);
+
+            let shader_entries = pso::GraphicsShaderSet {
+                vertex : pso::EntryPoint { entry : ENTRY_NAME, module : &vs_module, specialization : pso::Specialization::default() },
+                hull : None,
+                domain : None,
+                geometry : None,
+                fragment : Some(pso::EntryPoint { entry : ENTRY_NAME, module : &fs_module, specialization : pso::Specialization::default() }),
+            };
+
+            let subpass = Subpass { index : 0, main_pass : render_pass };
+            let mut pipeline_desc = pso::GraphicsPipelineDesc::new(shader_entries, Primitive::TriangleList, pso::Rasterizer::FILL, &layout, subpass);
+            pipeline_desc.blender.targets.push(pso::ColorBlendDesc {
+                mask : pso::ColorMask::ALL,
+                blend : Some(pso::BlendState::ALPHA),
+            });
+            pipeline_desc.vertex_buffers.push(pso::VertexBufferDesc {
+                binding : 0,
+                stride : stride as u32,
+                rate : VertexInputRate::Vertex,
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 0,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rg32Sfloat, offset : 0 },
+            });
+            pipeline_desc.attributes.push(pso::AttributeDesc {
+                location : 1,
+                binding : 0,
+                element : pso::Element { format : f::Format::Rgba8Unorm, offset : 8 },
+            });
+
+            let pipeline = unsafe { device.create_graphics_pipeline(&pipeline_desc, None) };
+            unsafe {
+                device.destroy_shader_module(vs_module);
+                device.destroy_shader_module(fs_module);
+            }
+            pipeline.expect("Can't create overlay pipeline")
+        };
+
+        Overlay {
+            vbuffers : vbuffers,
+            vertex_counts : vec![0; frames_in_flight],
+            layout : layout,
+            pipeline : pipeline,
+        }
+    }
+
+    // Lays out the frame's panel text and labels, each label at (x, y) pixels from the top left of a
+    // `width` x `height` viewport. The frame's previous submission must have completed.
+    pub fn write(&mut self, device : &BackendDevice, frame_idx : usize, panel : Option<&str>, labels : &[(f32, f32, &str, u32)], width : f32, height : f32) {
+        let mut vertices = Vec::new();
+
+        for &(label_x, label_y, text, color) in labels {
+            font::for_each_pixel(text, |x, y| {
+                add_rect(&mut vertices, label_x + x as f32 * SCALE, label_y + y as f32 * SCALE, SCALE, SCALE, color, width, height);
+            });
+        }
+
+        if let Some(text) = panel {
+            let (text_width, text_height) = font::text_size(text);
+            add_rect(&mut vertices, 0.0, 0.0, text_width as f32 * SCALE + MARGIN * 2.0, text_height as f32 * SCALE + MARGIN * 2.0, PANEL_COLOR, width, height);
+            font::for_each_pixel(text, |x, y| {
+                add_rect(&mut vertices, MARGIN + x as f32 * SCALE, MARGIN + y as f32 * SCALE, SCALE, SCALE, TEXT_COLOR, width, height);
+            });
+        }
+        vertices.truncate(MAX_VERTICES);
+
+        self.vertex_counts[frame_idx] = vertices.len() as u32;
+        if vertices.is_empty() {
+            return;
+        }
+
+        self.vbuffers[frame_idx].write(device, &vertices);
+    }
+
+    pub fn record_draw(&self, encoder : &mut command::RenderPassInlineEncoder<Backend>, frame_idx : usize) {
+        if self.vertex_counts[frame_idx] == 0 {
+            return;
+        }
+
+        unsafe {
+            encoder.bind_graphics_pipeline(&self.pipeline);
+            encoder.bind_vertex_buffers(0, Some((&self.vbuffers[frame_idx].device_buffer, 0)));
+            encoder.draw(0 .. self.vertex_counts[frame_idx], 0 .. 1);
+        }
+    }
+
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe {
+            device.destroy_graphics_pipeline(self.pipeline);
+            device.destroy_pipeline_layout(self.layout);
+        }
+        for upload in self.vbuffers {
+            upload.destroy(device, allocator);
+        }
+    }
+}
+
+// Frame timings for the overlay, in milliseconds, smoothed so the numbers are readable.
+pub struct FrameStats
+{
+    pub frame_ms : f32,
+    pub script_ms : f32,
+    pub upload_ms : f32,
+    samples : u32,
+}
+
+fn milliseconds(duration : Duration) -> f32 {
+    duration.as_micros() as f32 / 1000.0
+}
+
+impl FrameStats
+{
+    pub fn new() -> FrameStats {
+        FrameStats { frame_ms : 0.0, script_ms : 0.0, upload_ms : 0.0, samples : 0 }
+    }
+
+    pub fn add(&mut self, frame : Duration, script : Duration, upload : Duration) {
+        // The first frame sets the averages, later ones move them by a twentieth.
+        let weight = if self.samples == 0 { 1.0 } else { 0.05 };
+        self.frame_ms += (milliseconds(frame) - self.frame_ms) * weight;
+        self.script_ms += (milliseconds(script) - self.script_ms) * weight;
+        self.upload_ms += (milliseconds(upload) - self.upload_ms) * weight;
+        self.samples += 1;
+    }
+}
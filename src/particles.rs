@@ -0,0 +1,518 @@
+use nalgebra_glm as glm;
+use glm::{Vec3, vec3};
+
+use rand::{Rng, XorShiftRng};
+
+use std::collections::VecDeque;
+
+// Persistent particles owned and integrated by the host. Scripts create emitters and set their
+// parameters once instead of re-submitting every particle every frame; emitter parameters apply to
+// the particles emitted after they change. Emitters can ask for callbacks when their particles spawn
+// or die, for behaviour the parameters don't cover.
+
+pub const MAX_PARTICLES : usize = 1 << 18;
+
+// Emitter callback flags, see set_emitter_callbacks.
+pub const CALLBACK_SPAWN : i32 = 1;
+pub const CALLBACK_DEATH : i32 = 2;
+
+// Most past positions a ribbon keeps.
+pub const MAX_TRAIL_POINTS : usize = 32;
+
+// How a simulated particle is drawn, see set_emitter_render_mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode
+{
+    Billboard,
+    // Stretched along its velocity.
+    Stretched,
+    // A strip through its past positions.
+    Ribbon,
+}
+
+impl RenderMode
+{
+    pub fn from_i32(mode : i32) -> Option<RenderMode> {
+        match mode {
+            0 => Some(RenderMode::Billboard),
+            1 => Some(RenderMode::Stretched),
+            2 => Some(RenderMode::Ribbon),
+            _ => None,
+        }
+    }
+}
+
+// Drawing state of a simulated particle. It doesn't affect the simulation, so it lives next to
+// SimParticle rather than in it, which the GPU integrator and snapshots share.
+#[derive(Debug, Clone)]
+pub struct ParticleTrail
+{
+    pub mode : RenderMode,
+    // Seconds of motion a streak or ribbon covers.
+    pub length : f32,
+    // Positions at the end of past updates, oldest first, for ribbons.
+    pub history : VecDeque<Vec3>,
+}
+
+impl ParticleTrail
+{
+    pub fn new(mode : RenderMode, length : f32) -> ParticleTrail {
+        ParticleTrail { mode : mode, length : length, history : VecDeque::new() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EmitterParams
+{
+    pub position : Vec3,
+    // Initial velocity, each component randomized by up to +-spread.
+    pub velocity : Vec3,
+    pub spread : f32,
+    // Particles per second.
+    pub rate : f32,
+    // Seconds.
+    pub lifetime : f32,
+    pub size : f32,
+    // Packed linear RGBA8, see color.rs.
+    pub color : u32,
+    pub gravity : Vec3,
+    // Fraction of the velocity lost per second.
+    pub drag : f32,
+    pub callbacks : i32,
+    pub render_mode : RenderMode,
+    pub trail_length : f32,
+}
+
+impl EmitterParams
+{
+    pub fn new() -> EmitterParams {
+        EmitterParams {
+            position : vec3(0.0, 0.0, 0.0),
+            velocity : vec3(0.0, 1.0, 0.0),
+            spread : 0.0,
+            rate : 10.0,
+            lifetime : 1.0,
+            size : 0.1,
+            color : 0xffff_ffff,
+            gravity : vec3(0.0, 0.0, 0.0),
+            drag : 0.0,
+            callbacks : 0,
+            render_mode : RenderMode::Billboard,
+            trail_length : 0.1,
+        }
+    }
+}
+
+struct Emitter
+{
+    params : EmitterParams,
+    // Fractional particles carried over to the next update.
+    pending : f32,
+    burst : u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SimParticle
+{
+    pub position : Vec3,
+    pub velocity : Vec3,
+    pub gravity : Vec3,
+    pub drag : f32,
+    pub age : f32,
+    pub lifetime : f32,
+    pub size : f32,
+    pub color : u32,
+    pub emitter : i32,
+}
+
+// Script callbacks produced by an update, delivered after it.
+#[derive(Debug, Clone, Copy)]
+pub enum ParticleEvent
+{
+    // `particle` indexes ParticleSystem::particles until the next update.
+    Spawned { emitter : i32, particle : usize },
+    Died { emitter : i32, position : Vec3 },
+}
+
+// Semi-implicit Euler. The reference the GPU integrator is checked against.
+pub fn integrate(particle : &mut SimParticle, dt : f32) {
+    particle.velocity += particle.gravity * dt;
+    particle.velocity *= 1.0 / (1.0 + particle.drag * dt);
+    particle.position += particle.velocity * dt;
+    particle.age += dt;
+}
+
+// Steps the live particles of `before` with integrate and compares them to `after`, another
+// integrator's result for the same step, skipping the slots `skip` says were reused in between.
+// Returns the number of particles compared and the largest position difference.
+pub fn compare_step<F : Fn(usize) -> bool>(before : &[SimParticle], after : &[SimParticle], dt : f32, skip : F) -> (usize, f32) {
+    let mut compared = 0;
+    let mut max_error = 0.0f32;
+    for (index, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+        if before.age >= before.lifetime || skip(index) {
+            continue;
+        }
+
+        let mut reference = *before;
+        integrate(&mut reference, dt);
+
+        max_error = max_error.max(glm::distance(&reference.position, &after.position));
+        compared += 1;
+    }
+
+    (compared, max_error)
+}
+
+pub struct ParticleSystem
+{
+    // Indexed by emitter id, destroyed emitters leave a free slot.
+    emitters : Vec<Option<Emitter>>,
+    particles : Vec<SimParticle>,
+    // Positions before the last update, parallel to `particles`, for drawing between updates.
+    previous_positions : Vec<Vec3>,
+    // Also parallel to `particles`.
+    trails : Vec<ParticleTrail>,
+    // When false the particles are integrated elsewhere (gpuparticles.rs): `particles` only holds the
+    // ones spawned since the last hand_over, and there are no death callbacks.
+    host_integration : bool,
+    // Set by clear, for a simulation that keeps its own copy of the particles.
+    cleared : bool,
+}
+
+impl ParticleSystem
+{
+    pub fn new() -> ParticleSystem {
+        ParticleSystem {
+            emitters : Vec::new(),
+            particles : Vec::new(),
+            previous_positions : Vec::new(),
+            trails : Vec::new(),
+            host_integration : true,
+            cleared : false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.emitters.clear();
+        self.particles.clear();
+        self.previous_positions.clear();
+        self.trails.clear();
+        self.cleared = true;
+    }
+
+    pub fn set_host_integration(&mut self, enabled : bool) {
+        self.host_integration = enabled;
+        self.particles.clear();
+        self.previous_positions.clear();
+        self.trails.clear();
+        self.cleared = true;
+    }
+
+    // Without host integration, drops the particles spawned so far once they have been handed to the
+    // simulation that owns them.
+    pub fn hand_over(&mut self) {
+        if !self.host_integration {
+            self.particles.clear();
+            self.previous_positions.clear();
+            self.trails.clear();
+        }
+    }
+
+    pub fn host_integration(&self) -> bool {
+        self.host_integration
+    }
+
+    // True once after clear.
+    pub fn take_cleared(&mut self) -> bool {
+        std::mem::replace(&mut self.cleared, false)
+    }
+
+    pub fn create_emitter(&mut self) -> i32 {
+        let emitter = Some(Emitter { params : EmitterParams::new(), pending : 0.0, burst : 0 });
+
+        match self.emitters.iter().position(|slot| slot.is_none()) {
+            Some(index) => {
+                self.emitters[index] = emitter;
+                index as i32
+            }
+            None => {
+                self.emitters.push(emitter);
+                self.emitters.len() as i32 - 1
+            }
+        }
+    }
+
+    // Stops emitting, particles already emitted live out their lifetime.
+    pub fn destroy_emitter(&mut self, id : i32) -> bool {
+        match self.emitters.get_mut(id as usize) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn emitter_mut(&mut self, id : i32) -> Option<&mut Emitter> {
+        if id < 0 {
+            return None;
+        }
+        self.emitters.get_mut(id as usize).and_then(|slot| slot.as_mut())
+    }
+
+    pub fn emitter_params_mut(&mut self, id : i32) -> Option<&mut EmitterParams> {
+        self.emitter_mut(id).map(|emitter| &mut emitter.params)
+    }
+
+    // Emits `count` extra particles on the next update.
+    pub fn emit(&mut self, id : i32, count : u32) -> bool {
+        match self.emitter_mut(id) {
+            Some(emitter) => {
+                emitter.burst = emitter.burst.saturating_add(count);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn particles(&self) -> &[SimParticle] {
+        &self.particles
+    }
+
+    // Replaces the particles, e.g. from a snapshot. They start at rest for interpolation, as billboards.
+    pub fn restore_particles(&mut self, particles : Vec<SimParticle>) {
+        self.previous_positions = particles.iter().map(|particle| particle.position).collect();
+        self.trails = particles.iter().map(|_| ParticleTrail::new(RenderMode::Billboard, 0.0)).collect();
+        self.particles = particles;
+        self.cleared = true;
+    }
+
+    // Between the position before the last update (alpha 0) and the current one (alpha 1).
+    pub fn interpolated_position(&self, index : usize, alpha : f32) -> Vec3 {
+        glm::lerp(&self.previous_positions[index], &self.particles[index].position, alpha)
+    }
+
+    pub fn trail(&self, index : usize) -> &ParticleTrail {
+        &self.trails[index]
+    }
+
+    pub fn trail_mut(&mut self, index : i32) -> Option<&mut ParticleTrail> {
+        if index < 0 {
+            return None;
+        }
+        self.trails.get_mut(index as usize)
+    }
+
+    pub fn particle_mut(&mut self, index : i32) -> Option<&mut SimParticle> {
+        if index < 0 {
+            return None;
+        }
+        self.particles.get_mut(index as usize)
+    }
+
+    fn wants_callback(&self, emitter : i32, flag : i32) -> bool {
+        self.emitters.get(emitter as usize).and_then(|slot| slot.as_ref()).map_or(false, |emitter| emitter.params.callbacks & flag != 0)
+    }
+
+    pub fn update(&mut self, dt : f32, rng : &mut XorShiftRng, events : &mut Vec<ParticleEvent>) {
+        if self.host_integration {
+            for (particle, previous) in self.particles.iter_mut().zip(self.previous_positions.iter_mut()) {
+                *previous = particle.position;
+                integrate(particle, dt);
+            }
+
+            for (trail, previous) in self.trails.iter_mut().zip(self.previous_positions.iter()) {
+                if trail.mode != RenderMode::Ribbon {
+                    trail.history.clear();
+                    continue;
+                }
+                let points = ((trail.length / dt).ceil().max(1.0) as usize).min(MAX_TRAIL_POINTS);
+                trail.history.push_back(*previous);
+                while trail.history.len() > points {
+                    trail.history.pop_front();
+                }
+            }
+        }
+
+        // Dead particles are swapped out, so spawned particles appended below keep their indices.
+        let mut index = 0;
+        while index < self.particles.len() {
+            let particle = self.particles[index];
+            if particle.age < particle.lifetime {
+                index += 1;
+                continue;
+            }
+
+            if self.wants_callback(particle.emitter, CALLBACK_DEATH) {
+                events.push(ParticleEvent::Died { emitter : particle.emitter, position : particle.position });
+            }
+            self.particles.swap_remove(index);
+            self.previous_positions.swap_remove(index);
+            self.trails.swap_remove(index);
+        }
+
+        for (id, slot) in self.emitters.iter_mut().enumerate() {
+            let emitter = match slot {
+                Some(emitter) => emitter,
+                None => continue,
+            };
+
+            emitter.pending += emitter.params.rate.max(0.0) * dt;
+            let count = emitter.pending as u32 + emitter.burst;
+            emitter.pending = emitter.pending.fract();
+            emitter.burst = 0;
+
+            let params = &emitter.params;
+            for _ in 0..count {
+                if self.particles.len() >= MAX_PARTICLES {
+                    break;
+                }
+
+                let jitter = vec3(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0)) * params.spread;
+                self.particles.push(SimParticle {
+                    position : params.position,
+                    velocity : params.velocity + jitter,
+                    gravity : params.gravity,
+                    drag : params.drag,
+                    age : 0.0,
+                    lifetime : params.lifetime,
+                    size : params.size,
+                    color : params.color,
+                    emitter : id as i32,
+                });
+                self.previous_positions.push(params.position);
+                self.trails.push(ParticleTrail::new(params.render_mode, params.trail_length));
+
+                if params.callbacks & CALLBACK_SPAWN != 0 {
+                    events.push(ParticleEvent::Spawned { emitter : id as i32, particle : self.particles.len() - 1 });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use rand::SeedableRng;
+
+    fn particle(velocity : Vec3, gravity : Vec3, drag : f32) -> SimParticle {
+        SimParticle {
+            position : vec3(0.0, 0.0, 0.0),
+            velocity : velocity,
+            gravity : gravity,
+            drag : drag,
+            age : 0.0,
+            lifetime : 1.0,
+            size : 0.1,
+            color : 0xffff_ffff,
+            emitter : 0,
+        }
+    }
+
+    fn close(a : Vec3, b : Vec3) -> bool {
+        glm::distance(&a, &b) < 1e-5
+    }
+
+    #[test]
+    fn integrate_moves_with_the_new_velocity() {
+        let mut p = particle(vec3(1.0, 0.0, 0.0), vec3(0.0, -10.0, 0.0), 0.0);
+        integrate(&mut p, 0.1);
+        assert!(close(p.velocity, vec3(1.0, -1.0, 0.0)));
+        // Explicit Euler would still be at y = 0.
+        assert!(close(p.position, vec3(0.1, -0.1, 0.0)));
+        assert_eq!(p.age, 0.1);
+
+        integrate(&mut p, 0.1);
+        assert!(close(p.velocity, vec3(1.0, -2.0, 0.0)));
+        assert!(close(p.position, vec3(0.2, -0.3, 0.0)));
+    }
+
+    #[test]
+    fn integrate_drag() {
+        let mut p = particle(vec3(4.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0), 2.0);
+        integrate(&mut p, 0.5);
+        assert!(close(p.velocity, vec3(2.0, 0.0, 0.0)));
+        assert!(close(p.position, vec3(1.0, 0.0, 0.0)));
+
+        // Implicit, so a long step slows the particle down without turning it around.
+        let mut p = particle(vec3(4.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0), 1000.0);
+        integrate(&mut p, 1.0);
+        assert!(p.velocity.x > 0.0 && p.velocity.x < 0.01);
+    }
+
+    #[test]
+    fn particles_die_at_their_lifetime() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut events = Vec::new();
+        let mut system = ParticleSystem::new();
+        let emitter = system.create_emitter();
+        {
+            let params = system.emitter_params_mut(emitter).unwrap();
+            params.rate = 0.0;
+            params.lifetime = 0.25;
+            params.callbacks = CALLBACK_DEATH;
+        }
+        system.emit(emitter, 3);
+
+        system.update(0.125, &mut rng, &mut events);
+        assert_eq!(system.particles().len(), 3);
+        assert!(system.particles().iter().all(|particle| particle.age == 0.0));
+
+        system.update(0.125, &mut rng, &mut events);
+        assert_eq!(system.particles().len(), 3);
+        assert!(events.is_empty());
+
+        system.update(0.125, &mut rng, &mut events);
+        assert!(system.particles().is_empty());
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| match event {
+            ParticleEvent::Died { emitter : id, .. } => *id == emitter,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn emitter_rate_carries_fractions_over() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut events = Vec::new();
+        let mut system = ParticleSystem::new();
+        let emitter = system.create_emitter();
+        {
+            let params = system.emitter_params_mut(emitter).unwrap();
+            params.rate = 10.0;
+            params.lifetime = 10.0;
+        }
+
+        system.update(0.25, &mut rng, &mut events);
+        assert_eq!(system.particles().len(), 2);
+        system.update(0.25, &mut rng, &mut events);
+        assert_eq!(system.particles().len(), 5);
+    }
+
+    #[test]
+    fn compare_step_skips_dead_and_reused_slots() {
+        let live = particle(vec3(1.0, 2.0, 0.0), vec3(0.0, -10.0, 0.0), 0.5);
+        let mut dead = live;
+        dead.age = dead.lifetime;
+        let before = [live, dead, live];
+
+        let mut stepped = live;
+        integrate(&mut stepped, 0.1);
+        let mut off = stepped;
+        off.position.y += 0.01;
+        // Garbage where nothing should be compared.
+        let mut garbage = live;
+        garbage.position = vec3(100.0, 100.0, 100.0);
+
+        let (compared, error) = compare_step(&before, &[stepped, garbage, garbage], 0.1, |index| index == 2);
+        assert_eq!(compared, 1);
+        assert_eq!(error, 0.0);
+
+        let (compared, error) = compare_step(&before, &[off, garbage, stepped], 0.1, |_| false);
+        assert_eq!(compared, 2);
+        assert!((error - 0.01).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,457 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use crate::vm::{VMInstance, VMError};
+use crate::world::WorldState;
+
+// Session recordings for bug reports. A recording holds everything that feeds a script apart from the
+// module itself: the RNG seed, the length of every frame and the input applied before it.
+// Replaying it headlessly against the same module reproduces the same WorldState every frame, and the
+// checksum stored per frame shows where that stops being true.
+//
+// The file is little endian: magic, version, header, then per frame its length and dt (f32), the world
+// checksum (u64), an event count (u8) and the events, each a tag byte plus its payload.
+
+const MAGIC : &[u8; 4] = b"WSRC";
+const VERSION : u32 = 2;
+
+// Input that changes what the scripts see, applied at the start of a frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent
+{
+    Resize { width : u32, height : u32 },
+    SetPaused(bool),
+    Step,
+    ChangeTimeScale { faster : bool },
+    // Another module was swapped in, a replay can't follow past this.
+    ScriptReloaded,
+}
+
+impl InputEvent
+{
+    pub fn apply(&self, world : &mut WorldState, vm : &mut VMInstance) {
+        match *self {
+            InputEvent::Resize { width, height } => {
+                if let Err(error) = vm.call_on_resize(world, width, height) {
+                    println!("{}", error);
+                }
+            }
+            InputEvent::SetPaused(paused) => world.set_paused(paused),
+            InputEvent::Step => world.step_once(),
+            InputEvent::ChangeTimeScale { faster } => world.change_time_scale(faster),
+            InputEvent::ScriptReloaded => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingHeader
+{
+    pub module_hash : String,
+    pub engine : String,
+    pub seed : u64,
+    pub tick_length : f32,
+    pub time_scale : f32,
+    pub paused : bool,
+    pub host_integration : bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRecord
+{
+    // Seconds since the previous frame, as WorldState::advance measured them or a frame export set them.
+    pub elapsed : f32,
+    // Simulation time it ran.
+    pub dt : f32,
+    pub checksum : u64,
+    pub events : Vec<InputEvent>,
+}
+
+pub enum ReplayError
+{
+    Io(io::Error),
+    Script(VMError),
+    ModuleMismatch { recorded : String, found : String },
+    ScriptReloaded { frame : usize },
+    Diverged { frame : usize, expected : u64, found : u64 },
+    // Same world, but a different amount of simulation time ran.
+    DtMismatch { frame : usize, expected : f32, found : f32 },
+}
+
+impl fmt::Display for ReplayError
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(error) => write!(f, "can't read recording: {}", error),
+            ReplayError::Script(error) => write!(f, "{}", error),
+            ReplayError::ModuleMismatch { recorded, found } => write!(f, "recorded with module {}, the script is {}", recorded, found),
+            ReplayError::ScriptReloaded { frame } => write!(f, "the script was reloaded at frame {}, can't replay further", frame),
+            ReplayError::Diverged { frame, expected, found } => write!(f, "diverged at frame {}: checksum {:016x}, recorded {:016x}", frame, found, expected),
+            ReplayError::DtMismatch { frame, expected, found } => write!(f, "diverged at frame {}: ran {} s of simulation, recorded {} s", frame, found, expected),
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError
+{
+    fn from(error: io::Error) -> Self {
+        ReplayError::Io(error)
+    }
+}
+
+impl From<VMError> for ReplayError
+{
+    fn from(error: VMError) -> Self {
+        ReplayError::Script(error)
+    }
+}
+
+fn invalid_data(message : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_u32<W : Write>(out : &mut W, value : u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W : Write>(out : &mut W, value : u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_f32<W : Write>(out : &mut W, value : f32) -> io::Result<()> {
+    write_u32(out, value.to_bits())
+}
+
+fn write_u8<W : Write>(out : &mut W, value : u8) -> io::Result<()> {
+    out.write_all(&[value])
+}
+
+fn write_str<W : Write>(out : &mut W, value : &str) -> io::Result<()> {
+    if value.len() > u8::max_value() as usize {
+        return Err(invalid_data("string too long"));
+    }
+    write_u8(out, value.len() as u8)?;
+    out.write_all(value.as_bytes())
+}
+
+fn read_u8<R : Read>(input : &mut R) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R : Read>(input : &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R : Read>(input : &mut R) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R : Read>(input : &mut R) -> io::Result<f32> {
+    read_u32(input).map(f32::from_bits)
+}
+
+fn read_str<R : Read>(input : &mut R) -> io::Result<String> {
+    let mut bytes = vec![0u8; read_u8(input)? as usize];
+    input.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("string is not UTF-8"))
+}
+
+fn write_event<W : Write>(out : &mut W, event : &InputEvent) -> io::Result<()> {
+    match *event {
+        InputEvent::Resize { width, height } => {
+            write_u8(out, 0)?;
+            write_u32(out, width)?;
+            write_u32(out, height)
+        }
+        InputEvent::SetPaused(paused) => {
+            write_u8(out, 1)?;
+            write_u8(out, paused as u8)
+        }
+        InputEvent::Step => write_u8(out, 2),
+        InputEvent::ChangeTimeScale { faster } => {
+            write_u8(out, 3)?;
+            write_u8(out, faster as u8)
+        }
+        InputEvent::ScriptReloaded => write_u8(out, 4),
+    }
+}
+
+fn read_event<R : Read>(input : &mut R) -> io::Result<InputEvent> {
+    Ok(match read_u8(input)? {
+        0 => InputEvent::Resize { width : read_u32(input)?, height : read_u32(input)? },
+        1 => InputEvent::SetPaused(read_u8(input)? != 0),
+        2 => InputEvent::Step,
+        3 => InputEvent::ChangeTimeScale { faster : read_u8(input)? != 0 },
+        4 => InputEvent::ScriptReloaded,
+        _ => return Err(invalid_data("unknown event")),
+    })
+}
+
+pub struct Recorder
+{
+    out : BufWriter<File>,
+}
+
+impl Recorder
+{
+    pub fn create(path : &str, header : &RecordingHeader) -> io::Result<Recorder> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(MAGIC)?;
+        write_u32(&mut out, VERSION)?;
+        write_str(&mut out, &header.module_hash)?;
+        write_str(&mut out, &header.engine)?;
+        write_u64(&mut out, header.seed)?;
+        write_f32(&mut out, header.tick_length)?;
+        write_f32(&mut out, header.time_scale)?;
+        write_u8(&mut out, header.paused as u8)?;
+        write_u8(&mut out, header.host_integration as u8)?;
+
+        Ok(Recorder { out : out })
+    }
+
+    pub fn record_frame(&mut self, frame : &FrameRecord) -> io::Result<()> {
+        if frame.events.len() > u8::max_value() as usize {
+            return Err(invalid_data("too many events in one frame"));
+        }
+
+        write_f32(&mut self.out, frame.elapsed)?;
+        write_f32(&mut self.out, frame.dt)?;
+        write_u64(&mut self.out, frame.checksum)?;
+        write_u8(&mut self.out, frame.events.len() as u8)?;
+        for event in &frame.events {
+            write_event(&mut self.out, event)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub fn read_recording(path : &str) -> io::Result<(RecordingHeader, Vec<FrameRecord>)> {
+    let mut input = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a recording"));
+    }
+    if read_u32(&mut input)? != VERSION {
+        return Err(invalid_data("unsupported recording version"));
+    }
+
+    let header = RecordingHeader {
+        module_hash : read_str(&mut input)?,
+        engine : read_str(&mut input)?,
+        seed : read_u64(&mut input)?,
+        tick_length : read_f32(&mut input)?,
+        time_scale : read_f32(&mut input)?,
+        paused : read_u8(&mut input)? != 0,
+        host_integration : read_u8(&mut input)? != 0,
+    };
+
+    let mut frames = Vec::new();
+    loop {
+        // A recording cut short by a crash ends mid-frame, keep the frames before it.
+        let elapsed = match read_f32(&mut input) {
+            Ok(elapsed) => elapsed,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        };
+
+        let frame = (|| -> io::Result<FrameRecord> {
+            let dt = read_f32(&mut input)?;
+            let checksum = read_u64(&mut input)?;
+            let count = read_u8(&mut input)?;
+            let events = (0..count).map(|_| read_event(&mut input)).collect::<io::Result<Vec<_>>>()?;
+            Ok(FrameRecord { elapsed : elapsed, dt : dt, checksum : checksum, events : events })
+        })();
+
+        match frame {
+            Ok(frame) => frames.push(frame),
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok((header, frames))
+}
+
+// Replays a recording against the VM's source file without a window. Returns the number of frames
+// that matched, which is all of them unless there is an error.
+pub fn replay(path : &str, vm : &mut VMInstance, world : &mut WorldState) -> Result<usize, ReplayError> {
+    let (header, frames) = read_recording(path)?;
+
+    if vm.engine_name() != header.engine {
+        println!("Recorded with the {} engine, replaying with {}; floating point results may differ", header.engine, vm.engine_name());
+    }
+
+    world.reseed(header.seed);
+    world.tick_length = header.tick_length;
+    world.set_time_scale(header.time_scale);
+    world.set_paused(header.paused);
+    world.particle_system.set_host_integration(header.host_integration);
+
+    vm.load_script(world)?;
+    let module_hash = vm.module_hash().unwrap_or("").to_string();
+    if module_hash != header.module_hash {
+        return Err(ReplayError::ModuleMismatch { recorded : header.module_hash, found : module_hash });
+    }
+
+    for (index, frame) in frames.iter().enumerate() {
+        for event in &frame.events {
+            if *event == InputEvent::ScriptReloaded {
+                return Err(ReplayError::ScriptReloaded { frame : index });
+            }
+            event.apply(world, vm);
+        }
+
+        world.advance_by(vm, frame.elapsed);
+
+        let checksum = world.checksum();
+        if checksum != frame.checksum {
+            return Err(ReplayError::Diverged { frame : index, expected : frame.checksum, found : checksum });
+        }
+        if world.frame_dt != frame.dt {
+            return Err(ReplayError::DtMismatch { frame : index, expected : frame.dt, found : world.frame_dt });
+        }
+
+        // The renderer takes the new particles at this point when they live on the GPU.
+        world.particle_system.hand_over();
+    }
+
+    vm.unload_script(world);
+    Ok(frames.len())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name : &str) -> String {
+        std::env::temp_dir().join(format!("webasm_scripting_{}_{}.wsrc", name, std::process::id())).to_string_lossy().into_owned()
+    }
+
+    fn header() -> RecordingHeader {
+        RecordingHeader {
+            module_hash : "0123456789abcdef0123456789abcdef".to_string(),
+            engine : "wasmi".to_string(),
+            seed : 0x1234_5678_9abc_def0,
+            tick_length : 1.0 / 60.0,
+            time_scale : 0.5,
+            paused : true,
+            host_integration : false,
+        }
+    }
+
+    fn frames() -> Vec<FrameRecord> {
+        vec![
+            FrameRecord { elapsed : 0.0, dt : 0.0, checksum : 1, events : Vec::new() },
+            FrameRecord {
+                elapsed : 0.016,
+                dt : 1.0 / 60.0,
+                checksum : u64::max_value(),
+                events : vec![
+                    InputEvent::Resize { width : 1920, height : 1080 },
+                    InputEvent::SetPaused(true),
+                    InputEvent::SetPaused(false),
+                    InputEvent::Step,
+                    InputEvent::ChangeTimeScale { faster : true },
+                    InputEvent::ChangeTimeScale { faster : false },
+                    InputEvent::ScriptReloaded,
+                ],
+            },
+            FrameRecord { elapsed : 0.25, dt : 0.25, checksum : 0xfeed, events : vec![InputEvent::Step] },
+        ]
+    }
+
+    fn write(path : &str, header : &RecordingHeader, frames : &[FrameRecord]) {
+        let mut recorder = Recorder::create(path, header).unwrap();
+        for frame in frames {
+            recorder.record_frame(frame).unwrap();
+        }
+        recorder.finish().unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip");
+        write(&path, &header(), &frames());
+        let read = read_recording(&path);
+        fs::remove_file(&path).unwrap();
+
+        let (read_header, read_frames) = read.unwrap();
+        assert_eq!(read_header, header());
+        assert_eq!(read_frames, frames());
+    }
+
+    #[test]
+    fn truncated_recording_keeps_whole_frames() {
+        let path = temp_path("truncated");
+        write(&path, &header(), &frames());
+        let bytes = fs::read(&path).unwrap();
+
+        // Cut into the last frame's event.
+        fs::write(&path, &bytes[.. bytes.len() - 1]).unwrap();
+        let (_, read_frames) = read_recording(&path).unwrap();
+        assert_eq!(read_frames, &frames()[.. 2]);
+
+        // Cut right after the header.
+        write(&path, &header(), &[]);
+        let header_length = fs::metadata(&path).unwrap().len() as usize;
+        fs::write(&path, &bytes[.. header_length]).unwrap();
+        let (read_header, read_frames) = read_recording(&path).unwrap();
+        assert_eq!(read_header, header());
+        assert!(read_frames.is_empty());
+
+        // Cut into the header.
+        fs::write(&path, &bytes[.. header_length - 1]).unwrap();
+        let error = read_recording(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("rejects");
+        write(&path, &header(), &[]);
+        let mut bytes = fs::read(&path).unwrap();
+
+        bytes[4] = VERSION as u8 + 1;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(read_recording(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        bytes[0] = b'X';
+        fs::write(&path, &bytes).unwrap();
+        let error = read_recording(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_event_is_an_error() {
+        let path = temp_path("unknown_event");
+        write(&path, &header(), &frames()[.. 1]);
+        let mut bytes = fs::read(&path).unwrap();
+        // One event with tag 9 in the last frame.
+        let last = bytes.len() - 1;
+        bytes[last] = 1;
+        bytes.push(9);
+        fs::write(&path, &bytes).unwrap();
+        let error = read_recording(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
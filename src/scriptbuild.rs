@@ -0,0 +1,122 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Builds C/C++ scripts with a local clang, so editing data/*.c no longer needs an online compiler.
+// Scripts are freestanding: no libc, the few functions they use come from the host (hostapi.rs) and
+// are declared by the headers in data/include. Unresolved imports are left to the host and every
+// public function is exported, `tick` included.
+
+const INCLUDE_DIRECTORY : &str = "data/include";
+// Where the generated webasm_scripting.h lives.
+const SDK_DIRECTORY : &str = "sdk";
+const OUTPUT_DIRECTORY : &str = "cache/build";
+
+// Tried in order unless $CLANG names the compiler.
+const CLANG_CANDIDATES : &[&str] = &["clang", "clang-10", "clang-9", "clang-8"];
+
+const PROBE_SOURCE : &str = "extern \"C\" void tick(float t) {}\n";
+
+#[derive(Debug, Clone)]
+pub struct Toolchain
+{
+    pub clang : PathBuf,
+}
+
+fn compile_flags() -> Vec<String> {
+    vec![
+        "--target=wasm32".to_string(),
+        // The scripts in data/ are C++ despite the extension.
+        "-x".to_string(), "c++".to_string(),
+        "-std=c++14".to_string(),
+        "-O2".to_string(),
+        "-nostdlib".to_string(),
+        "-nostdlibinc".to_string(),
+        "-fno-exceptions".to_string(),
+        "-fno-rtti".to_string(),
+        "-fno-threadsafe-statics".to_string(),
+        format!("-isystem{}", INCLUDE_DIRECTORY),
+        format!("-I{}", SDK_DIRECTORY),
+        "-Wl,--no-entry".to_string(),
+        "-Wl,--export-dynamic".to_string(),
+        "-Wl,--allow-undefined".to_string(),
+    ]
+}
+
+// Finds a clang that can compile and link for wasm32, i.e. one built with the WebAssembly target and
+// with wasm-ld next to it.
+pub fn detect_toolchain() -> Option<Toolchain> {
+    let candidates : Vec<String> = match env::var("CLANG") {
+        Ok(clang) => vec![clang],
+        Err(_) => CLANG_CANDIDATES.iter().map(|c| c.to_string()).collect(),
+    };
+
+    let probe_output = env::temp_dir().join(format!("webasm_scripting_probe_{}.wasm", std::process::id()));
+
+    for clang in candidates {
+        let child = Command::new(&clang)
+            .args(compile_flags())
+            .arg("-o").arg(&probe_output)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(_) => continue,
+        };
+
+        let written = child.stdin.take().map_or(false, |mut stdin| stdin.write_all(PROBE_SOURCE.as_bytes()).is_ok());
+        let succeeded = child.wait().map(|status| status.success()).unwrap_or(false);
+        let _ = fs::remove_file(&probe_output);
+
+        if written && succeeded {
+            return Some(Toolchain { clang : PathBuf::from(clang) });
+        }
+    }
+
+    None
+}
+
+pub fn is_buildable(source_file : &str) -> bool {
+    match Path::new(source_file).extension().and_then(|extension| extension.to_str()) {
+        Some("c") | Some("cpp") | Some("cc") => true,
+        _ => false,
+    }
+}
+
+// Compiles `source_file` and returns the module. On failure the error is the compiler's diagnostics.
+// Every build links to a file of its own in cache/build, so a reload that supersedes one still running
+// can't read the other's half written output.
+pub fn build(toolchain : &Toolchain, source_file : &str) -> Result<Vec<u8>, String> {
+    static BUILD_COUNT : AtomicU32 = AtomicU32::new(0);
+
+    let name = Path::new(source_file).file_stem().map_or("script".into(), |stem| stem.to_string_lossy());
+    let build_id = BUILD_COUNT.fetch_add(1, Ordering::Relaxed);
+    let output = Path::new(OUTPUT_DIRECTORY).join(format!("{}-{}-{}.wasm", name, std::process::id(), build_id));
+
+    fs::create_dir_all(OUTPUT_DIRECTORY).map_err(|error| format!("can't create {}: {}", OUTPUT_DIRECTORY, error))?;
+
+    let result = Command::new(&toolchain.clang)
+        .args(compile_flags())
+        .arg("-o").arg(&output)
+        .arg(source_file)
+        .output()
+        .map_err(|error| format!("can't run {}: {}", toolchain.clang.display(), error));
+
+    let bytecode = result.and_then(|result| {
+        if !result.status.success() {
+            return Err(String::from_utf8_lossy(&result.stderr).into_owned());
+        }
+        fs::read(&output).map_err(|error| format!("can't read {}: {}", output.display(), error))
+    });
+    // Also after a failure, the linker may have left a partial file.
+    let _ = fs::remove_file(&output);
+
+    bytecode
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::engine::{ScriptEngine, CompiledScript};
+
+// Compiled scripts on disk, so reloading an unchanged script skips the compiler. Artifacts live in
+// `<directory>/<engine>-<version>/<script>-<module hash>.bin`. A new engine version gets a new folder
+// and the old ones are deleted; a changed script gets a new hash and replaces its previous artifact.
+// Anything that fails to load is deleted and recompiled.
+
+pub fn module_hash(bytecode : &[u8]) -> String {
+    blake2b_simd::Params::new().hash_length(16).hash(bytecode).to_hex().to_string()
+}
+
+#[derive(Clone)]
+pub struct ScriptCache
+{
+    directory : PathBuf,
+}
+
+impl ScriptCache
+{
+    pub fn new<P : Into<PathBuf>>(directory : P) -> ScriptCache {
+        ScriptCache { directory : directory.into() }
+    }
+
+    fn engine_directory(&self, engine : &dyn ScriptEngine) -> PathBuf {
+        self.directory.join(format!("{}-{}", engine.name(), engine.version()))
+    }
+
+    fn artifact_path(&self, engine : &dyn ScriptEngine, script_name : &str, bytecode : &[u8]) -> PathBuf {
+        self.engine_directory(engine).join(format!("{}-{}.bin", script_name, module_hash(bytecode)))
+    }
+
+    pub fn load(&self, engine : &dyn ScriptEngine, script_name : &str, bytecode : &[u8]) -> Option<Box<dyn CompiledScript>> {
+        if !engine.caches_artifacts() {
+            return None;
+        }
+
+        let path = self.artifact_path(engine, script_name, bytecode);
+        let artifact = fs::read(&path).ok()?;
+
+        match engine.load_artifact(&artifact) {
+            Ok(script) => Some(script),
+            Err(error) => {
+                println!("Discarding cached script {}: {}", path.display(), error);
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, engine : &dyn ScriptEngine, script_name : &str, bytecode : &[u8], script : &dyn CompiledScript) {
+        let artifact = match script.artifact() {
+            Some(artifact) => artifact,
+            None => return,
+        };
+
+        let engine_directory = self.engine_directory(engine);
+        self.remove_other_versions(engine);
+        if let Err(error) = fs::create_dir_all(&engine_directory) {
+            println!("Can't create script cache {}: {}", engine_directory.display(), error);
+            return;
+        }
+
+        let path = self.artifact_path(engine, script_name, bytecode);
+        remove_matching(&engine_directory, |name| is_artifact_of(name, script_name));
+
+        // Write next to the final path and rename, a crash mid-write must not leave a truncated artifact.
+        let temp_path = path.with_extension("tmp");
+        let result = fs::write(&temp_path, &artifact).and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(error) = result {
+            println!("Can't write cached script {}: {}", path.display(), error);
+            let _ = fs::remove_file(&temp_path);
+        }
+    }
+
+    fn remove_other_versions(&self, engine : &dyn ScriptEngine) {
+        let prefix = format!("{}-", engine.name());
+        let current = format!("{}-{}", engine.name(), engine.version());
+
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && name != current {
+                let _ = fs::remove_dir_all(entry.path());
+            }
+        }
+    }
+}
+
+// `<script>-<hash>.bin`, checked exactly so that `snow` doesn't match `snow-storm`'s artifacts.
+fn is_artifact_of(file_name : &str, script_name : &str) -> bool {
+    let hash_len = module_hash(&[]).len();
+    let prefix = format!("{}-", script_name);
+
+    file_name.len() == prefix.len() + hash_len + ".bin".len()
+        && file_name.starts_with(&prefix)
+        && file_name.ends_with(".bin")
+        && file_name[prefix.len()..prefix.len() + hash_len].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn remove_matching<F : Fn(&str) -> bool>(directory : &Path, predicate : F) {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        if predicate(&entry.file_name().to_string_lossy()) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
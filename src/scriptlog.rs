@@ -0,0 +1,455 @@
+use std::time::{Duration, Instant};
+
+use log::{log, LogLevel};
+
+use crate::guestmemory::{self, GuestMemory};
+
+// Scripts log through the `log` crate under the `script::<name>` target, so RUST_LOG=script=info
+// shows them. A script logging every frame would drown the terminal, so each script gets a budget of
+// messages per second and the rest are counted and reported once the window ends.
+
+const MAX_MESSAGES_PER_WINDOW : u32 = 20;
+const WINDOW : Duration = Duration::from_secs(1);
+
+const MAX_MESSAGE_LEN : usize = 4096;
+
+pub struct ScriptLog
+{
+    target : String,
+    window_start : Instant,
+    messages_in_window : u32,
+    suppressed : u32,
+}
+
+// Level numbering matches the constants scripts use: 1 = error ... 5 = trace.
+fn log_level(level : i32) -> LogLevel {
+    match level {
+        i32::MIN..=1 => LogLevel::Error,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Info,
+        4 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+impl ScriptLog
+{
+    pub fn new(script_name : &str) -> ScriptLog {
+        ScriptLog { target : format!("script::{}", script_name), window_start : Instant::now(), messages_in_window : 0, suppressed : 0 }
+    }
+
+    pub fn log(&mut self, level : i32, message : &str) {
+        self.flush();
+
+        if self.messages_in_window >= MAX_MESSAGES_PER_WINDOW {
+            self.suppressed += 1;
+            return;
+        }
+        self.messages_in_window += 1;
+
+        log!(target: &self.target, log_level(level), "{}", message);
+    }
+
+    // Starts a new window once the current one has ended and reports what it suppressed. Called after
+    // every tick too, so a script that floods the log and then goes quiet still gets its count reported.
+    pub fn flush(&mut self) {
+        if self.window_start.elapsed() >= WINDOW {
+            self.report_suppressed();
+            self.window_start = Instant::now();
+            self.messages_in_window = 0;
+        }
+    }
+
+    // Reports the messages suppressed so far without waiting for the window to end, for scripts being unloaded.
+    pub fn finish(&mut self) {
+        self.report_suppressed();
+    }
+
+    fn report_suppressed(&mut self) {
+        if self.suppressed > 0 {
+            log!(target: &self.target, LogLevel::Warn, "{} messages suppressed", self.suppressed);
+        }
+        self.suppressed = 0;
+    }
+}
+
+struct Spec
+{
+    left_align : bool,
+    plus_sign : bool,
+    space_sign : bool,
+    zero_pad : bool,
+    alternate : bool,
+    width : usize,
+    precision : Option<usize>,
+    long_long : bool,
+}
+
+fn pad(spec : &Spec, body : String, numeric : bool) -> String {
+    let len = body.chars().count();
+    if len >= spec.width {
+        return body;
+    }
+
+    let fill = spec.width - len;
+    if spec.left_align {
+        format!("{}{}", body, " ".repeat(fill))
+    }
+    else if spec.zero_pad && numeric {
+        // Zeros go after the sign and the 0x prefix.
+        let mut prefix = if body.starts_with('-') || body.starts_with('+') || body.starts_with(' ') { 1 } else { 0 };
+        if body[prefix..].starts_with("0x") || body[prefix..].starts_with("0X") {
+            prefix += 2;
+        }
+        let (prefix, digits) = body.split_at(prefix);
+        format!("{}{}{}", prefix, "0".repeat(fill), digits)
+    }
+    else {
+        format!("{}{}", " ".repeat(fill), body)
+    }
+}
+
+fn signed(spec : &Spec, negative : bool, digits : String) -> String {
+    let sign = if negative { "-" } else if spec.plus_sign { "+" } else if spec.space_sign { " " } else { "" };
+    format!("{}{}", sign, digits)
+}
+
+fn format_exponent(value : f64, precision : usize, upper : bool) -> String {
+    // Rust prints `1.5e2`, C wants `1.5e+02`.
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+    let exponent : i32 = exponent[1..].parse().unwrap();
+    let e = if upper { 'E' } else { 'e' };
+    format!("{}{}{}{:02}", mantissa, e, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+fn format_general(value : f64, precision : usize, alternate : bool, upper : bool) -> String {
+    let precision = if precision == 0 { 1 } else { precision };
+    let exponent = if value == 0.0 { 0 } else { value.abs().log10().floor() as i32 };
+
+    let formatted = if exponent < -4 || exponent >= precision as i32 {
+        format_exponent(value, precision - 1, upper)
+    }
+    else {
+        format!("{:.*}", (precision as i32 - 1 - exponent).max(0) as usize, value)
+    };
+
+    if alternate || !formatted.contains('.') {
+        return formatted;
+    }
+
+    // %g drops trailing zeros from the fraction.
+    let (mantissa, exponent) = match formatted.find(|c| c == 'e' || c == 'E') {
+        Some(i) => formatted.split_at(i),
+        None => (&formatted[..], ""),
+    };
+    format!("{}{}", mantissa.trim_end_matches('0').trim_end_matches('.'), exponent)
+}
+
+// Expands a printf-style format string. `args_ptr` points at the argument buffer clang builds for
+// variadic calls on wasm32: each argument stored at its natural alignment, ints and pointers as 4
+// bytes, long long and double as 8.
+pub fn format_printf<M : GuestMemory + ?Sized>(memory : &M, format : &str, args_ptr : u32) -> String {
+    let mut out = String::new();
+    let mut args = args_ptr as usize;
+
+    let next_u32 = |args : &mut usize| -> u32 {
+        *args = (*args + 3) & !3;
+        let value = guestmemory::read_u32(memory, *args).unwrap_or(0);
+        *args += 4;
+        value
+    };
+    let next_u64 = |args : &mut usize| -> u64 {
+        *args = (*args + 7) & !7;
+        let value = guestmemory::read_u64(memory, *args).unwrap_or(0);
+        *args += 8;
+        value
+    };
+
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut spec = Spec { left_align : false, plus_sign : false, space_sign : false, zero_pad : false, alternate : false, width : 0, precision : None, long_long : false };
+
+        while let Some(&flag) = chars.peek() {
+            match flag {
+                '-' => spec.left_align = true,
+                '+' => spec.plus_sign = true,
+                ' ' => spec.space_sign = true,
+                '0' => spec.zero_pad = true,
+                '#' => spec.alternate = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'*') {
+            chars.next();
+            // A negative width from `*` means left-aligned.
+            let width = next_u32(&mut args) as i32;
+            spec.left_align |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        }
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            spec.width = spec.width * 10 + digit as usize;
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'.') {
+            chars.next();
+            let mut precision = 0;
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                precision = (next_u32(&mut args) as i32).max(0) as usize;
+            }
+            while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                precision = precision * 10 + digit as usize;
+                chars.next();
+            }
+            spec.precision = Some(precision);
+        }
+
+        // Length modifiers. Only `ll` (and `j`) change the argument size on wasm32.
+        let mut longs = 0;
+        while let Some(&modifier) = chars.peek() {
+            match modifier {
+                'l' => longs += 1,
+                'j' => longs = 2,
+                'h' | 'z' | 't' | 'L' => (),
+                _ => break,
+            }
+            chars.next();
+        }
+        spec.long_long = longs >= 2;
+
+        let conversion = match chars.next() {
+            Some(conversion) => conversion,
+            None => break,
+        };
+
+        let formatted = match conversion {
+            '%' => "%".to_string(),
+            'd' | 'i' => {
+                let value = if spec.long_long { next_u64(&mut args) as i64 } else { next_u32(&mut args) as i32 as i64 };
+                let digits = value.unsigned_abs().to_string();
+                pad(&spec, signed(&spec, value < 0, digits), true)
+            }
+            'u' | 'x' | 'X' | 'o' => {
+                let value = if spec.long_long { next_u64(&mut args) } else { next_u32(&mut args) as u64 };
+                let digits = match conversion {
+                    'u' => value.to_string(),
+                    'x' => format!("{}{:x}", if spec.alternate && value != 0 { "0x" } else { "" }, value),
+                    'X' => format!("{}{:X}", if spec.alternate && value != 0 { "0X" } else { "" }, value),
+                    _ => format!("{}{:o}", if spec.alternate { "0" } else { "" }, value),
+                };
+                pad(&spec, digits, true)
+            }
+            'c' => {
+                let value = next_u32(&mut args) as u8 as char;
+                pad(&spec, value.to_string(), false)
+            }
+            'p' => {
+                let value = next_u32(&mut args);
+                pad(&spec, format!("0x{:x}", value), false)
+            }
+            's' => {
+                let ptr = next_u32(&mut args);
+                let max_len = spec.precision.unwrap_or(MAX_MESSAGE_LEN);
+                pad(&spec, guestmemory::read_c_string(memory, ptr, max_len), false)
+            }
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                let value = f64::from_bits(next_u64(&mut args));
+                let precision = spec.precision.unwrap_or(6);
+                let body = if value.is_nan() {
+                    "nan".to_string()
+                }
+                else if value.is_infinite() {
+                    "inf".to_string()
+                }
+                else {
+                    match conversion {
+                        'f' | 'F' => format!("{:.*}", precision, value.abs()),
+                        'e' | 'E' => format_exponent(value.abs(), precision, conversion == 'E'),
+                        _ => format_general(value.abs(), precision, spec.alternate, conversion == 'G'),
+                    }
+                };
+                let body = if conversion.is_uppercase() { body.to_uppercase() } else { body };
+                pad(&spec, signed(&spec, value.is_sign_negative() && !value.is_nan(), body), value.is_finite())
+            }
+            other => {
+                // Unknown conversion, print it verbatim so the mistake is visible.
+                format!("%{}", other)
+            }
+        };
+        out.push_str(&formatted);
+
+        if out.len() > MAX_MESSAGE_LEN {
+            break;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::cell::Cell;
+
+    const MEMORY_SIZE : usize = 256;
+    const ARGS : u32 = 16;
+    const STRINGS : u32 = 160;
+
+    // Guest memory with a vararg buffer at ARGS laid out as clang does for wasm32. Padding is filled
+    // with 0xee so reading an argument at the wrong offset shows.
+    struct Guest
+    {
+        bytes : Vec<u8>,
+        args_end : usize,
+        strings_end : usize,
+    }
+
+    impl Guest
+    {
+        fn new() -> Guest {
+            Guest { bytes : vec![0xee; MEMORY_SIZE], args_end : ARGS as usize, strings_end : STRINGS as usize }
+        }
+
+        fn arg(&mut self, bytes : &[u8]) -> &mut Guest {
+            let start = (self.args_end + bytes.len() - 1) / bytes.len() * bytes.len();
+            self.bytes[start .. start + bytes.len()].copy_from_slice(bytes);
+            self.args_end = start + bytes.len();
+            self
+        }
+
+        fn int(&mut self, value : i32) -> &mut Guest {
+            self.arg(&value.to_le_bytes())
+        }
+
+        fn long_long(&mut self, value : i64) -> &mut Guest {
+            self.arg(&value.to_le_bytes())
+        }
+
+        fn double(&mut self, value : f64) -> &mut Guest {
+            self.arg(&value.to_bits().to_le_bytes())
+        }
+
+        // Copies `value` and a NUL into memory and passes a pointer to it.
+        fn string(&mut self, value : &str) -> &mut Guest {
+            let ptr = self.strings_end;
+            self.bytes[ptr .. ptr + value.len()].copy_from_slice(value.as_bytes());
+            self.bytes[ptr + value.len()] = 0;
+            self.strings_end += value.len() + 1;
+            self.int(ptr as i32)
+        }
+
+        fn format(&self, format : &str) -> String {
+            self.format_at(format, ARGS)
+        }
+
+        fn format_at(&self, format : &str, args_ptr : u32) -> String {
+            let memory : Vec<Cell<u8>> = self.bytes.iter().cloned().map(Cell::new).collect();
+            format_printf(&memory[..], format, args_ptr)
+        }
+    }
+
+    #[test]
+    fn suppressed_messages_are_flushed() {
+        let mut log = ScriptLog::new("test");
+        for _ in 0 .. MAX_MESSAGES_PER_WINDOW + 5 {
+            log.log(5, "message");
+        }
+        assert_eq!(log.messages_in_window, MAX_MESSAGES_PER_WINDOW);
+        assert_eq!(log.suppressed, 5);
+
+        // Within the window the count is kept.
+        log.flush();
+        assert_eq!(log.suppressed, 5);
+
+        // Once it ends, flushing reports it without waiting for another message.
+        log.window_start -= WINDOW;
+        log.flush();
+        assert_eq!(log.suppressed, 0);
+        assert_eq!(log.messages_in_window, 0);
+
+        log.messages_in_window = MAX_MESSAGES_PER_WINDOW;
+        log.log(5, "message");
+        assert_eq!(log.suppressed, 1);
+        log.finish();
+        assert_eq!(log.suppressed, 0);
+    }
+
+    #[test]
+    fn integers() {
+        let mut guest = Guest::new();
+        guest.int(-42).int(17).int(-1).int(255).int(255).int(255).int(8).int(65).int(0x1234);
+        assert_eq!(guest.format("%d %i %u %x %X %#x %o %c %p"), "-42 17 4294967295 ff FF 0xff 10 A 0x1234");
+    }
+
+    #[test]
+    fn widths_and_flags() {
+        let mut guest = Guest::new();
+        guest.int(42).int(42).int(-42).int(42).int(42).int(6).int(42).int(4).int(42).int(-4).int(42).int(255).string("hello").string("hello");
+        assert_eq!(guest.format("[%5d][%-5d][%05d][%+d][% d][%*d][%-*d][%*d][%05x][%.3s][%8s]"),
+                   "[   42][42   ][-0042][+42][ 42][    42][42  ][42  ][000ff][hel][   hello]");
+    }
+
+    #[test]
+    fn percent_takes_no_argument() {
+        let mut guest = Guest::new();
+        guest.int(5);
+        assert_eq!(guest.format("100%% done %d%%"), "100% done 5%");
+        // Unknown conversions are printed as they are, a lone % at the end is dropped.
+        assert_eq!(guest.format("%q %d %"), "%q 5 ");
+    }
+
+    #[test]
+    fn doubles_are_8_byte_aligned() {
+        let mut guest = Guest::new();
+        guest.int(7).double(1.5).int(9);
+        assert_eq!(guest.args_end, 36);
+        assert_eq!(guest.format("%d %f %d"), "7 1.500000 9");
+
+        let mut guest = Guest::new();
+        guest.double(-2.5).int(3).double(0.75).double(0.75).double(2.0);
+        assert_eq!(guest.format("%.2f %d %5.1f|%-8.3f|%+.0f"), "-2.50 3   0.8|0.750   |+2");
+    }
+
+    #[test]
+    fn float_conversions() {
+        let mut guest = Guest::new();
+        guest.double(12345.678).double(0.5).double(12345.678).double(0.00001).double(1e20).double(f64::NAN).double(f64::NEG_INFINITY);
+        assert_eq!(guest.format("%e %E %g %g %G %f %f"), "1.234568e+04 5.000000E-01 12345.7 1e-05 1E+20 nan -inf");
+    }
+
+    #[test]
+    fn long_long_is_8_byte_aligned() {
+        let mut guest = Guest::new();
+        guest.int(1).long_long(-5_000_000_000).long_long(-1).int(3);
+        assert_eq!(guest.format("%d %lld %llu %d"), "1 -5000000000 18446744073709551615 3");
+        // Plain %ld is 4 bytes on wasm32.
+        let mut guest = Guest::new();
+        guest.int(-1).int(2);
+        assert_eq!(guest.format("%ld %lu"), "-1 2");
+    }
+
+    #[test]
+    fn bad_pointers() {
+        let mut guest = Guest::new();
+        guest.int(-16).int(MEMORY_SIZE as i32).int((MEMORY_SIZE - 3) as i32);
+        guest.bytes[MEMORY_SIZE - 3 ..].copy_from_slice(b"abc");
+        // Out of memory strings are empty, one running into the end of memory stops there.
+        assert_eq!(guest.format("[%s][%s][%s]"), "[][][abc]");
+
+        // Arguments past the end of memory read as zero.
+        let mut guest = Guest::new();
+        guest.bytes[MEMORY_SIZE - 4 ..].copy_from_slice(&9i32.to_le_bytes());
+        assert_eq!(guest.format_at("%d %d %f", (MEMORY_SIZE - 4) as u32), "9 0 0.000000");
+        assert_eq!(guest.format_at("%d", u32::MAX - 2), "0");
+    }
+}
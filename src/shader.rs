@@ -0,0 +1,13 @@
+use hal::{Device};
+
+use std::fs;
+
+use crate::backenderror::BackendDevice;
+
+// Compiles a GLSL file from data/ at runtime.
+pub fn load_shader(device : &BackendDevice, path : &str, ty : glsl_to_spirv::ShaderType) -> <back::Backend as hal::Backend>::ShaderModule {
+    let glsl = fs::read_to_string(path).unwrap();
+    let file = glsl_to_spirv::compile(&glsl, ty).unwrap();
+    let spirv : Vec<u32> = hal::read_spirv(file).unwrap();
+    unsafe { device.create_shader_module(&spirv) }.unwrap()
+}
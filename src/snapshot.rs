@@ -0,0 +1,471 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use nalgebra_glm as glm;
+use glm::{Vec3, vec3};
+use serde::{Serialize, Deserialize};
+
+use crate::particles::SimParticle;
+use crate::world::{Particle, WorldState};
+
+// Camera and particles of a WorldState at one point in time. Written as JSON for reading and diffing, or
+// in a compact binary form for tools; either loads back into a WorldState, which can then be drawn
+// without a script. Emitters and script state are not part of it.
+//
+// The binary form is little endian: magic, version, time, camera (9 f32), then the script particles
+// (count u32, position, size, color) and the simulated ones (count u32, SimParticle's fields in order).
+
+const MAGIC : &[u8; 4] = b"WSSN";
+const VERSION : u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraSnapshot
+{
+    pub position : [f32; 3],
+    pub lookat : [f32; 3],
+    pub up : [f32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParticleSnapshot
+{
+    pub position : [f32; 3],
+    pub size : f32,
+    pub color : u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimParticleSnapshot
+{
+    pub position : [f32; 3],
+    pub velocity : [f32; 3],
+    pub gravity : [f32; 3],
+    pub drag : f32,
+    pub age : f32,
+    pub lifetime : f32,
+    pub size : f32,
+    pub color : u32,
+    pub emitter : i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot
+{
+    pub version : u32,
+    // Simulation time.
+    pub time : f32,
+    pub camera : CameraSnapshot,
+    // Submitted by the script in the last tick.
+    pub particles : Vec<ParticleSnapshot>,
+    // Simulated by the host.
+    pub simulated : Vec<SimParticleSnapshot>,
+}
+
+pub enum SnapshotError
+{
+    Io(io::Error),
+    Json(serde_json::Error),
+    Format(String),
+    // JSON has no NaN or infinity, serde_json would write null and fail to read it back.
+    NotFinite(String),
+}
+
+impl fmt::Display for SnapshotError
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::Json(error) => write!(f, "invalid snapshot: {}", error),
+            SnapshotError::Format(error) => write!(f, "invalid snapshot: {}", error),
+            SnapshotError::NotFinite(field) => write!(f, "{} is not a finite number, only the binary form can hold it", field),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError
+{
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError
+{
+    fn from(error: serde_json::Error) -> Self {
+        SnapshotError::Json(error)
+    }
+}
+
+fn to_array(v : &Vec3) -> [f32; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn to_vec3(v : &[f32; 3]) -> Vec3 {
+    vec3(v[0], v[1], v[2])
+}
+
+impl Snapshot
+{
+    pub fn capture(world : &WorldState) -> Snapshot {
+        Snapshot {
+            version : VERSION,
+            time : world.time,
+            camera : CameraSnapshot {
+                position : to_array(&world.camera_position),
+                lookat : to_array(&world.camera_lookat),
+                up : to_array(&world.camera_up),
+            },
+            particles : world.particles_list.iter().map(|p| ParticleSnapshot {
+                position : to_array(&p.position),
+                size : p.size,
+                color : p.color,
+            }).collect(),
+            simulated : world.particle_system.particles().iter().map(|p| SimParticleSnapshot {
+                position : to_array(&p.position),
+                velocity : to_array(&p.velocity),
+                gravity : to_array(&p.gravity),
+                drag : p.drag,
+                age : p.age,
+                lifetime : p.lifetime,
+                size : p.size,
+                color : p.color,
+                emitter : p.emitter,
+            }).collect(),
+        }
+    }
+
+    // Replaces the world's camera and particles. Emitters are left alone.
+    pub fn restore(&self, world : &mut WorldState) {
+        world.time = self.time;
+        world.camera_position = to_vec3(&self.camera.position);
+        world.camera_lookat = to_vec3(&self.camera.lookat);
+        world.camera_up = to_vec3(&self.camera.up);
+
+        world.particles_list = self.particles.iter().map(|p| Particle {
+            position : to_vec3(&p.position),
+            size : p.size,
+            color : p.color,
+        }).collect();
+
+        world.particle_system.restore_particles(self.simulated.iter().map(|p| SimParticle {
+            position : to_vec3(&p.position),
+            velocity : to_vec3(&p.velocity),
+            gravity : to_vec3(&p.gravity),
+            drag : p.drag,
+            age : p.age,
+            lifetime : p.lifetime,
+            size : p.size,
+            color : p.color,
+            emitter : p.emitter,
+        }).collect());
+    }
+
+    // The first field that is NaN or infinite, as `simulated[3].velocity`.
+    fn not_finite(&self) -> Option<String> {
+        let finite = |values : &[f32]| values.iter().all(|value| value.is_finite());
+        if !finite(&[self.time]) {
+            return Some("time".to_string());
+        }
+        if !finite(&self.camera.position) || !finite(&self.camera.lookat) || !finite(&self.camera.up) {
+            return Some("camera".to_string());
+        }
+        if let Some(index) = self.particles.iter().position(|p| !finite(&p.position) || !finite(&[p.size])) {
+            return Some(format!("particles[{}]", index));
+        }
+        self.simulated.iter().enumerate().find_map(|(index, p)| {
+            let fields : [(&str, &[f32]); 7] = [("position", &p.position), ("velocity", &p.velocity), ("gravity", &p.gravity),
+                                                ("drag", &[p.drag]), ("age", &[p.age]), ("lifetime", &[p.lifetime]), ("size", &[p.size])];
+            fields.iter().find(|(_, values)| !finite(values)).map(|(name, _)| format!("simulated[{}].{}", index, name))
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String, SnapshotError> {
+        if let Some(field) = self.not_finite() {
+            return Err(SnapshotError::NotFinite(field));
+        }
+        Ok(serde_json::to_string_pretty(self).expect("snapshots always serialize"))
+    }
+
+    pub fn from_json(text : &str) -> Result<Snapshot, SnapshotError> {
+        let snapshot : Snapshot = serde_json::from_str(text)?;
+        if snapshot.version != VERSION {
+            return Err(SnapshotError::Format(format!("unsupported version {}", snapshot.version)));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn write_binary<W : Write>(&self, out : &mut W) -> io::Result<()> {
+        let mut words : Vec<u32> = Vec::with_capacity(12 + self.particles.len() * 5 + self.simulated.len() * 15);
+        let mut add = |values : &[f32]| words.extend(values.iter().map(|v| v.to_bits()));
+
+        add(&[self.time]);
+        add(&self.camera.position);
+        add(&self.camera.lookat);
+        add(&self.camera.up);
+
+        words.push(self.particles.len() as u32);
+        for p in &self.particles {
+            words.extend(p.position.iter().map(|v| v.to_bits()));
+            words.push(p.size.to_bits());
+            words.push(p.color);
+        }
+
+        words.push(self.simulated.len() as u32);
+        for p in &self.simulated {
+            for v in [p.position, p.velocity, p.gravity].iter() {
+                words.extend(v.iter().map(|x| x.to_bits()));
+            }
+            words.extend([p.drag, p.age, p.lifetime, p.size].iter().map(|x| x.to_bits()));
+            words.push(p.color);
+            words.push(p.emitter as u32);
+        }
+
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        for word in words {
+            out.write_all(&word.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_binary<R : Read>(input : &mut R) -> Result<Snapshot, SnapshotError> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+
+        if bytes.len() < 8 || &bytes[0..4] != MAGIC {
+            return Err(SnapshotError::Format("not a snapshot".to_string()));
+        }
+
+        let mut words = Words { bytes : &bytes[4..] };
+        let version = words.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::Format(format!("unsupported version {}", version)));
+        }
+
+        let time = words.f32()?;
+        let camera = CameraSnapshot { position : words.vec3()?, lookat : words.vec3()?, up : words.vec3()? };
+
+        // Counts are checked against the remaining bytes by the reads, not trusted for allocation.
+        let count = words.u32()? as usize;
+        let mut particles = Vec::with_capacity(count.min(words.remaining() / 5));
+        for _ in 0..count {
+            particles.push(ParticleSnapshot { position : words.vec3()?, size : words.f32()?, color : words.u32()? });
+        }
+
+        let count = words.u32()? as usize;
+        let mut simulated = Vec::with_capacity(count.min(words.remaining() / 15));
+        for _ in 0..count {
+            simulated.push(SimParticleSnapshot {
+                position : words.vec3()?,
+                velocity : words.vec3()?,
+                gravity : words.vec3()?,
+                drag : words.f32()?,
+                age : words.f32()?,
+                lifetime : words.f32()?,
+                size : words.f32()?,
+                color : words.u32()?,
+                emitter : words.u32()? as i32,
+            });
+        }
+
+        Ok(Snapshot { version : version, time : time, camera : camera, particles : particles, simulated : simulated })
+    }
+
+    // JSON for .json files, the binary form otherwise.
+    pub fn save(&self, path : &str) -> Result<(), SnapshotError> {
+        if is_json(path) {
+            fs::write(path, self.to_json()?)?;
+        } else {
+            let mut out = BufWriter::new(File::create(path)?);
+            self.write_binary(&mut out)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path : &str) -> Result<Snapshot, SnapshotError> {
+        if is_json(path) {
+            Snapshot::from_json(&fs::read_to_string(path)?)
+        } else {
+            Snapshot::read_binary(&mut BufReader::new(File::open(path)?))
+        }
+    }
+}
+
+// Little endian words from the binary form.
+struct Words<'a>
+{
+    bytes : &'a [u8],
+}
+
+impl<'a> Words<'a>
+{
+    fn remaining(&self) -> usize {
+        self.bytes.len() / 4
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        if self.bytes.len() < 4 {
+            return Err(SnapshotError::Format("truncated".to_string()));
+        }
+        let word = u32::from_le_bytes([self.bytes[0], self.bytes[1], self.bytes[2], self.bytes[3]]);
+        self.bytes = &self.bytes[4..];
+        Ok(word)
+    }
+
+    fn f32(&mut self) -> Result<f32, SnapshotError> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn vec3(&mut self) -> Result<[f32; 3], SnapshotError> {
+        Ok([self.f32()?, self.f32()?, self.f32()?])
+    }
+}
+
+fn is_json(path : &str) -> bool {
+    Path::new(path).extension().map_or(false, |extension| extension == "json")
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            version : VERSION,
+            time : 12.5,
+            camera : CameraSnapshot { position : [0.0, 1.0, -10.0], lookat : [0.0, 0.0, 0.0], up : [0.0, 1.0, 0.0] },
+            particles : vec![
+                ParticleSnapshot { position : [1.0, 2.0, 3.0], size : 0.25, color : 0xff00_80ff },
+                ParticleSnapshot { position : [-1.0, 0.5, 1e-3], size : 1.0, color : 0 },
+            ],
+            simulated : vec![SimParticleSnapshot {
+                position : [0.1, 0.2, 0.3],
+                velocity : [1.0, -2.0, 3.0],
+                gravity : [0.0, -9.81, 0.0],
+                drag : 0.5,
+                age : 0.75,
+                lifetime : 2.0,
+                size : 0.125,
+                color : 0xffff_ffff,
+                emitter : -1,
+            }],
+        }
+    }
+
+    fn binary(snapshot : &Snapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write_binary(&mut bytes).unwrap();
+        bytes
+    }
+
+    fn read(bytes : &[u8]) -> Result<Snapshot, SnapshotError> {
+        Snapshot::read_binary(&mut &bytes[..])
+    }
+
+    fn format_error(result : Result<Snapshot, SnapshotError>) -> String {
+        match result {
+            Err(SnapshotError::Format(error)) => error,
+            Err(error) => panic!("expected a format error, got {}", error),
+            Ok(_) => panic!("expected a format error"),
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let json = snapshot().to_json().ok().unwrap();
+        assert_eq!(Snapshot::from_json(&json).ok(), Some(snapshot()));
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        assert_eq!(read(&binary(&snapshot())).ok(), Some(snapshot()));
+
+        let empty = Snapshot { particles : Vec::new(), simulated : Vec::new(), ..snapshot() };
+        assert_eq!(read(&binary(&empty)).ok(), Some(empty));
+    }
+
+    #[test]
+    fn binary_layout() {
+        let bytes = binary(&snapshot());
+        // Header, time and camera, two script particles, one simulated one, and a count before each list.
+        assert_eq!(bytes.len(), 8 + 4 * (10 + 1 + 2 * 5 + 1 + 15));
+        assert_eq!(&bytes[0 .. 4], b"WSSN");
+        assert_eq!(&bytes[4 .. 8], &VERSION.to_le_bytes());
+        assert_eq!(&bytes[8 .. 12], &12.5f32.to_le_bytes());
+
+        let word = |index : usize| u32::from_le_bytes([bytes[8 + index * 4], bytes[9 + index * 4], bytes[10 + index * 4], bytes[11 + index * 4]]);
+        assert_eq!(word(10), 2);
+        assert_eq!(word(15), 0xff00_80ff);
+        assert_eq!(word(21), 1);
+        assert_eq!(word(36), (-1i32) as u32);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = binary(&snapshot());
+        bytes[4 .. 8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(format_error(read(&bytes)), format!("unsupported version {}", VERSION + 1));
+
+        let json = snapshot().to_json().ok().unwrap().replacen(&format!("\"version\": {}", VERSION), &format!("\"version\": {}", VERSION + 1), 1);
+        assert_eq!(format_error(Snapshot::from_json(&json)), format!("unsupported version {}", VERSION + 1));
+
+        assert_eq!(format_error(read(b"WSRC\x01\x00\x00\x00")), "not a snapshot");
+        assert_eq!(format_error(read(b"WSS")), "not a snapshot");
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = binary(&snapshot());
+        // Every cut, whether between words or inside one, is an error rather than a partial snapshot.
+        for length in 8 .. bytes.len() {
+            assert_eq!(format_error(read(&bytes[.. length])), "truncated", "cut at {}", length);
+        }
+    }
+
+    #[test]
+    fn count_beyond_the_data() {
+        let mut bytes = binary(&Snapshot { particles : Vec::new(), simulated : Vec::new(), ..snapshot() });
+        // A script particle count no file could hold; it must fail without reserving memory for it.
+        bytes[48 .. 52].copy_from_slice(&u32::max_value().to_le_bytes());
+        assert_eq!(format_error(read(&bytes)), "truncated");
+
+        let mut bytes = binary(&snapshot());
+        let simulated_count = bytes.len() - 4 * 16;
+        bytes[simulated_count .. simulated_count + 4].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(format_error(read(&bytes)), "truncated");
+    }
+
+    #[test]
+    fn words() {
+        let mut words = Words { bytes : &[1, 0, 0, 0, 0, 0, 0x80, 0x3f, 0xff, 0xff] };
+        assert_eq!(words.remaining(), 2);
+        assert_eq!(words.u32().ok(), Some(1));
+        assert_eq!(words.f32().ok(), Some(1.0));
+        // Two bytes left aren't a word.
+        assert_eq!(words.remaining(), 0);
+        assert!(words.u32().is_err());
+    }
+
+    #[test]
+    fn non_finite_values() {
+        let mut with_nan = snapshot();
+        with_nan.simulated[0].velocity[1] = f32::NAN;
+        match with_nan.to_json() {
+            Err(SnapshotError::NotFinite(field)) => assert_eq!(field, "simulated[0].velocity"),
+            _ => panic!("NaN written as JSON"),
+        }
+
+        let mut with_infinity = snapshot();
+        with_infinity.time = f32::INFINITY;
+        assert!(with_infinity.to_json().is_err());
+
+        // The binary form keeps them.
+        let read_back = read(&binary(&with_nan)).ok().unwrap();
+        assert!(read_back.simulated[0].velocity[1].is_nan());
+        assert_eq!(read(&binary(&with_infinity)).ok(), Some(with_infinity));
+    }
+}
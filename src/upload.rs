@@ -1,41 +1,70 @@
-use hal::{
-    buffer,
-    memory as m
-};
 use hal::{Device};
-use crate::backenderror::{BackendError, BackendMemory, BackendBuffer, BackendDevice};
+use crate::allocator::{Allocation, GpuAllocator, MemoryUsage};
+use crate::backenderror::{BackendError, BackendBuffer, BackendDevice};
 
 pub struct UploadBuffer
 {
     pub size : u64,
     pub device_buffer : BackendBuffer,
-    pub device_memory : BackendMemory
+    allocation : Allocation
 }
 
 impl UploadBuffer
 {
-    pub fn new(device : &BackendDevice, adapter_mem : &hal::adapter::MemoryProperties, size : u64, usage : hal::buffer::Usage) -> Result<UploadBuffer, BackendError> {
+    pub fn new(device : &BackendDevice, allocator : &mut GpuAllocator, size : u64, usage : hal::buffer::Usage, memory_usage : MemoryUsage)
+        -> Result<UploadBuffer, BackendError> {
         let mut buffer = unsafe { device.create_buffer(size, usage) }?;
 
         let buffer_req = unsafe { device.get_buffer_requirements(&buffer) };
 
-        let upload_type = adapter_mem.memory_types
-            .iter()
-            .enumerate()
-            .position(|(id, mem_type)| {
-                // type_mask is a bit field where each bit represents a memory type. If the bit is set
-                // to 1 it means we can use that type for our buffer. So this code finds the first
-                // memory type that has a `1` (or, is allowed), and is visible to the CPU.
-                buffer_req.type_mask & (1 << id) != 0
-                    && mem_type.properties.contains(m::Properties::CPU_VISIBLE)
-            })
-            .unwrap()
-            .into();
+        let allocation = match allocator.allocate(device, &buffer_req, memory_usage) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { device.destroy_buffer(buffer) };
+                return Err(error);
+            }
+        };
 
-        let buffer_memory = unsafe { device.allocate_memory(upload_type, buffer_req.size) }?;
+        if let Err(error) = unsafe { device.bind_buffer_memory(allocation.memory(), allocation.offset(), &mut buffer) } {
+            unsafe { device.destroy_buffer(buffer) };
+            allocator.free(device, allocation);
+            return Err(error.into());
+        }
 
-        unsafe { device.bind_buffer_memory(&buffer_memory, 0, &mut buffer) }?;
+        Ok(UploadBuffer { size : size, device_buffer : buffer, allocation : allocation })
+    }
+
+    fn mapped_bytes<T>(&self, count : usize) -> (*mut T, u64) {
+        let bytes = (count * std::mem::size_of::<T>()) as u64;
+        assert!(bytes <= self.size, "{} bytes don't fit a buffer of {}", bytes, self.size);
+
+        let pointer = self.allocation.mapping().expect("Buffer memory is not host visible").as_ptr();
+        assert_eq!(pointer as usize % std::mem::align_of::<T>(), 0);
+        (pointer as *mut T, bytes)
+    }
+
+    // Lets `fill` write the first `count` elements of the buffer in place. The GPU must not be using them.
+    pub fn write_with<T : Copy, R, F : FnOnce(&mut [T]) -> R>(&mut self, device : &BackendDevice, count : usize, fill : F) -> R {
+        let (pointer, bytes) = self.mapped_bytes::<T>(count);
+        let result = fill(unsafe { std::slice::from_raw_parts_mut(pointer, count) });
+        self.allocation.flush(device, bytes);
+        result
+    }
 
-        Ok(UploadBuffer { size : size, device_buffer : buffer, device_memory : buffer_memory })
+    pub fn write<T : Copy>(&mut self, device : &BackendDevice, data : &[T]) {
+        self.write_with(device, data.len(), |mapped| mapped.copy_from_slice(data));
     }
-}
\ No newline at end of file
+
+    // Passes the first `count` elements to `read`. Whatever wrote them must have completed.
+    pub fn read_with<T : Copy, R, F : FnOnce(&[T]) -> R>(&self, device : &BackendDevice, count : usize, read : F) -> R {
+        let (pointer, bytes) = self.mapped_bytes::<T>(count);
+        self.allocation.invalidate(device, bytes);
+        read(unsafe { std::slice::from_raw_parts(pointer, count) })
+    }
+
+    // The GPU must be done with the buffer.
+    pub fn destroy(self, device : &BackendDevice, allocator : &mut GpuAllocator) {
+        unsafe { device.destroy_buffer(self.device_buffer) };
+        allocator.free(device, self.allocation);
+    }
+}
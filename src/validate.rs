@@ -0,0 +1,365 @@
+use std::fmt;
+
+use wasmparser::{ModuleReader, SectionCode, ImportSectionEntryType, ExternalKind, Type};
+
+use crate::hostapi::{self, ValueType, FunctionSignature, ScriptExport, HOST_NAMESPACE};
+
+// Checks a module's imports and exports against the host API table before instantiation, so a
+// script with a typo in an import or a wrong `tick` signature gets a readable error instead of a
+// generic link error from the engine.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleSignature
+{
+    pub params : Vec<Option<ValueType>>,
+    pub returns : Vec<Option<ValueType>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ImportKind
+{
+    Function(ModuleSignature),
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleImport
+{
+    pub module : String,
+    pub name : String,
+    pub kind : ImportKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleExport
+{
+    pub name : String,
+    // None for exports that are not functions (memories, tables, globals).
+    pub signature : Option<ModuleSignature>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModuleInterface
+{
+    pub imports : Vec<ModuleImport>,
+    pub exports : Vec<ModuleExport>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Problem
+{
+    Malformed(String),
+    UnknownImport { module : String, name : String },
+    UnsupportedImport { module : String, name : String },
+    MismatchedImport { name : String, expected : FunctionSignature, found : ModuleSignature },
+    MissingExport { name : String },
+    MismatchedExport { name : String, expected : FunctionSignature, found : Option<ModuleSignature> },
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationError
+{
+    pub problems : Vec<Problem>,
+}
+
+fn value_type(ty : Type) -> Option<ValueType> {
+    match ty {
+        Type::I32 => Some(ValueType::I32),
+        Type::I64 => Some(ValueType::I64),
+        Type::F32 => Some(ValueType::F32),
+        Type::F64 => Some(ValueType::F64),
+        _ => None,
+    }
+}
+
+impl ModuleSignature
+{
+    pub fn matches(&self, expected : &FunctionSignature) -> bool {
+        fn same(found : &[Option<ValueType>], expected : &[ValueType]) -> bool {
+            found.len() == expected.len() && found.iter().zip(expected.iter()).all(|(f, e)| *f == Some(*e))
+        }
+        same(&self.params, expected.params) && same(&self.returns, expected.returns)
+    }
+
+    // The current signature or the legacy one.
+    pub fn matches_export(&self, expected : &ScriptExport) -> bool {
+        self.matches(&expected.signature) || expected.legacy_signature.as_ref().map_or(false, |legacy| self.matches(legacy))
+    }
+}
+
+fn signature_at(types : &[ModuleSignature], index : u32) -> Result<ModuleSignature, String> {
+    types.get(index as usize).cloned().ok_or_else(|| format!("type index {} out of range", index))
+}
+
+fn parse_error(error : wasmparser::BinaryReaderError) -> String {
+    format!("{} at offset {}", error.message(), error.offset())
+}
+
+pub fn read_interface(bytecode : &[u8]) -> Result<ModuleInterface, String> {
+    let mut types = Vec::new();
+    let mut functions = Vec::new();
+    let mut interface = ModuleInterface { imports : Vec::new(), exports : Vec::new() };
+    let mut export_indices = Vec::new();
+
+    let mut reader = ModuleReader::new(bytecode).map_err(parse_error)?;
+    while !reader.eof() {
+        let section = reader.read().map_err(parse_error)?;
+        match section.code {
+            SectionCode::Type => {
+                for ty in section.get_type_section_reader().map_err(parse_error)? {
+                    let ty = ty.map_err(parse_error)?;
+                    types.push(ModuleSignature {
+                        params : ty.params.iter().map(|t| value_type(*t)).collect(),
+                        returns : ty.returns.iter().map(|t| value_type(*t)).collect(),
+                    });
+                }
+            }
+            SectionCode::Import => {
+                for import in section.get_import_section_reader().map_err(parse_error)? {
+                    let import = import.map_err(parse_error)?;
+                    let kind = match import.ty {
+                        ImportSectionEntryType::Function(type_index) => {
+                            // Imported functions come first in the function index space.
+                            functions.push(type_index);
+                            ImportKind::Function(signature_at(&types, type_index)?)
+                        }
+                        ImportSectionEntryType::Table(_) => ImportKind::Table,
+                        ImportSectionEntryType::Memory(_) => ImportKind::Memory,
+                        ImportSectionEntryType::Global(_) => ImportKind::Global,
+                    };
+                    interface.imports.push(ModuleImport { module : import.module.to_string(), name : import.field.to_string(), kind : kind });
+                }
+            }
+            SectionCode::Function => {
+                for type_index in section.get_function_section_reader().map_err(parse_error)? {
+                    functions.push(type_index.map_err(parse_error)?);
+                }
+            }
+            SectionCode::Export => {
+                for export in section.get_export_section_reader().map_err(parse_error)? {
+                    let export = export.map_err(parse_error)?;
+                    let index = match export.kind {
+                        ExternalKind::Function => Some(export.index),
+                        _ => None,
+                    };
+                    export_indices.push((export.field.to_string(), index));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for (name, index) in export_indices {
+        let signature = match index {
+            Some(index) => {
+                let type_index = functions.get(index as usize).ok_or_else(|| format!("function index {} out of range", index))?;
+                Some(signature_at(&types, *type_index)?)
+            }
+            None => None,
+        };
+        interface.exports.push(ModuleExport { name : name, signature : signature });
+    }
+
+    Ok(interface)
+}
+
+pub fn check_interface(interface : &ModuleInterface) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    for import in &interface.imports {
+        let host = if import.module == HOST_NAMESPACE { hostapi::host_function(&import.name) } else { None };
+        match (&import.kind, host) {
+            (_, None) => problems.push(Problem::UnknownImport { module : import.module.clone(), name : import.name.clone() }),
+            (ImportKind::Function(found), Some(host)) => {
+                if !found.matches(&host.signature) {
+                    problems.push(Problem::MismatchedImport { name : import.name.clone(), expected : host.signature.clone(), found : found.clone() });
+                }
+            }
+            (_, Some(_)) => problems.push(Problem::UnsupportedImport { module : import.module.clone(), name : import.name.clone() }),
+        }
+    }
+
+    for expected in hostapi::SCRIPT_EXPORTS {
+        match interface.exports.iter().find(|e| e.name == expected.name) {
+            None => {
+                if expected.required {
+                    problems.push(Problem::MissingExport { name : expected.name.to_string() });
+                }
+            }
+            Some(export) => {
+                let matches = export.signature.as_ref().map_or(false, |s| s.matches_export(expected));
+                if !matches {
+                    problems.push(Problem::MismatchedExport { name : expected.name.to_string(), expected : expected.signature.clone(), found : export.signature.clone() });
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+pub fn validate_module(bytecode : &[u8]) -> Result<ModuleInterface, ValidationError> {
+    let interface = match read_interface(bytecode) {
+        Ok(interface) => interface,
+        Err(error) => return Err(ValidationError { problems : vec![Problem::Malformed(error)] }),
+    };
+
+    let problems = check_interface(&interface);
+    if problems.is_empty() {
+        Ok(interface)
+    }
+    else {
+        Err(ValidationError { problems : problems })
+    }
+}
+
+impl fmt::Display for ModuleSignature
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        fn join(types : &[Option<ValueType>]) -> String {
+            types.iter().map(|t| t.map_or("?".to_string(), |t| t.to_string())).collect::<Vec<_>>().join(", ")
+        }
+        write!(f, "({})->({})", join(&self.params), join(&self.returns))
+    }
+}
+
+impl fmt::Display for Problem
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Malformed(error) => write!(f, "malformed module: {}", error),
+            Problem::UnknownImport { module, name } => write!(f, "unknown import `{}.{}`, the host doesn't provide it", module, name),
+            Problem::UnsupportedImport { module, name } => write!(f, "import `{}.{}` must be a function", module, name),
+            Problem::MismatchedImport { name, expected, found } => write!(f, "import `{}`: expected `{}`, got `{}`", name, expected, found),
+            Problem::MissingExport { name } => write!(f, "missing required export `{}`", name),
+            Problem::MismatchedExport { name, expected, found : Some(found) } => write!(f, "export `{}`: expected `{}`, got `{}`", name, expected, found),
+            Problem::MismatchedExport { name, expected, found : None } => write!(f, "export `{}`: expected function `{}`, got a non-function export", name, expected),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "script doesn't match the host API:")?;
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn module(wat : &str) -> Vec<u8> {
+        let buffer = wast::parser::ParseBuffer::new(wat).unwrap();
+        let mut wat = wast::parser::parse::<wast::Wat>(&buffer).unwrap();
+        wat.module.encode().unwrap()
+    }
+
+    fn problems(wat : &str) -> Vec<Problem> {
+        match validate_module(&module(wat)) {
+            Ok(_) => Vec::new(),
+            Err(error) => error.problems,
+        }
+    }
+
+    #[test]
+    fn valid_module() {
+        let interface = validate_module(&module(r#"(module
+            (import "env" "add_particle" (func (param f32 f32 f32 f32 i32)))
+            (memory (export "memory") 1)
+            (func (export "tick") (param f32 f32)))"#)).unwrap();
+
+        assert_eq!(interface.imports.len(), 1);
+        assert_eq!(interface.exports.len(), 2);
+        assert!(interface.exports.iter().any(|export| export.name == "memory" && export.signature.is_none()));
+    }
+
+    #[test]
+    fn unknown_import() {
+        let problems = problems(r#"(module
+            (import "env" "add_partcle" (func (param f32 f32 f32 f32 i32)))
+            (import "wasi" "add_particle" (func (param f32 f32 f32 f32 i32)))
+            (func (export "tick") (param f32 f32)))"#);
+
+        assert_eq!(problems.len(), 2);
+        match &problems[0] {
+            Problem::UnknownImport { module, name } => assert_eq!((module.as_str(), name.as_str()), ("env", "add_partcle")),
+            problem => panic!("unexpected {:?}", problem),
+        }
+        match &problems[1] {
+            Problem::UnknownImport { module, name } => assert_eq!((module.as_str(), name.as_str()), ("wasi", "add_particle")),
+            problem => panic!("unexpected {:?}", problem),
+        }
+    }
+
+    #[test]
+    fn mismatched_import() {
+        let problems = problems(r#"(module
+            (import "env" "add_particle" (func (param f32 f32 f32 f32 f32)))
+            (func (export "tick") (param f32 f32)))"#);
+
+        assert_eq!(problems.len(), 1);
+        match &problems[0] {
+            Problem::MismatchedImport { name, expected, found } => {
+                assert_eq!(name, "add_particle");
+                assert_eq!(*expected, hostapi::host_function("add_particle").unwrap().signature);
+                assert_eq!(found.params, vec![Some(ValueType::F32); 5]);
+            }
+            problem => panic!("unexpected {:?}", problem),
+        }
+    }
+
+    #[test]
+    fn non_function_import() {
+        let problems = problems(r#"(module
+            (import "env" "add_particle" (global i32))
+            (func (export "tick") (param f32 f32)))"#);
+
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(&problems[0], Problem::UnsupportedImport { name, .. } if name == "add_particle"));
+    }
+
+    #[test]
+    fn missing_tick() {
+        let problems = problems(r#"(module
+            (func (export "init")))"#);
+
+        assert_eq!(problems.len(), 1);
+        assert!(matches!(&problems[0], Problem::MissingExport { name } if name == "tick"));
+    }
+
+    #[test]
+    fn legacy_tick() {
+        assert!(problems(r#"(module (func (export "tick") (param f32)))"#).is_empty());
+    }
+
+    #[test]
+    fn mismatched_tick() {
+        let problems = problems(r#"(module
+            (func (export "tick") (param i32)))"#);
+
+        assert_eq!(problems.len(), 1);
+        match &problems[0] {
+            Problem::MismatchedExport { name, found : Some(found), .. } => {
+                assert_eq!(name, "tick");
+                assert_eq!(found.params, vec![Some(ValueType::I32)]);
+            }
+            problem => panic!("unexpected {:?}", problem),
+        }
+    }
+
+    #[test]
+    fn malformed_module() {
+        match validate_module(b"\0asm\x01\0\0\0\x01\x05") {
+            Err(error) => assert!(matches!(error.problems[0], Problem::Malformed(_))),
+            Ok(_) => panic!("truncated module validated"),
+        }
+    }
+}
@@ -1,127 +1,323 @@
-use wasmer_runtime::{
-    imports,
-    func,
-    error,
-    Ctx,
-    Value,
-    instantiate
-};
-
 use std::fs;
-use std::ffi::c_void;
-
-use nalgebra_glm as glm;
-use glm::{Vec3, vec3};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::WorldState;
-use crate::world::Particle;
-
-use rand::Rng;
+use crate::engine::{ScriptEngine, CompiledScript, ScriptInstance, ScriptValue};
+use crate::host::HostContext;
+use crate::hostapi::{self, FunctionSignature};
+use crate::validate::{self, ValidationError, ModuleSignature};
+use crate::scriptlog::ScriptLog;
+use crate::scriptcache::{self, ScriptCache};
+use crate::scriptbuild::{self, Toolchain};
+use crate::color::ColorSpace;
+use crate::particles::ParticleEvent;
 
 #[derive(Debug)]
 pub enum VMError
 {
     IoError(std::io::Error),
-    WasmerError(wasmer_runtime::error::Error),
-    WasmerCallError(wasmer_runtime::error::CallError),
+    CompileError(String),
+    InstantiationError(String),
+    CallError(String),
+    MissingExport(String),
+    SignatureMismatch { name : String, expected : FunctionSignature, found : ModuleSignature },
+    ValidationError(ValidationError),
+    WatError { file : String, line : usize, column : usize, message : String },
+    BuildError(String),
 }
 
-impl From<std::io::Error> for VMError
+impl fmt::Display for VMError
 {
-    fn from(error: std::io::Error) -> Self {
-        VMError::IoError(error)
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VMError::IoError(error) => write!(f, "io error: {}", error),
+            VMError::CompileError(error) => write!(f, "compilation failed: {}", error),
+            VMError::InstantiationError(error) => write!(f, "instantiation failed: {}", error),
+            VMError::CallError(error) => write!(f, "script call failed: {}", error),
+            VMError::MissingExport(name) => write!(f, "missing required export `{}`", name),
+            VMError::SignatureMismatch { name, expected, found } => write!(f, "export `{}`: expected `{}`, got `{}`", name, expected, found),
+            VMError::ValidationError(error) => write!(f, "{}", error),
+            VMError::WatError { file, line, column, message } => write!(f, "{}:{}:{}: {}", file, line, column, message),
+            VMError::BuildError(error) => write!(f, "build failed: {}", error.trim_end()),
+        }
     }
 }
 
-impl From<wasmer_runtime::error::Error> for VMError
+impl From<std::io::Error> for VMError
 {
-    fn from(error: wasmer_runtime::error::Error) -> Self {
-        VMError::WasmerError(error)
+    fn from(error: std::io::Error) -> Self {
+        VMError::IoError(error)
     }
 }
 
-impl From<wasmer_runtime::error::CallError> for VMError
+impl From<ValidationError> for VMError
 {
-    fn from(error: wasmer_runtime::error::CallError) -> Self {
-        VMError::WasmerCallError(error)
+    fn from(error: ValidationError) -> Self {
+        VMError::ValidationError(error)
     }
 }
 
-fn set_camera(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, x1 : f32, y1: f32, z1: f32) {
-    let world: &mut WorldState = unsafe { &mut *(ctx.data as *mut WorldState) };
-    world.set_camera(vec3(x0, y0, z0), vec3(x1, y1, z1));
-}
+const DEFAULT_SCRIPT : &str = "data/test.wasm";
 
-fn add_particle(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, size : f32, color : u32) {
-    let world: &mut WorldState = unsafe { &mut *(ctx.data as *mut WorldState) };
-    world.add_particle(Particle{ position : vec3(x0, y0, z0), size : size, color : color });
-}
+// How often the source file is checked for changes.
+const SOURCE_CHECK_INTERVAL : Duration = Duration::from_millis(500);
 
-fn sinf(ctx: &mut Ctx, x : f32) -> f32 {
-    x.sin()
+// A script that has been read, validated and compiled, ready to be instantiated.
+struct CompiledSource
+{
+    script_name : String,
+    module_hash : String,
+    compiled : Box<dyn CompiledScript>,
 }
 
-fn cosf(ctx: &mut Ctx, x : f32) -> f32 {
-    x.cos()
+// Assembles a module in the WebAssembly text format, errors carry a 1-based line and column.
+fn assemble_wat(source_file : &str, text : &str) -> Result<Vec<u8>, VMError> {
+    let to_error = |error : wast::Error| {
+        let (line, column) = error.span().linecol_in(text);
+        VMError::WatError { file : source_file.to_string(), line : line + 1, column : column + 1, message : error.message() }
+    };
+
+    let buffer = wast::parser::ParseBuffer::new(text).map_err(to_error)?;
+    let mut wat = wast::parser::parse::<wast::Wat>(&buffer).map_err(to_error)?;
+    wat.module.encode().map_err(to_error)
 }
 
-fn memcpy(ctx: &mut Ctx, dst : i32, src : i32, len : i32) -> i32 {
-    let memory = ctx.memory(0);
-    
-    for i in 0..len {
-        let c : u8 = memory.view()[(src + i) as usize].get();
-        memory.view()[(dst + i) as usize].set(c);
+fn read_bytecode(toolchain : Option<&Toolchain>, source_file : &str) -> Result<Vec<u8>, VMError> {
+    if scriptbuild::is_buildable(source_file) {
+        let toolchain = toolchain.ok_or_else(|| VMError::BuildError(format!("no clang with the wasm32 target found to build {}, set CLANG", source_file)))?;
+        return scriptbuild::build(toolchain, source_file).map_err(VMError::BuildError);
     }
 
-    dst
+    match Path::new(source_file).extension().and_then(|extension| extension.to_str()) {
+        Some("wat") => assemble_wat(source_file, &fs::read_to_string(source_file)?),
+        _ => Ok(fs::read(source_file)?),
+    }
 }
 
-fn rand(ctx: &mut Ctx) -> i32 {
-    let mut rng = rand::thread_rng();
-    rng.gen()
+// Everything up to instantiation, which is the slow part. Touches no VMInstance state so it can run
+// on a worker thread.
+fn compile_source(engine : &dyn ScriptEngine, cache : Option<&ScriptCache>, toolchain : Option<&Toolchain>, source_file : &str) -> Result<CompiledSource, VMError> {
+    let bytecode = read_bytecode(toolchain, source_file)?;
+
+    validate::validate_module(&bytecode)?;
+
+    let script_name = Path::new(source_file).file_stem().map_or(source_file.into(), |stem| stem.to_string_lossy()).into_owned();
+
+    let cached = cache.and_then(|cache| cache.load(engine, &script_name, &bytecode));
+    let compiled = match cached {
+        Some(compiled) => compiled,
+        None => {
+            let compiled = engine.compile(&bytecode)?;
+            if let Some(cache) = cache {
+                cache.store(engine, &script_name, &bytecode, &*compiled);
+            }
+            compiled
+        }
+    };
+
+    Ok(CompiledSource { script_name : script_name, module_hash : scriptcache::module_hash(&bytecode), compiled : compiled })
 }
 
 pub struct VMInstance {
-    instance : Option<wasmer_runtime::Instance>
+    engine : Arc<dyn ScriptEngine>,
+    instance : Option<Box<dyn ScriptInstance>>,
+    // Of the loaded module's bytecode, see scriptcache::module_hash.
+    module_hash : Option<String>,
+    log : ScriptLog,
+    color_space : ColorSpace,
+    cache : Option<ScriptCache>,
+    toolchain : Option<Toolchain>,
+    source_file : String,
+    source_modified : Option<SystemTime>,
+    last_source_check : Instant,
+    pending_reload : Option<Receiver<Result<CompiledSource, VMError>>>,
 }
 
 impl VMInstance {
-    pub fn new() -> VMInstance {
-        VMInstance { instance : None }
-    }
-
-    pub fn load_script(&mut self) -> Result<(), VMError> {
-        let source_file = "data/test.wasm";
-        let bytecode = fs::read(source_file)?;
-        
-        let import_object = imports! {
-            // Define the "env" namespace that was implicitly used
-            // by our sample application.
-            "env" => {
-                // name        // the func! macro autodetects the signature
-                "set_camera" => func!(set_camera),
-                "add_particle" => func!(add_particle),
-                "cosf" => func!(cosf),
-                "sinf" => func!(sinf),
-                "memcpy" => func!(memcpy),
-                "rand" => func!(rand),
-            },
+    pub fn new(engine : Box<dyn ScriptEngine>, cache : Option<ScriptCache>) -> VMInstance {
+        VMInstance {
+            engine : Arc::from(engine),
+            instance : None,
+            module_hash : None,
+            log : ScriptLog::new("none"),
+            color_space : ColorSpace::Linear,
+            cache : cache,
+            toolchain : None,
+            source_file : DEFAULT_SCRIPT.to_string(),
+            source_modified : None,
+            last_source_check : Instant::now(),
+            pending_reload : None
+        }
+    }
+
+    // A .wasm binary, a .wat text module or a C/C++ source built with a local clang, used from the next
+    // load or reload on.
+    pub fn set_source_file(&mut self, source_file : &str) {
+        self.source_file = source_file.to_string();
+        self.source_modified = None;
+
+        if scriptbuild::is_buildable(source_file) && self.toolchain.is_none() {
+            self.toolchain = scriptbuild::detect_toolchain();
+            match &self.toolchain {
+                Some(toolchain) => println!("Building scripts with {}", toolchain.clang.display()),
+                None => println!("No clang with the wasm32 target found, set CLANG to build {}", source_file),
+            }
+        }
+    }
+
+    fn read_source_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.source_file).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // Loads the script synchronously, for startup where there is nothing to keep running meanwhile.
+    pub fn load_script(&mut self, world : &mut WorldState) -> Result<(), VMError> {
+        self.source_modified = self.read_source_modified();
+        let source = compile_source(&*self.engine, self.cache.as_ref(), self.toolchain.as_ref(), &self.source_file)?;
+        self.swap_in(world, source)
+    }
+
+    // Starts a background reload when the source file changed since it was last loaded. Checked at most
+    // every SOURCE_CHECK_INTERVAL, so it is cheap to call every frame.
+    pub fn reload_if_changed(&mut self) {
+        if self.last_source_check.elapsed() < SOURCE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_source_check = Instant::now();
+
+        let modified = self.read_source_modified();
+        if modified.is_some() && modified != self.source_modified {
+            self.reload_script_async();
+        }
+    }
+
+    // Starts compiling the script on a worker thread, the current script keeps running until
+    // poll_reload swaps the new one in. A reload requested while another is in flight supersedes it.
+    pub fn reload_script_async(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        let engine = self.engine.clone();
+        let cache = self.cache.clone();
+        let toolchain = self.toolchain.clone();
+        let source_file = self.source_file.clone();
+        self.source_modified = self.read_source_modified();
+
+        thread::spawn(move || {
+            let result = compile_source(&*engine, cache.as_ref(), toolchain.as_ref(), &source_file);
+            // The receiver is gone if a newer reload replaced this one.
+            let _ = sender.send(result);
+        });
+
+        self.pending_reload = Some(receiver);
+    }
+
+    // Call at a frame boundary. Returns None while nothing finished compiling, otherwise the outcome of
+    // the reload; on failure the old script stays loaded.
+    pub fn poll_reload(&mut self, world : &mut WorldState) -> Option<Result<(), VMError>> {
+        let result = match self.pending_reload.as_ref()?.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(VMError::CompileError("script compilation thread panicked".to_string())),
         };
+        self.pending_reload = None;
+
+        Some(result.and_then(|source| self.swap_in(world, source)))
+    }
 
-        let mut instance = instantiate(&bytecode, &import_object)?;
+    fn swap_in(&mut self, world : &mut WorldState, source : CompiledSource) -> Result<(), VMError> {
+        let instance = source.compiled.instantiate()?;
 
+        // The old script keeps running if the new one fails to instantiate, so only shut it down now.
+        self.unload_script(world);
+        // Emitters belong to the script that created them.
+        world.particle_system.clear();
         self.instance = Some(instance);
+        self.module_hash = Some(source.module_hash);
+        self.log = ScriptLog::new(&source.script_name);
+        self.color_space = ColorSpace::Linear;
 
-        Ok(())
+        self.call_export(world, "init", &[])
+    }
+
+    pub fn unload_script(&mut self, world : &mut WorldState) {
+        if let Err(error) = self.call_export(world, "shutdown", &[]) {
+            println!("{}", error);
+        }
+        self.log.finish();
+
+        self.instance = None;
+        self.module_hash = None;
+    }
+
+    pub fn module_hash(&self) -> Option<&str> {
+        self.module_hash.as_ref().map(|hash| hash.as_str())
+    }
+
+    pub fn engine_name(&self) -> &str {
+        self.engine.name()
     }
 
-    pub fn call_tick(&mut self, world : &mut WorldState, t : f32) -> Result<(), VMError> {
-        let mut instance = self.instance.as_mut().unwrap();
+    // Linear memory of the running script in bytes, 0 without one.
+    pub fn memory_size(&self) -> usize {
+        self.instance.as_ref().map_or(0, |instance| instance.memory_size())
+    }
+
+    pub fn call_tick(&mut self, world : &mut WorldState, t : f32, dt : f32) -> Result<(), VMError> {
+        let result = self.call_export(world, "tick", &[ScriptValue::F32(t), ScriptValue::F32(dt)]);
+        self.log.flush();
+        result
+    }
+
+    pub fn call_on_resize(&mut self, world : &mut WorldState, width : u32, height : u32) -> Result<(), VMError> {
+        self.call_export(world, "on_resize", &[ScriptValue::I32(width as i32), ScriptValue::I32(height as i32)])
+    }
+
+    pub fn call_particle_event(&mut self, world : &mut WorldState, event : ParticleEvent) -> Result<(), VMError> {
+        match event {
+            ParticleEvent::Spawned { emitter, particle } =>
+                self.call_export(world, "on_particle_spawn", &[ScriptValue::I32(emitter), ScriptValue::I32(particle as i32)]),
+            ParticleEvent::Died { emitter, position } =>
+                self.call_export(world, "on_particle_death", &[ScriptValue::I32(emitter), ScriptValue::F32(position.x), ScriptValue::F32(position.y), ScriptValue::F32(position.z)]),
+        }
+    }
+
+    // Calls one of the exports listed in hostapi::SCRIPT_EXPORTS. Optional exports a script doesn't
+    // provide are skipped; the ones it does provide are checked against the table first.
+    fn call_export(&mut self, world : &mut WorldState, name : &str, args : &[ScriptValue]) -> Result<(), VMError> {
+        let expected = hostapi::script_export(name).expect("export missing from hostapi::SCRIPT_EXPORTS");
+
+        let instance = match self.instance.as_mut() {
+            Some(instance) => instance,
+            None => return Ok(()),
+        };
+
+        let signature = match instance.export_signature(name) {
+            Some(signature) => signature,
+            None => {
+                if expected.required {
+                    return Err(VMError::MissingExport(name.to_string()));
+                }
+                return Ok(());
+            }
+        };
+
+        if !signature.matches_export(expected) {
+            return Err(VMError::SignatureMismatch {
+                name : name.to_string(),
+                expected : expected.signature.clone(),
+                found : signature
+            });
+        }
+
+        // Legacy signatures take a prefix of the arguments.
+        let args = &args[.. signature.params.len().min(args.len())];
 
-        instance.context_mut().data = world as *mut _ as *mut c_void;
+        let mut host = HostContext { world : world, log : &mut self.log, color_space : &mut self.color_space };
+        instance.call(&mut host, name, args)?;
 
-        instance.call("tick", &[Value::F32(t)])?;
-        
         Ok(())
     }
 }
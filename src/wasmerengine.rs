@@ -0,0 +1,352 @@
+use wasmer_runtime::{
+    imports,
+    func,
+    Ctx,
+    Value,
+    Export,
+    Memory,
+};
+use wasmer_runtime::types::Type;
+use wasmer_runtime::cache::Artifact;
+
+use std::cell::Cell;
+use std::ffi::c_void;
+
+use crate::engine::{ScriptEngine, CompiledScript, ScriptInstance, ScriptValue};
+use crate::guestmemory::GuestMemory;
+use crate::host::{self, HostContext};
+use crate::hostapi::ValueType;
+use crate::validate::ModuleSignature;
+use crate::vm::VMError;
+
+// JIT backend on top of wasmer_runtime.
+
+// What Ctx::data points to during a call.
+struct CallData
+{
+    host : *mut c_void,
+    // The exported memory, null without one. The wasmi backend uses the same, and a module without
+    // memory gets an empty one instead of a panic in Ctx::memory.
+    memory : *const Memory,
+}
+
+fn call_data(ctx : &Ctx) -> &CallData {
+    unsafe { &*(ctx.data as *const CallData) }
+}
+
+fn host_context<'a>(ctx : &'a Ctx) -> &'a mut HostContext<'a> {
+    unsafe { &mut *(call_data(ctx).host as *mut HostContext) }
+}
+
+fn with_memory<R, F : FnOnce(&mut HostContext, &[Cell<u8>]) -> R>(ctx : &Ctx, f : F) -> R {
+    let no_memory : &[Cell<u8>] = &[];
+    match unsafe { call_data(ctx).memory.as_ref() } {
+        Some(memory) => f(host_context(ctx), &memory.view::<u8>()[..]),
+        None => f(host_context(ctx), no_memory),
+    }
+}
+
+fn set_camera(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, x1 : f32, y1: f32, z1: f32) {
+    host::set_camera(host_context(ctx), x0, y0, z0, x1, y1, z1);
+}
+
+fn add_particle(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, size : f32, color : u32) {
+    host::add_particle(host_context(ctx), x0, y0, z0, size, color);
+}
+
+fn add_particle_rgba(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, size : f32, r : f32, g : f32, b : f32, a : f32) {
+    host::add_particle_rgba(host_context(ctx), x0, y0, z0, size, r, g, b, a);
+}
+
+fn add_particle_hsv(ctx: &mut Ctx, x0 : f32, y0: f32, z0: f32, size : f32, h : f32, s : f32, v : f32, a : f32) {
+    host::add_particle_hsv(host_context(ctx), x0, y0, z0, size, h, s, v, a);
+}
+
+fn set_color_space(ctx: &mut Ctx, space : i32) {
+    host::set_color_space(host_context(ctx), space);
+}
+
+fn create_emitter(ctx: &mut Ctx) -> i32 {
+    host::create_emitter(host_context(ctx))
+}
+
+fn destroy_emitter(ctx: &mut Ctx, emitter : i32) {
+    host::destroy_emitter(host_context(ctx), emitter);
+}
+
+fn emit_particles(ctx: &mut Ctx, emitter : i32, count : i32) {
+    host::emit_particles(host_context(ctx), emitter, count);
+}
+
+fn set_emitter_position(ctx: &mut Ctx, emitter : i32, x : f32, y : f32, z : f32) {
+    host::set_emitter_position(host_context(ctx), emitter, x, y, z);
+}
+
+fn set_emitter_velocity(ctx: &mut Ctx, emitter : i32, x : f32, y : f32, z : f32, spread : f32) {
+    host::set_emitter_velocity(host_context(ctx), emitter, x, y, z, spread);
+}
+
+fn set_emitter_rate(ctx: &mut Ctx, emitter : i32, particles_per_second : f32) {
+    host::set_emitter_rate(host_context(ctx), emitter, particles_per_second);
+}
+
+fn set_emitter_lifetime(ctx: &mut Ctx, emitter : i32, seconds : f32) {
+    host::set_emitter_lifetime(host_context(ctx), emitter, seconds);
+}
+
+fn set_emitter_appearance(ctx: &mut Ctx, emitter : i32, size : f32, color : u32) {
+    host::set_emitter_appearance(host_context(ctx), emitter, size, color);
+}
+
+fn set_emitter_physics(ctx: &mut Ctx, emitter : i32, gx : f32, gy : f32, gz : f32, drag : f32) {
+    host::set_emitter_physics(host_context(ctx), emitter, gx, gy, gz, drag);
+}
+
+fn set_emitter_callbacks(ctx: &mut Ctx, emitter : i32, flags : i32) {
+    host::set_emitter_callbacks(host_context(ctx), emitter, flags);
+}
+
+fn set_particle_velocity(ctx: &mut Ctx, particle : i32, x : f32, y : f32, z : f32) {
+    host::set_particle_velocity(host_context(ctx), particle, x, y, z);
+}
+
+fn set_particle_color(ctx: &mut Ctx, particle : i32, color : u32) {
+    host::set_particle_color(host_context(ctx), particle, color);
+}
+
+fn set_emitter_render_mode(ctx: &mut Ctx, emitter : i32, mode : i32, length : f32) {
+    host::set_emitter_render_mode(host_context(ctx), emitter, mode, length);
+}
+
+fn set_particle_render_mode(ctx: &mut Ctx, particle : i32, mode : i32, length : f32) {
+    host::set_particle_render_mode(host_context(ctx), particle, mode, length);
+}
+
+fn sinf(_ctx: &mut Ctx, x : f32) -> f32 {
+    host::sinf(x)
+}
+
+fn cosf(_ctx: &mut Ctx, x : f32) -> f32 {
+    host::cosf(x)
+}
+
+fn draw_line(ctx: &mut Ctx, x0 : f32, y0 : f32, z0 : f32, x1 : f32, y1 : f32, z1 : f32, color : u32) {
+    host::draw_line(host_context(ctx), x0, y0, z0, x1, y1, z1, color);
+}
+
+fn draw_box(ctx: &mut Ctx, minx : f32, miny : f32, minz : f32, maxx : f32, maxy : f32, maxz : f32, color : u32) {
+    host::draw_box(host_context(ctx), minx, miny, minz, maxx, maxy, maxz, color);
+}
+
+fn draw_sphere_wire(ctx: &mut Ctx, x : f32, y : f32, z : f32, radius : f32, color : u32) {
+    host::draw_sphere_wire(host_context(ctx), x, y, z, radius, color);
+}
+
+fn draw_text_2d(ctx: &mut Ctx, x : f32, y : f32, ptr : i32, len : i32, color : u32) {
+    with_memory(ctx, |host, memory| host::draw_text_2d(host, memory, x, y, ptr, len, color));
+}
+
+fn draw_text_3d(ctx: &mut Ctx, x : f32, y : f32, z : f32, ptr : i32, len : i32, color : u32) {
+    with_memory(ctx, |host, memory| host::draw_text_3d(host, memory, x, y, z, ptr, len, color));
+}
+
+fn add_triangles(ctx: &mut Ctx, ptr : i32, count : i32) {
+    with_memory(ctx, |host, memory| host::add_triangles(host, memory, ptr, count));
+}
+
+fn add_mesh(ctx: &mut Ctx, vertices : i32, vertex_count : i32, indices : i32, index_count : i32) {
+    with_memory(ctx, |host, memory| host::add_mesh(host, memory, vertices, vertex_count, indices, index_count));
+}
+
+fn memcpy(ctx: &mut Ctx, dst : i32, src : i32, len : i32) -> i32 {
+    with_memory(ctx, |host, memory| host::memcpy(host, memory, dst, src, len))
+}
+
+fn rand(ctx: &mut Ctx) -> i32 {
+    host::rand(host_context(ctx))
+}
+
+fn log_message(ctx: &mut Ctx, level : i32, ptr : i32, len : i32) {
+    with_memory(ctx, |host, memory| host::log_message(host, memory, level, ptr, len));
+}
+
+fn log_printf(ctx: &mut Ctx, level : i32, format_ptr : i32, args_ptr : i32) {
+    with_memory(ctx, |host, memory| host::log_printf(host, memory, level, format_ptr, args_ptr));
+}
+
+fn value_type(ty : &Type) -> Option<ValueType> {
+    match ty {
+        Type::I32 => Some(ValueType::I32),
+        Type::I64 => Some(ValueType::I64),
+        Type::F32 => Some(ValueType::F32),
+        Type::F64 => Some(ValueType::F64),
+        _ => None,
+    }
+}
+
+fn to_wasmer(value : &ScriptValue) -> Value {
+    match *value {
+        ScriptValue::I32(v) => Value::I32(v),
+        ScriptValue::I64(v) => Value::I64(v),
+        ScriptValue::F32(v) => Value::F32(v),
+        ScriptValue::F64(v) => Value::F64(v),
+    }
+}
+
+fn from_wasmer(value : &Value) -> Option<ScriptValue> {
+    match *value {
+        Value::I32(v) => Some(ScriptValue::I32(v)),
+        Value::I64(v) => Some(ScriptValue::I64(v)),
+        Value::F32(v) => Some(ScriptValue::F32(v)),
+        Value::F64(v) => Some(ScriptValue::F64(v)),
+        _ => None,
+    }
+}
+
+pub struct WasmerEngine;
+
+impl WasmerEngine {
+    pub fn new() -> WasmerEngine {
+        WasmerEngine
+    }
+}
+
+impl ScriptEngine for WasmerEngine {
+    fn name(&self) -> &'static str {
+        "wasmer"
+    }
+
+    // The wasmer release cargo resolved, see build.rs. Artifacts are native code from the default
+    // compiler backend and can't be loaded by another one.
+    fn version(&self) -> &'static str {
+        env!("WASMER_VERSION")
+    }
+
+    fn compile(&self, bytecode : &[u8]) -> Result<Box<dyn CompiledScript>, VMError> {
+        let module = wasmer_runtime::compile(bytecode).map_err(|error| VMError::CompileError(error.to_string()))?;
+        Ok(Box::new(WasmerModule { module : module }))
+    }
+
+    fn caches_artifacts(&self) -> bool {
+        true
+    }
+
+    fn load_artifact(&self, artifact : &[u8]) -> Result<Box<dyn CompiledScript>, VMError> {
+        let artifact = Artifact::deserialize(artifact).map_err(|error| VMError::CompileError(format!("{:?}", error)))?;
+        let module = unsafe { wasmer_runtime_core::load_cache_with(artifact, &wasmer_runtime::default_compiler()) }
+            .map_err(|error| VMError::CompileError(format!("{:?}", error)))?;
+        Ok(Box::new(WasmerModule { module : module }))
+    }
+}
+
+struct WasmerModule
+{
+    module : wasmer_runtime::Module,
+}
+
+impl CompiledScript for WasmerModule {
+    fn artifact(&self) -> Option<Vec<u8>> {
+        self.module.cache().ok()?.serialize().ok()
+    }
+
+    fn instantiate(&self) -> Result<Box<dyn ScriptInstance>, VMError> {
+        let import_object = imports! {
+            // Define the "env" namespace that was implicitly used
+            // by our sample application.
+            "env" => {
+                // name        // the func! macro autodetects the signature
+                "set_camera" => func!(set_camera),
+                "add_particle" => func!(add_particle),
+                "add_particle_rgba" => func!(add_particle_rgba),
+                "add_particle_hsv" => func!(add_particle_hsv),
+                "set_color_space" => func!(set_color_space),
+                "create_emitter" => func!(create_emitter),
+                "destroy_emitter" => func!(destroy_emitter),
+                "emit_particles" => func!(emit_particles),
+                "set_emitter_position" => func!(set_emitter_position),
+                "set_emitter_velocity" => func!(set_emitter_velocity),
+                "set_emitter_rate" => func!(set_emitter_rate),
+                "set_emitter_lifetime" => func!(set_emitter_lifetime),
+                "set_emitter_appearance" => func!(set_emitter_appearance),
+                "set_emitter_physics" => func!(set_emitter_physics),
+                "set_emitter_callbacks" => func!(set_emitter_callbacks),
+                "set_particle_velocity" => func!(set_particle_velocity),
+                "set_particle_color" => func!(set_particle_color),
+                "set_emitter_render_mode" => func!(set_emitter_render_mode),
+                "set_particle_render_mode" => func!(set_particle_render_mode),
+                "draw_line" => func!(draw_line),
+                "draw_box" => func!(draw_box),
+                "draw_sphere_wire" => func!(draw_sphere_wire),
+                "draw_text_2d" => func!(draw_text_2d),
+                "draw_text_3d" => func!(draw_text_3d),
+                "add_triangles" => func!(add_triangles),
+                "add_mesh" => func!(add_mesh),
+                "cosf" => func!(cosf),
+                "sinf" => func!(sinf),
+                "memcpy" => func!(memcpy),
+                "rand" => func!(rand),
+                "log_message" => func!(log_message),
+                "log_printf" => func!(log_printf),
+            },
+        };
+
+        let instance = self.module.instantiate(&import_object).map_err(|error| VMError::InstantiationError(error.to_string()))?;
+
+        let memory = instance.exports().find_map(|(_, export)| match export {
+            Export::Memory(memory) => Some(memory),
+            _ => None,
+        });
+
+        Ok(Box::new(WasmerInstance { instance : instance, memory : memory }))
+    }
+}
+
+struct WasmerInstance
+{
+    instance : wasmer_runtime::Instance,
+    memory : Option<Memory>,
+}
+
+impl ScriptInstance for WasmerInstance {
+    fn export_signature(&self, name : &str) -> Option<ModuleSignature> {
+        let func = self.instance.dyn_func(name).ok()?;
+        let signature = func.signature();
+        Some(ModuleSignature {
+            params : signature.params().iter().map(value_type).collect(),
+            returns : signature.returns().iter().map(value_type).collect(),
+        })
+    }
+
+    fn call(&mut self, host : &mut HostContext, name : &str, args : &[ScriptValue]) -> Result<Vec<ScriptValue>, VMError> {
+        let args : Vec<Value> = args.iter().map(to_wasmer).collect();
+
+        let call_data = CallData {
+            host : host as *mut _ as *mut c_void,
+            memory : self.memory.as_ref().map_or(std::ptr::null(), |memory| memory as *const Memory),
+        };
+        self.instance.context_mut().data = &call_data as *const CallData as *mut c_void;
+        let result = self.instance.call(name, &args);
+        self.instance.context_mut().data = std::ptr::null_mut();
+
+        let values = result.map_err(|error| VMError::CallError(error.to_string()))?;
+        Ok(values.iter().filter_map(from_wasmer).collect())
+    }
+
+    fn memory_size(&self) -> usize {
+        self.memory.as_ref().map_or(0, |memory| memory.view::<u8>().len())
+    }
+
+    fn read_memory(&self, offset : usize, buffer : &mut [u8]) -> bool {
+        match &self.memory {
+            Some(memory) => memory.view::<u8>()[..].read(offset, buffer),
+            None => false,
+        }
+    }
+
+    fn write_memory(&mut self, offset : usize, data : &[u8]) -> bool {
+        match &self.memory {
+            Some(memory) => memory.view::<u8>()[..].write(offset, data),
+            None => false,
+        }
+    }
+}
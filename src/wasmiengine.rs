@@ -0,0 +1,223 @@
+use wasmi::{
+    Externals,
+    FuncInstance,
+    FuncRef,
+    HostError,
+    ImportsBuilder,
+    MemoryRef,
+    ModuleImportResolver,
+    ModuleInstance,
+    ModuleRef,
+    NopExternals,
+    RuntimeArgs,
+    RuntimeValue,
+    Signature,
+    Trap,
+    TrapKind,
+};
+use wasmi::nan_preserving_float::{F32, F64};
+
+use std::cell::Cell;
+use std::fmt;
+
+use crate::engine::{ScriptEngine, CompiledScript, ScriptInstance, ScriptValue};
+use crate::guestmemory::GuestMemory;
+use crate::host::{self, HostContext};
+use crate::hostapi::{self, ValueType, HOST_FUNCTIONS, HOST_NAMESPACE};
+use crate::validate::ModuleSignature;
+use crate::vm::VMError;
+
+// Interpreter backend on top of wasmi, for platforms where a JIT isn't available. Host functions are
+// resolved to their index in hostapi::HOST_FUNCTIONS and called through host::dispatch.
+
+fn wasmi_type(ty : ValueType) -> wasmi::ValueType {
+    match ty {
+        ValueType::I32 => wasmi::ValueType::I32,
+        ValueType::I64 => wasmi::ValueType::I64,
+        ValueType::F32 => wasmi::ValueType::F32,
+        ValueType::F64 => wasmi::ValueType::F64,
+    }
+}
+
+fn value_type(ty : wasmi::ValueType) -> ValueType {
+    match ty {
+        wasmi::ValueType::I32 => ValueType::I32,
+        wasmi::ValueType::I64 => ValueType::I64,
+        wasmi::ValueType::F32 => ValueType::F32,
+        wasmi::ValueType::F64 => ValueType::F64,
+    }
+}
+
+fn to_wasmi(value : &ScriptValue) -> RuntimeValue {
+    match *value {
+        ScriptValue::I32(v) => RuntimeValue::I32(v),
+        ScriptValue::I64(v) => RuntimeValue::I64(v),
+        ScriptValue::F32(v) => RuntimeValue::F32(F32::from_float(v)),
+        ScriptValue::F64(v) => RuntimeValue::F64(F64::from_float(v)),
+    }
+}
+
+fn from_wasmi(value : &RuntimeValue) -> ScriptValue {
+    match *value {
+        RuntimeValue::I32(v) => ScriptValue::I32(v),
+        RuntimeValue::I64(v) => ScriptValue::I64(v),
+        RuntimeValue::F32(v) => ScriptValue::F32(v.to_float()),
+        RuntimeValue::F64(v) => ScriptValue::F64(v.to_float()),
+    }
+}
+
+impl GuestMemory for MemoryRef
+{
+    fn size(&self) -> usize {
+        self.current_size().0 * wasmi::LINEAR_MEMORY_PAGE_SIZE.0
+    }
+
+    fn read(&self, offset : usize, buffer : &mut [u8]) -> bool {
+        offset <= u32::max_value() as usize && self.get_into(offset as u32, buffer).is_ok()
+    }
+
+    fn write(&self, offset : usize, data : &[u8]) -> bool {
+        offset <= u32::max_value() as usize && self.set(offset as u32, data).is_ok()
+    }
+}
+
+#[derive(Debug)]
+struct HostCallError(String);
+
+impl fmt::Display for HostCallError
+{
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl HostError for HostCallError {}
+
+fn format_signature(signature : &Signature) -> String {
+    let params : Vec<ValueType> = signature.params().iter().map(|t| value_type(*t)).collect();
+    let returns : Vec<ValueType> = signature.return_type().into_iter().map(value_type).collect();
+    hostapi::format_signature(&params, &returns)
+}
+
+struct HostResolver;
+
+impl ModuleImportResolver for HostResolver {
+    fn resolve_func(&self, field_name : &str, signature : &Signature) -> Result<FuncRef, wasmi::Error> {
+        let index = HOST_FUNCTIONS.iter().position(|f| f.name == field_name)
+            .ok_or_else(|| wasmi::Error::Instantiation(format!("unknown import `{}`", field_name)))?;
+
+        let expected = &HOST_FUNCTIONS[index].signature;
+        let params : Vec<wasmi::ValueType> = expected.params.iter().map(|t| wasmi_type(*t)).collect();
+        let return_type = expected.returns.first().map(|t| wasmi_type(*t));
+
+        if signature.params() != &params[..] || signature.return_type() != return_type {
+            return Err(wasmi::Error::Instantiation(format!("import `{}`: expected `{}`, got `{}`", field_name, expected, format_signature(signature))));
+        }
+
+        Ok(FuncInstance::alloc_host(Signature::new(params, return_type), index))
+    }
+}
+
+struct HostExternals<'a, 'b>
+{
+    host : &'a mut HostContext<'b>,
+    memory : Option<&'a MemoryRef>,
+}
+
+impl<'a, 'b> Externals for HostExternals<'a, 'b> {
+    fn invoke_index(&mut self, index : usize, args : RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        let function = HOST_FUNCTIONS.get(index).ok_or_else(|| Trap::new(TrapKind::Unreachable))?;
+        let args : Vec<ScriptValue> = args.as_ref().iter().map(from_wasmi).collect();
+
+        let no_memory : &[Cell<u8>] = &[];
+        let result = match self.memory {
+            Some(memory) => host::dispatch(self.host, memory, function.name, &args),
+            None => host::dispatch(self.host, no_memory, function.name, &args),
+        };
+
+        let result = result.map_err(|error| Trap::new(TrapKind::Host(Box::new(HostCallError(error)))))?;
+        Ok(result.as_ref().map(to_wasmi))
+    }
+}
+
+pub struct WasmiEngine;
+
+impl WasmiEngine {
+    pub fn new() -> WasmiEngine {
+        WasmiEngine
+    }
+}
+
+impl ScriptEngine for WasmiEngine {
+    fn name(&self) -> &'static str {
+        "wasmi"
+    }
+
+    // The wasmi release cargo resolved, see build.rs.
+    fn version(&self) -> &'static str {
+        env!("WASMI_VERSION")
+    }
+
+    fn compile(&self, bytecode : &[u8]) -> Result<Box<dyn CompiledScript>, VMError> {
+        let module = wasmi::Module::from_buffer(bytecode).map_err(|error| VMError::CompileError(error.to_string()))?;
+        Ok(Box::new(WasmiModule { module : module }))
+    }
+}
+
+struct WasmiModule
+{
+    module : wasmi::Module,
+}
+
+impl CompiledScript for WasmiModule {
+    fn instantiate(&self) -> Result<Box<dyn ScriptInstance>, VMError> {
+        let imports = ImportsBuilder::new().with_resolver(HOST_NAMESPACE, &HostResolver);
+
+        // There is no HostContext to give a start function, scripts do their setup in `init`.
+        let instance = ModuleInstance::new(&self.module, &imports)
+            .map_err(|error| VMError::InstantiationError(error.to_string()))?
+            .run_start(&mut NopExternals)
+            .map_err(|error| VMError::InstantiationError(error.to_string()))?;
+
+        let memory = instance.export_by_name("memory").and_then(|export| export.as_memory().cloned());
+
+        Ok(Box::new(WasmiInstance { instance : instance, memory : memory }))
+    }
+}
+
+struct WasmiInstance
+{
+    instance : ModuleRef,
+    memory : Option<MemoryRef>,
+}
+
+impl ScriptInstance for WasmiInstance {
+    fn export_signature(&self, name : &str) -> Option<ModuleSignature> {
+        let export = self.instance.export_by_name(name)?;
+        let signature = export.as_func()?.signature();
+        Some(ModuleSignature {
+            params : signature.params().iter().map(|t| Some(value_type(*t))).collect(),
+            returns : signature.return_type().into_iter().map(|t| Some(value_type(t))).collect(),
+        })
+    }
+
+    fn call(&mut self, host : &mut HostContext, name : &str, args : &[ScriptValue]) -> Result<Vec<ScriptValue>, VMError> {
+        let args : Vec<RuntimeValue> = args.iter().map(to_wasmi).collect();
+        let mut externals = HostExternals { host : host, memory : self.memory.as_ref() };
+
+        let result = self.instance.invoke_export(name, &args, &mut externals).map_err(|error| VMError::CallError(error.to_string()))?;
+        Ok(result.iter().map(from_wasmi).collect())
+    }
+
+    fn memory_size(&self) -> usize {
+        self.memory.as_ref().map_or(0, |memory| memory.size())
+    }
+
+    fn read_memory(&self, offset : usize, buffer : &mut [u8]) -> bool {
+        self.memory.as_ref().map_or(false, |memory| memory.read(offset, buffer))
+    }
+
+    fn write_memory(&mut self, offset : usize, data : &[u8]) -> bool {
+        self.memory.as_ref().map_or(false, |memory| memory.write(offset, data))
+    }
+}
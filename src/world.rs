@@ -1,6 +1,25 @@
 use nalgebra_glm as glm;
 use glm::{Vec3, vec3};
 use crate::vm::VMInstance;
+use crate::particles::ParticleSystem;
+use crate::debugdraw::DebugDraw;
+use crate::mesh::MeshBatch;
+
+use rand::{SeedableRng, XorShiftRng};
+
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TICK_RATE : f32 = 60.0;
+
+// Longest frame the simulation catches up on, a longer stall (a breakpoint, a window drag) slows the
+// simulation down instead of running hundreds of ticks at once.
+const MAX_FRAME_TIME : f32 = 0.25;
+
+pub const MIN_TIME_SCALE : f32 = 0.1;
+pub const MAX_TIME_SCALE : f32 = 10.0;
+
+// The speeds change_time_scale steps through.
+const TIME_SCALES : [f32; 9] = [0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 5.0, 7.5, 10.0];
 
 pub struct Particle
 {
@@ -14,14 +33,67 @@ pub struct WorldState
     pub camera_position : Vec3,
     pub camera_lookat : Vec3,
     pub camera_up : Vec3,
+    // Particles the script submitted this frame.
     pub particles_list : Vec<Particle>,
+    // Particles the host simulates across frames.
+    pub particle_system : ParticleSystem,
+    // Triangles the script submitted this frame.
+    pub meshes : MeshBatch,
+    // Lines and text the script submitted this frame.
+    pub debug : DebugDraw,
+    // The script runs in fixed steps of tick_length seconds. `time` is the simulation time, which
+    // lags the wall clock by less than a step.
+    pub tick_length : f32,
+    pub time : f32,
+    // Simulation seconds run by the last advance.
+    pub frame_dt : f32,
+    // How far the wall clock is into the next step, for interpolating what is drawn.
+    pub alpha : f32,
+    // Pausing and scaling act on the wall clock time fed to the accumulator, so scripts still see
+    // fixed steps.
+    paused : bool,
+    time_scale : f32,
+    // Single steps requested while paused.
+    pending_steps : u32,
+    accumulator : f32,
+    last_time : Option<f32>,
+    // Everything random a script sees comes from here, see reseed.
+    pub rng : XorShiftRng,
+    seed : u64,
+    // Wall clock time spent in the script during the last advance, for the overlay.
+    pub script_time : Duration,
+}
+
+fn seeded_rng(seed : u64) -> XorShiftRng {
+    // XorShift must not start from all zeros.
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, seed as u32 ^ 0x9e37_79b9, (seed >> 32) as u32 ^ 0x7f4a_7c15])
 }
 
 impl WorldState
 {
     pub fn new(position : Vec3, lookat : Vec3, up : Vec3) -> WorldState
     {
-        WorldState { camera_position : position, camera_lookat : lookat, camera_up : up, particles_list : Vec::new() }
+        WorldState {
+            camera_position : position,
+            camera_lookat : lookat,
+            camera_up : up,
+            particles_list : Vec::new(),
+            particle_system : ParticleSystem::new(),
+            meshes : MeshBatch::new(),
+            debug : DebugDraw::new(),
+            tick_length : 1.0 / DEFAULT_TICK_RATE,
+            time : 0.0,
+            frame_dt : 0.0,
+            alpha : 0.0,
+            paused : false,
+            time_scale : 1.0,
+            pending_steps : 0,
+            accumulator : 0.0,
+            last_time : None,
+            rng : seeded_rng(0),
+            seed : 0,
+            script_time : Duration::new(0, 0),
+        }
     }
 
     pub fn set_camera(&mut self, position : Vec3, lookat : Vec3)
@@ -34,12 +106,174 @@ impl WorldState
         self.particles_list.push(particle);
     }
 
-    pub fn tick(&mut self, vm : &mut VMInstance, time : f32) {
+    pub fn particle_count(&self) -> usize {
+        if self.particle_system.host_integration() {
+            self.particles_list.len() + self.particle_system.particles().len()
+        } else {
+            self.particles_list.len()
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn reseed(&mut self, seed : u64) {
+        self.seed = seed;
+        self.rng = seeded_rng(seed);
+    }
+
+    // FNV-1a over the state scripts can affect, to find where a replay diverges.
+    pub fn checksum(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let mut add = |word : u32| {
+            for byte in word.to_le_bytes().iter() {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+
+        add(self.time.to_bits());
+        for v in [self.camera_position, self.camera_lookat, self.camera_up].iter() {
+            v.iter().for_each(|x| add(x.to_bits()));
+        }
+        for particle in &self.particles_list {
+            particle.position.iter().for_each(|x| add(x.to_bits()));
+            add(particle.size.to_bits());
+            add(particle.color);
+        }
+        for vertex in &self.meshes.vertices {
+            for v in [vertex.position, vertex.normal].iter() {
+                v.iter().for_each(|x| add(x.to_bits()));
+            }
+            vertex.uv.iter().for_each(|x| add(x.to_bits()));
+            add(vertex.color);
+        }
+        self.meshes.indices.iter().for_each(|&index| add(index));
+        for particle in self.particle_system.particles() {
+            for v in [particle.position, particle.velocity, particle.gravity].iter() {
+                v.iter().for_each(|x| add(x.to_bits()));
+            }
+            for x in [particle.drag, particle.age, particle.lifetime, particle.size].iter() {
+                add(x.to_bits());
+            }
+            add(particle.color);
+            add(particle.emitter as u32);
+        }
+
+        hash
+    }
+
+    pub fn set_tick_rate(&mut self, ticks_per_second : f32) {
+        self.tick_length = 1.0 / ticks_per_second;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused : bool) {
+        self.paused = paused;
+        self.pending_steps = 0;
+    }
+
+    // Runs exactly one tick on the next advance, pausing first if needed.
+    pub fn step_once(&mut self) {
+        if !self.paused {
+            self.set_paused(true);
+        }
+        self.pending_steps += 1;
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    pub fn set_time_scale(&mut self, scale : f32) {
+        self.time_scale = scale.max(MIN_TIME_SCALE).min(MAX_TIME_SCALE);
+    }
+
+    // Next speed up or down from the current one.
+    pub fn change_time_scale(&mut self, faster : bool) {
+        let scale = if faster {
+            TIME_SCALES.iter().find(|&&scale| scale > self.time_scale)
+        } else {
+            TIME_SCALES.iter().rev().find(|&&scale| scale < self.time_scale)
+        };
+
+        if let Some(&scale) = scale {
+            self.time_scale = scale;
+        }
+    }
+
+    // Empty when running at normal speed, for the window title.
+    pub fn time_status(&self) -> String {
+        if self.paused {
+            "paused".to_string()
+        } else if self.time_scale != 1.0 {
+            format!("{}x", self.time_scale)
+        } else {
+            String::new()
+        }
+    }
+
+    // Runs as many fixed steps as fit in the wall clock time since the last call, carrying the rest over.
+    // Returns that time, advance_by with it does the same.
+    pub fn advance(&mut self, vm : &mut VMInstance, wall_time : f32) -> f32 {
+        let elapsed = self.last_time.map_or(0.0, |last_time| (wall_time - last_time).max(0.0));
+        self.last_time = Some(wall_time);
+        self.advance_by(vm, elapsed);
+        elapsed
+    }
+
+    // Like advance, with the frame's length given directly. Frame exports use it so that every frame of
+    // the same length runs the same number of ticks, which differences of wall times can't guarantee.
+    pub fn advance_by(&mut self, vm : &mut VMInstance, elapsed : f32) {
+        self.frame_dt = 0.0;
+        self.script_time = Duration::new(0, 0);
+        if self.paused {
+            for _ in 0..self.pending_steps {
+                self.tick(vm);
+                self.frame_dt += self.tick_length;
+            }
+            self.pending_steps = 0;
+            return;
+        }
+
+        self.accumulator += elapsed.min(MAX_FRAME_TIME) * self.time_scale;
+        while self.accumulator >= self.tick_length {
+            self.accumulator -= self.tick_length;
+            self.tick(vm);
+            self.frame_dt += self.tick_length;
+        }
+
+        self.alpha = self.accumulator / self.tick_length;
+    }
+
+    // One fixed step.
+    pub fn tick(&mut self, vm : &mut VMInstance) {
         self.particles_list.clear();
+        self.meshes.clear();
+        self.debug.clear();
 
-        let result = vm.call_tick(self, time);
-        if result.is_err() {
-            println!("{:?}", result);
+        let dt = self.tick_length;
+        let start = Instant::now();
+        if let Err(error) = vm.call_tick(self, self.time, dt) {
+            println!("{}", error);
         }
+        self.script_time += start.elapsed();
+
+        let mut events = Vec::new();
+        self.particle_system.update(dt, &mut self.rng, &mut events);
+        let start = Instant::now();
+        for event in events {
+            if let Err(error) = vm.call_particle_event(self, event) {
+                println!("{}", error);
+                break;
+            }
+        }
+        self.script_time += start.elapsed();
+
+        self.time += dt;
     }
 }
\ No newline at end of file